@@ -0,0 +1,415 @@
+//! HTTP REST API for scripts: rooms, room history, posting, users and DMs.
+//! Every route except token creation needs an `Authorization: Bearer`
+//! token tied to a registered account.
+
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use tracing::{error, info};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::errors::{error_reply, ActionError, ErrorCode};
+use crate::login_guard::attempt_login;
+use crate::presence::snapshot_all;
+use crate::rate_limit::{account_key, ip_key, Action};
+use crate::roles::{account_role, Role};
+use crate::room::{dm_key, post_to_room, room_list};
+use crate::room_policy::claim_post;
+use crate::tokens::{bearer_token, issue_token, save_tokens_async, token_account};
+use crate::types::{AppState, HistoryItem};
+
+/// Messages returned per page when `limit` is not given.
+const DEFAULT_PAGE: usize = 50;
+
+/// Largest accepted `limit`.
+const MAX_PAGE: usize = 200;
+
+/// Largest accepted JSON request body.
+const MAX_BODY_BYTES: u64 = 16 * 1024;
+
+/// An authenticated API caller.
+struct Caller {
+    account: String,
+    token: String,
+}
+
+/// A failed API request, turned into a JSON error by `handle_rejection`.
+#[derive(Debug)]
+struct ApiRejection(ActionError);
+
+impl warp::reject::Reject for ApiRejection {}
+
+fn reject(code: ErrorCode, message: &str) -> Rejection {
+    warp::reject::custom(ApiRejection(ActionError::new(code, message)))
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    before: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    q: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct PostMessage {
+    text: String,
+}
+
+/// All `/api` routes, with API errors rendered as JSON.
+pub fn routes(state: AppState) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let state_f = warp::any().map(move || state.clone());
+    let auth = warp::header::optional::<String>("authorization")
+        .and(state_f.clone())
+        .and_then(authenticate);
+
+    let create_token = warp::path!("tokens")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+        .and(warp::body::json())
+        .and(warp::addr::remote())
+        .and(state_f.clone())
+        .and_then(create_token);
+    let revoke_token = warp::path!("tokens")
+        .and(warp::delete())
+        .and(auth.clone())
+        .and(state_f.clone())
+        .and_then(revoke_token);
+    let rooms = warp::path!("rooms")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(state_f.clone())
+        .and_then(list_rooms);
+    let room_messages = warp::path!("rooms" / String / "messages")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<PageQuery>())
+        .and(state_f.clone())
+        .and_then(room_messages);
+    let post_message = warp::path!("rooms" / String / "messages")
+        .and(warp::post())
+        .and(auth.clone())
+        .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+        .and(warp::body::json())
+        .and(state_f.clone())
+        .and_then(post_message);
+    let users = warp::path!("users")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(state_f.clone())
+        .and_then(list_users);
+    let dms = warp::path!("dms")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(state_f.clone())
+        .and_then(list_dms);
+    let dm_messages = warp::path!("dms" / String / "messages")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<PageQuery>())
+        .and(state_f.clone())
+        .and_then(dm_messages);
+    let audit = warp::path!("audit")
+        .and(warp::get())
+        .and(auth)
+        .and(warp::query::<AuditQuery>())
+        .and(state_f)
+        .and_then(export_audit);
+
+    warp::path("api")
+        .and(
+            create_token
+                .or(revoke_token)
+                .or(rooms)
+                .or(room_messages)
+                .or(post_message)
+                .or(users)
+                .or(dms)
+                .or(dm_messages)
+                .or(audit),
+        )
+        .recover(handle_rejection)
+}
+
+/// Resolve the bearer token in the `Authorization` header.
+async fn authenticate(header: Option<String>, state: AppState) -> Result<Caller, Rejection> {
+    let header =
+        header.ok_or_else(|| reject(ErrorCode::NotAuthenticated, "missing bearer token"))?;
+    let token = bearer_token(&header)
+        .ok_or_else(|| reject(ErrorCode::NotAuthenticated, "expected a bearer token"))?;
+    let account = token_account(&state.api_tokens, &state.users, &state.bots, token)
+        .ok_or_else(|| reject(ErrorCode::AuthFailed, "invalid token"))?;
+    Ok(Caller {
+        account,
+        token: token.to_string(),
+    })
+}
+
+/// Render API rejections and body errors as JSON; anything else (such as
+/// an unknown path) passes through to the other routes.
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    let e = if let Some(ApiRejection(e)) = rejection.find::<ApiRejection>() {
+        e.clone()
+    } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        ActionError::new(ErrorCode::BadRequest, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        ActionError::new(ErrorCode::BadRequest, e.to_string())
+    } else if rejection.find::<warp::reject::PayloadTooLarge>().is_some() {
+        ActionError::new(ErrorCode::PayloadTooLarge, "request body is too large")
+    } else {
+        return Err(rejection);
+    };
+    Ok(error_reply(&e, None))
+}
+
+/// `POST /api/tokens`: exchange account credentials for a new token.
+async fn create_token(
+    body: TokenRequest,
+    remote: Option<SocketAddr>,
+    state: AppState,
+) -> Result<impl Reply, Rejection> {
+    let ip = remote
+        .map(|a| a.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    state
+        .rate_limiter
+        .check(Action::Token, &ip_key(&ip))
+        .map_err(|e| warp::reject::custom(ApiRejection(e)))?;
+    attempt_login(&state, &body.username, &body.password, &ip)
+        .await
+        .map_err(|e| warp::reject::custom(ApiRejection(e)))?;
+    let token = issue_token(&state.api_tokens, &body.username);
+    if let Err(e) = save_tokens_async(&state.api_tokens).await {
+        error!("failed to save API tokens: {}", e);
+    }
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({"ok": true, "token": token, "account": body.username})),
+        StatusCode::CREATED,
+    ))
+}
+
+/// `DELETE /api/tokens`: revoke the token used for this request.
+async fn revoke_token(caller: Caller, state: AppState) -> Result<impl Reply, Rejection> {
+    state.api_tokens.remove(&caller.token);
+    if let Err(e) = save_tokens_async(&state.api_tokens).await {
+        error!("failed to save API tokens: {}", e);
+    }
+    info!("Revoked an API token of {}", caller.account);
+    Ok(warp::reply::json(&json!({"ok": true})))
+}
+
+/// `GET /api/rooms`
+async fn list_rooms(_caller: Caller, state: AppState) -> Result<impl Reply, Rejection> {
+    let mut rooms = room_list(&state.clients, &state.histories).await;
+    rooms.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(warp::reply::json(&json!({ "rooms": rooms })))
+}
+
+/// `GET /api/rooms/{room}/messages?before=&limit=`
+async fn room_messages(
+    room: String,
+    _caller: Caller,
+    query: PageQuery,
+    state: AppState,
+) -> Result<impl Reply, Rejection> {
+    let room = segment(&room)?;
+    let locked = state.histories.read().await;
+    let q = locked
+        .get(&room)
+        .ok_or_else(|| reject(ErrorCode::NotFound, "unknown room"))?;
+    let (messages, has_more) = page(q, &query)?;
+    Ok(warp::reply::json(
+        &json!({ "room": room, "messages": messages, "has_more": has_more }),
+    ))
+}
+
+/// `POST /api/rooms/{room}/messages`: post as the token's account. The
+/// message is broadcast to everyone in the room like any other.
+async fn post_message(
+    room: String,
+    caller: Caller,
+    body: PostMessage,
+    state: AppState,
+) -> Result<impl Reply, Rejection> {
+    let room = segment(&room)?;
+    let text = body.text.trim();
+    if text.is_empty() {
+        return Err(reject(ErrorCode::BadRequest, "text must not be empty"));
+    }
+    if !state.histories.read().await.contains_key(&room) {
+        return Err(reject(ErrorCode::NotFound, "unknown room"));
+    }
+    let action = if state.bots.contains_key(&caller.account) {
+        Action::BotMessage
+    } else {
+        Action::Message
+    };
+    let key = account_key(&caller.account);
+    state
+        .rate_limiter
+        .check(action, &key)
+        .map_err(|e| warp::reject::custom(ApiRejection(e)))?;
+    let role = account_role(&state.roles, Some(&caller.account));
+    let claim =
+        claim_post(&state, &room, &key, role).map_err(|e| warp::reject::custom(ApiRejection(e)))?;
+    let mut item = HistoryItem::new(caller.account.clone(), text);
+    item.bot = state.bots.contains_key(&caller.account);
+    let item = post_to_room(&state, &room, item, role, None, Some(&key))
+        .await
+        .map_err(|e| warp::reject::custom(ApiRejection(e)))?;
+    claim.commit();
+    info!("{} posted to '{}' via the API", caller.account, room);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({ "ok": true, "message": item })),
+        StatusCode::CREATED,
+    ))
+}
+
+/// `GET /api/users`: registered accounts and bots with role and presence.
+async fn list_users(_caller: Caller, state: AppState) -> Result<impl Reply, Rejection> {
+    let presences = snapshot_all(&state);
+    let accounts: Vec<(String, bool)> = state
+        .users
+        .iter()
+        .map(|r| (r.key().clone(), false))
+        .chain(state.bots.iter().map(|r| (r.key().clone(), true)))
+        .collect();
+    let mut users: Vec<serde_json::Value> = accounts
+        .iter()
+        .map(|(account, bot)| {
+            // Sessions of an account share one display name
+            let name = state
+                .clients
+                .iter()
+                .find(|c| {
+                    c.value()
+                        .account
+                        .as_deref()
+                        .map(|a| a.eq_ignore_ascii_case(account))
+                        .unwrap_or(false)
+                })
+                .map(|c| c.value().name.clone());
+            let presence = name.as_ref().and_then(|n| presences.get(n));
+            json!({
+                "name": account,
+                "role": account_role(&state.roles, Some(account)),
+                "online": name.is_some(),
+                "status": presence.map(|p| p.status.as_str()).unwrap_or("offline"),
+                "emoji": presence.and_then(|p| p.emoji.clone()),
+                "text": presence.and_then(|p| p.text.clone()),
+                "bot": bot,
+            })
+        })
+        .collect();
+    users.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    Ok(warp::reply::json(&json!({ "users": users })))
+}
+
+/// `GET /api/dms`: the caller's DM conversations, newest first.
+async fn list_dms(caller: Caller, state: AppState) -> Result<impl Reply, Rejection> {
+    let me = caller.account.to_lowercase();
+    let locked = state.private_histories.read().await;
+    let mut conversations: Vec<(u64, serde_json::Value)> = locked
+        .iter()
+        .filter_map(|(key, q)| {
+            let (a, b) = key.split_once(',')?;
+            let with = if a == me {
+                b
+            } else if b == me {
+                a
+            } else {
+                return None;
+            };
+            let last = q.iter().rev().find(|i| !i.deleted)?;
+            let count = q.iter().filter(|i| !i.deleted).count();
+            Some((
+                last.ts,
+                json!({ "with": with, "count": count, "last": last }),
+            ))
+        })
+        .collect();
+    conversations.sort_by_key(|c| std::cmp::Reverse(c.0));
+    let conversations: Vec<serde_json::Value> = conversations.into_iter().map(|(_, c)| c).collect();
+    Ok(warp::reply::json(
+        &json!({ "conversations": conversations }),
+    ))
+}
+
+/// `GET /api/dms/{user}/messages?before=&limit=`
+async fn dm_messages(
+    user: String,
+    caller: Caller,
+    query: PageQuery,
+    state: AppState,
+) -> Result<impl Reply, Rejection> {
+    let user = segment(&user)?;
+    let locked = state.private_histories.read().await;
+    let q = locked
+        .get(&dm_key(&caller.account, &user))
+        .ok_or_else(|| reject(ErrorCode::NotFound, "no conversation with that user"))?;
+    let (messages, has_more) = page(q, &query)?;
+    Ok(warp::reply::json(
+        &json!({ "with": user.to_lowercase(), "messages": messages, "has_more": has_more }),
+    ))
+}
+
+/// `GET /api/audit?q=`: the audit log as JSON lines (admins only),
+/// optionally only entries naming a user or room.
+async fn export_audit(
+    caller: Caller,
+    query: AuditQuery,
+    state: AppState,
+) -> Result<impl Reply, Rejection> {
+    if account_role(&state.roles, Some(&caller.account)) < Role::Admin {
+        return Err(reject(ErrorCode::Forbidden, "admins only"));
+    }
+    let q = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let body = state.audit.export(q).await.map_err(|e| {
+        error!("failed to read audit log: {}", e);
+        reject(ErrorCode::Internal, "could not read the audit log")
+    })?;
+    Ok(warp::reply::with_header(
+        body,
+        "content-type",
+        "application/x-ndjson",
+    ))
+}
+
+/// Decode a percent-encoded path segment such as a room name.
+fn segment(raw: &str) -> Result<String, Rejection> {
+    percent_encoding::percent_decode_str(raw)
+        .decode_utf8()
+        .map(|s| s.into_owned())
+        .map_err(|_| reject(ErrorCode::BadRequest, "path is not valid UTF-8"))
+}
+
+/// Up to `limit` non-deleted messages before the `before` id (or the
+/// newest ones), oldest first, and whether older messages remain.
+fn page(
+    q: &VecDeque<HistoryItem>,
+    query: &PageQuery,
+) -> Result<(Vec<HistoryItem>, bool), Rejection> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let visible: Vec<&HistoryItem> = q.iter().filter(|i| !i.deleted).collect();
+    let end = match &query.before {
+        Some(id) => visible
+            .iter()
+            .position(|i| &i.id == id)
+            .ok_or_else(|| reject(ErrorCode::NotFound, "unknown message id"))?,
+        None => visible.len(),
+    };
+    let start = end.saturating_sub(limit);
+    let messages = visible[start..end].iter().map(|i| (*i).clone()).collect();
+    Ok((messages, start > 0))
+}
@@ -0,0 +1,210 @@
+//! Audit log of moderation and administrative actions (kicks, deletions,
+//! role changes, room settings, mutes, ...). Entries are appended to a JSON
+//! lines file, queried with `/audit` and exported via `GET /api/audit`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::helpers::now_ts;
+use crate::types::Clients;
+
+/// Entries kept in memory for `/audit`; older ones stay in the file only.
+const MAX_RECENT: usize = 1000;
+
+/// One recorded action.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub ts: u64,
+    /// Who acted: an account or display name, or `system` for automatic actions
+    pub actor: String,
+    /// What was done, e.g. `kick`, `delete`, `role`, `filter`, `mute`
+    pub action: String,
+    /// Whom or what it was done to
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AuditEntry {
+    /// Whether `query` names the entry's actor, target or room.
+    pub fn mentions(&self, query: &str) -> bool {
+        self.actor.eq_ignore_ascii_case(query)
+            || self.target.eq_ignore_ascii_case(query)
+            || self
+                .room
+                .as_deref()
+                .is_some_and(|r| r.eq_ignore_ascii_case(query))
+    }
+
+    /// One line for `/audit`.
+    pub fn describe(&self, now: u64) -> String {
+        let mut line = format!(
+            "{} ago: {} {} {}",
+            age(now.saturating_sub(self.ts)),
+            self.actor,
+            self.action,
+            self.target
+        );
+        if let Some(room) = &self.room {
+            line.push_str(&format!(" in '{}'", room));
+        }
+        if let Some(reason) = &self.reason {
+            line.push_str(&format!(" ({})", reason));
+        }
+        line
+    }
+}
+
+/// Name to record as the actor for a client: its account, or its display
+/// name for guests.
+pub fn actor_name(clients: &Clients, client_id: &str) -> String {
+    clients
+        .get(client_id)
+        .map(|c| c.account.clone().unwrap_or_else(|| c.name.clone()))
+        .unwrap_or_default()
+}
+
+/// A duration in seconds as `45s`, `12m`, `3h` or `2d`.
+fn age(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86400 => format!("{}h", s / 3600),
+        s => format!("{}d", s / 86400),
+    }
+}
+
+/// The audit log, held in `AppState::audit`. Entries are written to the
+/// file in order by a background task, so recording never blocks.
+pub struct AuditLog {
+    path: String,
+    recent: Mutex<VecDeque<AuditEntry>>,
+    tx: mpsc::UnboundedSender<AuditEntry>,
+}
+
+impl AuditLog {
+    /// Load the newest entries of `path` and start the writer task. Must be
+    /// called inside the runtime.
+    pub fn open(path: &str) -> Self {
+        let mut recent = VecDeque::with_capacity(MAX_RECENT);
+        if let Ok(s) = std::fs::read_to_string(path) {
+            for line in s.lines().filter(|l| !l.trim().is_empty()) {
+                match serde_json::from_str::<AuditEntry>(line) {
+                    Ok(entry) => {
+                        if recent.len() == MAX_RECENT {
+                            recent.pop_front();
+                        }
+                        recent.push_back(entry);
+                    }
+                    Err(e) => warn!("skipping bad line in {}: {}", path, e),
+                }
+            }
+            info!("Loaded {} audit entries from {}", recent.len(), path);
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_loop(path.to_string(), rx));
+        AuditLog {
+            path: path.to_string(),
+            recent: Mutex::new(recent),
+            tx,
+        }
+    }
+
+    /// Record that `actor` did `action` to `target`.
+    pub fn record(
+        &self,
+        actor: &str,
+        action: &str,
+        target: &str,
+        room: Option<&str>,
+        reason: Option<&str>,
+    ) {
+        let entry = AuditEntry {
+            ts: now_ts(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            room: room.map(str::to_string),
+            reason: reason.map(str::to_string),
+        };
+        {
+            let mut recent = self.recent.lock().unwrap();
+            if recent.len() == MAX_RECENT {
+                recent.pop_front();
+            }
+            recent.push_back(entry.clone());
+        }
+        if self.tx.send(entry).is_err() {
+            error!("audit writer has stopped; entry not saved");
+        }
+    }
+
+    /// The newest `limit` recent entries mentioning `query` (all when
+    /// `None`), oldest first.
+    pub fn search(&self, query: Option<&str>, limit: usize) -> Vec<AuditEntry> {
+        let recent = self.recent.lock().unwrap();
+        let mut found: Vec<AuditEntry> = recent
+            .iter()
+            .rev()
+            .filter(|e| query.is_none_or(|q| e.mentions(q)))
+            .take(limit)
+            .cloned()
+            .collect();
+        found.reverse();
+        found
+    }
+
+    /// The whole log file as JSON lines, keeping only entries mentioning
+    /// `query` if given.
+    pub async fn export(&self, query: Option<&str>) -> std::io::Result<String> {
+        let s = match tokio::fs::read_to_string(&self.path).await {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let Some(query) = query else {
+            return Ok(s);
+        };
+        let mut out = String::new();
+        for line in s.lines() {
+            let keep = serde_json::from_str::<AuditEntry>(line).is_ok_and(|e| e.mentions(query));
+            if keep {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Append entries to the file as they arrive.
+async fn write_loop(path: String, mut rx: mpsc::UnboundedReceiver<AuditEntry>) {
+    while let Some(entry) = rx.recv().await {
+        let mut line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("failed to serialize audit entry: {}", e);
+                continue;
+            }
+        };
+        line.push('\n');
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await;
+        let result = match file {
+            Ok(mut f) => f.write_all(line.as_bytes()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("failed to write {}: {}", path, e);
+        }
+    }
+}
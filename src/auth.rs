@@ -6,7 +6,9 @@ use std::fs;
 use std::path::Path;
 use tracing::{error, info};
 
-use crate::types::Users;
+use crate::bots::bot_name;
+use crate::errors::{ActionError, ErrorCode};
+use crate::types::{Bots, Users};
 
 /// Load users from `users.json` (synchronous, done at startup).
 pub fn load_users() -> anyhow::Result<HashMap<String, String>> {
@@ -32,16 +34,57 @@ pub async fn save_users_async(map: HashMap<String, String>) -> anyhow::Result<()
     Ok(())
 }
 
-/// Register a new user. Returns Err on duplicate or save error.
-pub async fn register_user(users: &Users, username: &str, password: &str) -> Result<(), String> {
-    if users.contains_key(username) {
-        return Err("username already exists".into());
+/// The stored spelling of a username, matched case-insensitively.
+pub fn user_name(users: &Users, name: &str) -> Option<String> {
+    users
+        .iter()
+        .find(|r| r.key().eq_ignore_ascii_case(name))
+        .map(|r| r.key().clone())
+}
+
+/// Register a new user. Returns Err on duplicate or save error. Names are
+/// unique regardless of case, since roles, inboxes, read markers, statuses
+/// and rate limits are all keyed by the lowercase account.
+pub async fn register_user(
+    users: &Users,
+    bots: &Bots,
+    username: &str,
+    password: &str,
+) -> Result<(), ActionError> {
+    if user_name(users, username).is_some() {
+        return Err(ActionError::new(
+            ErrorCode::Conflict,
+            "username already exists",
+        ));
+    }
+    if bot_name(bots, username).is_some() {
+        return Err(ActionError::new(
+            ErrorCode::Conflict,
+            "username is taken by a bot",
+        ));
     }
 
-    // Hash password with bcrypt
-    let hashed = hash(password, DEFAULT_COST).map_err(|e| format!("hash error: {}", e))?;
+    // Hash password with bcrypt on the blocking pool; it takes a noticeable
+    // fraction of a second
+    let password = password.to_string();
+    let hashed = tokio::task::spawn_blocking(move || hash(password, DEFAULT_COST))
+        .await
+        .map_err(|e| ActionError::new(ErrorCode::Internal, format!("hash task failed: {}", e)))?
+        .map_err(|e| ActionError::new(ErrorCode::Internal, format!("hash error: {}", e)))?;
 
+    // Another registration of the same name may have finished while this
+    // one was hashing; if both got in, both back out
     users.insert(username.to_string(), hashed);
+    let taken = users
+        .iter()
+        .any(|r| r.key() != username && r.key().eq_ignore_ascii_case(username));
+    if taken {
+        users.remove(username);
+        return Err(ActionError::new(
+            ErrorCode::Conflict,
+            "username already exists",
+        ));
+    }
 
     // Create a copy for saving (synchronous collection)
     let map_to_save: HashMap<String, String> = users
@@ -51,25 +94,65 @@ pub async fn register_user(users: &Users, username: &str, password: &str) -> Res
 
     if let Err(e) = save_users_async(map_to_save).await {
         error!("failed to save users: {}", e);
-        return Err(format!("failed to save users: {}", e));
+        return Err(ActionError::new(
+            ErrorCode::Internal,
+            format!("failed to save users: {}", e),
+        ));
     }
 
     info!("Registered new user: {}", username);
     Ok(())
 }
 
-/// Verify login credentials.
+/// Verify login credentials. The bcrypt check runs on the blocking pool.
+/// Callers outside `login_guard.rs` skip failed-login backoff.
 pub async fn verify_login(users: &Users, username: &str, password: &str) -> bool {
-    if let Some(r) = users.get(username) {
-        let stored_hash = r.value();
-        match verify(password, stored_hash) {
-            Ok(valid) => valid,
-            Err(e) => {
-                error!("bcrypt verify error for {}: {}", username, e);
-                false
-            }
+    let Some(stored_hash) = users.get(username).map(|r| r.value().clone()) else {
+        return false;
+    };
+    let password = password.to_string();
+    match tokio::task::spawn_blocking(move || verify(password, &stored_hash)).await {
+        Ok(Ok(valid)) => valid,
+        Ok(Err(e)) => {
+            error!("bcrypt verify error for {}: {}", username, e);
+            false
         }
-    } else {
-        false
+        Err(e) => {
+            error!("bcrypt verify task failed for {}: {}", username, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bots::BotAccount;
+    use dashmap::DashMap;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn register_rejects_names_differing_only_in_case() {
+        let users: Users = Arc::new(DashMap::new());
+        users.insert("alice".to_string(), "hash".to_string());
+        let bots: Bots = Arc::new(DashMap::new());
+        bots.insert(
+            "Helper".to_string(),
+            BotAccount {
+                created_by: "alice".to_string(),
+                created: 0,
+            },
+        );
+
+        let err = register_user(&users, &bots, "ALICE", "secret123")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::Conflict);
+        let err = register_user(&users, &bots, "helper", "secret123")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::Conflict);
+        assert_eq!(users.len(), 1);
+        assert_eq!(user_name(&users, "Alice").as_deref(), Some("alice"));
     }
 }
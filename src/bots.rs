@@ -0,0 +1,86 @@
+//! Bot accounts: created by admins, authenticated with API tokens, and
+//! connected to `/ws?token=...` without the name phase.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use tracing::info;
+
+use crate::helpers::now_ts;
+use crate::tokens::issue_token;
+use crate::types::{ApiTokens, Bots};
+
+/// Longest bot name, in characters.
+const MAX_BOT_NAME: usize = 32;
+
+/// One bot account.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BotAccount {
+    pub created_by: String,
+    pub created: u64,
+}
+
+/// Load bots from `bots.json` (synchronous, done at startup).
+pub fn load_bots() -> anyhow::Result<HashMap<String, BotAccount>> {
+    let path = "bots.json";
+    if Path::new(path).exists() {
+        let s = fs::read_to_string(path)?;
+        let m: HashMap<String, BotAccount> = serde_json::from_str(&s)?;
+        info!("Loaded {} bot accounts from disk", m.len());
+        Ok(m)
+    } else {
+        Ok(HashMap::new())
+    }
+}
+
+/// Save bots to disk (async-friendly via spawn_blocking).
+pub async fn save_bots_async(bots: &Bots) -> anyhow::Result<()> {
+    let map: HashMap<String, BotAccount> = bots
+        .iter()
+        .map(|r| (r.key().clone(), r.value().clone()))
+        .collect();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let s = serde_json::to_string_pretty(&map)?;
+        fs::write("bots.json", s)?;
+        Ok(())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Create a bot and issue its first API token, which is returned.
+pub fn create_bot(bots: &Bots, tokens: &ApiTokens, name: &str, created_by: &str) -> String {
+    bots.insert(
+        name.to_string(),
+        BotAccount {
+            created_by: created_by.to_string(),
+            created: now_ts(),
+        },
+    );
+    info!("{} created bot account {}", created_by, name);
+    issue_token(tokens, name)
+}
+
+/// The stored spelling of a bot name, matched case-insensitively.
+pub fn bot_name(bots: &Bots, name: &str) -> Option<String> {
+    bots.iter()
+        .find(|r| r.key().eq_ignore_ascii_case(name))
+        .map(|r| r.key().clone())
+}
+
+/// Whether a bot name is acceptable: letters, digits, `-` and `_`.
+pub fn valid_bot_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= MAX_BOT_NAME
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Revoke every API token issued to `account`. Returns how many were removed.
+pub fn revoke_tokens_for(tokens: &ApiTokens, account: &str) -> usize {
+    let before = tokens.len();
+    tokens.retain(|_, t| !t.account.eq_ignore_ascii_case(account));
+    before - tokens.len()
+}
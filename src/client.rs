@@ -1,40 +1,120 @@
-//! WebSocket client handling and connection lifecycle.
+//! Client connection lifecycle, shared by the WebSocket and SSE transports.
 
-use futures::{SinkExt, StreamExt};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::fmt::Display;
 use std::time::Instant;
 use tokio::sync::mpsc;
 use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::auth::{register_user, verify_login};
-use crate::commands::{handle_cmd_with_rooms, handle_message_with_rooms};
-use crate::helpers::{client_tx_by_id, make_unique_name};
-use crate::rate_limit::check_rate_limit;
+use crate::auth::register_user;
+use crate::commands::{handle_command, handle_message_in_room, handle_message_with_rooms};
+use crate::config::Config;
+use crate::encoding::{send, Encoding, Outbound};
+use crate::errors::{send_result, ActionError, ActionResult, ErrorCode};
+use crate::helpers::{device_label, make_unique_name, now_ts, other_session_in_room};
+use crate::login_guard::attempt_login;
+use crate::mentions::notify_pending;
+use crate::message_filter::MessageContext;
+use crate::outgoing_webhooks::RoomEvent;
+use crate::presence::{
+    broadcast_user_presence, forget_guest, presence_key, send_presence_snapshot,
+};
+use crate::protocol::{negotiate, read_frame, server_features, Frame, Transport};
+use crate::rate_limit::{client_key, ip_key, Action};
+use crate::roles::client_role;
 use crate::room::{send_history_to_client_room, send_system_to_room, send_user_list_to_room};
-use crate::types::{Client, Clients, Histories, Incoming, Outgoing, PrivateHistories, Tx, Users};
+use crate::tokens::token_account;
+use crate::types::{
+    AppState, Client, Incoming, Outgoing, Tx, WsParams, MIN_PROTOCOL_VERSION, PROTOCOL_V1,
+    PROTOCOL_VERSION,
+};
 use crate::typing::{broadcast_typing_status, set_typing_status};
+use crate::unread::{mark_current_room_read, mark_read, send_unread_summary};
 
 /// Handle a new WebSocket connection.
 pub async fn client_connected(
     ws: warp::ws::WebSocket,
     remote: Option<std::net::SocketAddr>,
-    clients: Clients,
-    histories: Histories,
-    private_histories: PrivateHistories,
-    users: Users,
-    metrics: std::sync::Arc<crate::metrics::ServerMetrics>,
+    user_agent: Option<String>,
+    params: WsParams,
+    state: AppState,
 ) {
+    let (sink, frames) = ws.split();
+    run_session(
+        Transport::WebSocket,
+        sink,
+        frames,
+        remote,
+        user_agent,
+        params,
+        state,
+    )
+    .await;
+}
+
+/// Run one client session: the name phase, then the main message loop, then
+/// cleanup. Encoded frames go to `sink`; frames from the client come from
+/// `frames`, which ends when the client goes away.
+pub async fn run_session<S, R, E>(
+    transport: Transport,
+    mut sink: S,
+    mut frames: R,
+    remote: Option<std::net::SocketAddr>,
+    user_agent: Option<String>,
+    params: WsParams,
+    state: AppState,
+) where
+    S: Sink<warp::ws::Message> + Unpin + Send + 'static,
+    R: Stream<Item = Result<warp::ws::Message, E>> + Unpin,
+    E: Display,
+{
+    let AppState {
+        clients,
+        histories,
+        metrics,
+        config,
+        ..
+    } = state.clone();
     let addr = remote
         .map(|a| a.to_string())
         .unwrap_or_else(|| "unknown".to_string());
+    let ip = remote
+        .map(|a| a.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
 
-    let (mut ws_tx, mut ws_rx) = ws.split();
     let (tx, mut rx) = mpsc::unbounded_channel();
 
-    // Forward task: send messages from rx to WebSocket sink
+    // Forward task: encode messages from rx and send them to the transport
+    // sink. Starts out as uncompressed JSON; switches when the client
+    // negotiates msgpack or deflate. Stops after sending a close frame.
+    let forward_metrics = metrics.clone();
     let forward_task = tokio::task::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if ws_tx.send(msg).await.is_err() {
+        let mut encoding = Encoding::Json;
+        let mut deflate_min: Option<usize> = None;
+        while let Some(out) = rx.recv().await {
+            let frame = match out {
+                Outbound::Payload(payload) => match payload.frame(encoding, deflate_min) {
+                    Some(wire) => {
+                        if deflate_min.is_some() {
+                            forward_metrics.record_deflate_frame(wire.raw_len, wire.wire_len);
+                        }
+                        wire.message
+                    }
+                    None => continue,
+                },
+                Outbound::SetEncoding(e) => {
+                    encoding = e;
+                    continue;
+                }
+                Outbound::SetCompression(min) => {
+                    deflate_min = Some(min);
+                    continue;
+                }
+                Outbound::Close(code, reason) => warp::ws::Message::close_with(code, reason),
+            };
+            let closing = frame.is_close();
+            if sink.send(frame).await.is_err() || closing {
                 break;
             }
         }
@@ -44,141 +124,123 @@ pub async fn client_connected(
     let default_room = "lobby".to_string();
     let mut chosen_name = format!("guest-{}", &client_id[..6]);
     let mut logged_in = false;
+    let mut account: Option<String> = None;
+    let device = device_label(user_agent.as_deref());
+    let mut protocol = params.v.unwrap_or(PROTOCOL_V1);
+    let mut client_capabilities: Vec<String> = Vec::new();
 
     // Helper to send system message to this connection only
     let send_system_to_this = |tx: &Tx, text: &str| {
-        let msg = Outgoing::System {
-            text: text.to_string(),
-        };
-        if let Ok(s) = serde_json::to_string(&msg) {
-            let _ = tx.send(warp::ws::Message::text(s));
-        }
+        send(
+            tx,
+            Outgoing::System {
+                text: text.to_string(),
+            },
+        );
     };
 
-    // Welcome prompt
-    send_system_to_this(
-        &tx,
-        "Welcome — choose a username, or /register or /login. Use /join <room> to switch rooms.",
-    );
+    // Bots authenticate with their token and skip the name phase
+    let mut bot = false;
+    let mut said_hello = false;
+    let mut auth_completed = false;
+    if let Some(token) = params.token.as_deref() {
+        match bot_for_token(&state, token) {
+            Ok(name) => {
+                chosen_name = make_unique_name(&clients, &name, Some(&name)).await;
+                send_system_to_this(&tx, &format!("Authenticated as bot '{}'", chosen_name));
+                account = Some(name);
+                logged_in = true;
+                bot = true;
+                auth_completed = true;
+            }
+            Err(e) => {
+                info!("Rejecting bot connection from {}: {}", addr, e.message);
+                send_result(&tx, protocol, None, Err(e));
+                let _ = tx.send(Outbound::Close(1008, "invalid bot token"));
+                drop(tx);
+                let _ = forward_task.await;
+                return;
+            }
+        }
+    } else {
+        send_system_to_this(
+            &tx,
+            "Welcome — choose a username, or /register or /login. Use /join <room> to switch rooms.",
+        );
+    }
 
     // Auth / name phase
-    let mut auth_completed = false;
-    while let Some(result) = ws_rx.next().await {
+    while !auth_completed {
+        let Some(result) = frames.next().await else {
+            break;
+        };
         match result {
             Ok(msg) => {
-                if msg.is_text() {
-                    if let Ok(text) = msg.to_str() {
-                        match serde_json::from_str::<Incoming>(text) {
-                            Ok(Incoming::Cmd { cmd }) => {
-                                let mut parts = cmd.splitn(3, ' ');
-                                match parts.next().unwrap_or("") {
-                                    "/name" => {
-                                        if let Some(name) = parts.next() {
-                                            let name = name.trim();
-                                            if !name.is_empty() {
-                                                chosen_name =
-                                                    make_unique_name(&clients, name).await;
-                                                logged_in = false;
-                                                send_system_to_this(&tx, &format!("Your name is '{}'. You are not authenticated.", chosen_name));
-                                                auth_completed = true;
-                                                break;
-                                            } else {
-                                                send_system_to_this(&tx, "Usage: /name <username>");
-                                            }
-                                        } else {
-                                            send_system_to_this(&tx, "Usage: /name <username>");
-                                        }
-                                    }
-                                    "/register" => {
-                                        let username_opt = parts.next();
-                                        let password_opt = parts.next();
-                                        if let (Some(username), Some(password)) =
-                                            (username_opt, password_opt)
-                                        {
-                                            let username = username.trim().to_string();
-                                            let password = password.trim().to_string();
-                                            match register_user(&users, &username, &password).await
-                                            {
-                                                Ok(_) => {
-                                                    chosen_name =
-                                                        make_unique_name(&clients, &username).await;
-                                                    logged_in = true;
-                                                    send_system_to_this(
-                                                        &tx,
-                                                        &format!(
-                                                            "Registered and logged in as '{}'",
-                                                            chosen_name
-                                                        ),
-                                                    );
-                                                    auth_completed = true;
-                                                    break;
-                                                }
-                                                Err(e) => {
-                                                    send_system_to_this(
-                                                        &tx,
-                                                        &format!("Register failed: {}", e),
-                                                    );
-                                                }
-                                            }
-                                        } else {
-                                            send_system_to_this(
-                                                &tx,
-                                                "Usage: /register <username> <password>",
-                                            );
-                                        }
-                                    }
-                                    "/login" => {
-                                        let username_opt = parts.next();
-                                        let password_opt = parts.next();
-                                        if let (Some(username), Some(password)) =
-                                            (username_opt, password_opt)
-                                        {
-                                            let username = username.trim().to_string();
-                                            let password = password.trim().to_string();
-                                            if verify_login(&users, &username, &password).await {
-                                                chosen_name =
-                                                    make_unique_name(&clients, &username).await;
-                                                logged_in = true;
-                                                send_system_to_this(
-                                                    &tx,
-                                                    &format!("Logged in as '{}'", chosen_name),
-                                                );
-                                                auth_completed = true;
-                                                break;
-                                            } else {
-                                                send_system_to_this(
-                                                    &tx,
-                                                    "Login failed: invalid username or password",
-                                                );
-                                            }
-                                        } else {
-                                            send_system_to_this(
-                                                &tx,
-                                                "Usage: /login <username> <password>",
-                                            );
-                                        }
-                                    }
-                                    other => {
-                                        send_system_to_this(&tx, &format!("Please choose a name or login/register first. Unknown: {}", other));
-                                    }
+                if let Some(frame) = read_frame(&msg, protocol) {
+                    match frame {
+                        Frame::Message(
+                            msg @ (Incoming::Cmd { .. }
+                            | Incoming::Name { .. }
+                            | Incoming::Register { .. }
+                            | Incoming::Login { .. }),
+                        ) => {
+                            let req_id = msg.req_id();
+                            match auth_command(msg, &state, &ip).await {
+                                Ok((name, acc, notice)) => {
+                                    chosen_name = name;
+                                    logged_in = acc.is_some();
+                                    account = acc;
+                                    send_system_to_this(&tx, &notice);
+                                    send_result(&tx, protocol, req_id, Ok(()));
+                                    auth_completed = true;
+                                    break;
                                 }
+                                Err(e) => send_result(&tx, protocol, req_id, Err(e)),
                             }
-                            Ok(Incoming::Msg { .. }) => {
-                                send_system_to_this(&tx, "Please choose a name or login/register before sending messages.");
-                            }
-                            Ok(Incoming::Typing { .. }) => {
-                                // Ignore typing during auth phase
+                        }
+                        Frame::Message(Incoming::Msg { req_id, .. }) => {
+                            send_result(&tx, protocol, req_id, Err(name_first()));
+                        }
+                        Frame::Message(Incoming::Typing { .. }) => {
+                            // Ignore typing during auth phase
+                        }
+                        Frame::Message(Incoming::Hello {
+                            version,
+                            min_version,
+                            capabilities,
+                            ..
+                        }) => match answer_hello(
+                            &tx,
+                            &config,
+                            transport,
+                            &addr,
+                            version,
+                            min_version,
+                            capabilities,
+                        ) {
+                            Some((agreed, caps)) => {
+                                protocol = agreed;
+                                client_capabilities = caps;
+                                said_hello = true;
                             }
-                            Ok(Incoming::React { .. })
-                            | Ok(Incoming::Edit { .. })
-                            | Ok(Incoming::Delete { .. })
-                            | Ok(Incoming::MarkRead { .. }) => {
-                                // Ignore these during auth phase
+                            None => {
+                                drop(tx);
+                                let _ = forward_task.await;
+                                return;
                             }
-                            Err(_) => {
-                                send_system_to_this(&tx, "Please choose a name or login/register before sending messages.");
+                        },
+                        Frame::Message(msg) => {
+                            // Only answered when the client asked for a reply
+                            if let Some(req_id) = msg.req_id() {
+                                send_result(&tx, protocol, Some(req_id), Err(name_first()));
                             }
                         }
+                        Frame::Legacy(_) => {
+                            send_result(&tx, protocol, None, Err(name_first()));
+                        }
+                        Frame::Invalid(reason) => {
+                            send(&tx, Outgoing::Invalid { reason });
+                        }
                     }
                 } else if msg.is_close() {
                     drop(tx);
@@ -187,7 +249,7 @@ pub async fn client_connected(
                 }
             }
             Err(e) => {
-                warn!("websocket error during auth for {}: {}", addr, e);
+                warn!("{} error during auth for {}: {}", transport, addr, e);
                 drop(tx);
                 let _ = forward_task.await;
                 return;
@@ -207,11 +269,17 @@ pub async fn client_connected(
         name: chosen_name.clone(),
         tx: tx.clone(),
         room: default_room.clone(),
-        last_message_times: Vec::new(),
         is_typing: false,
-        last_read_msg_id: None,
         last_active: Instant::now(),
         logged_in,
+        account,
+        device: device.clone(),
+        ip: ip.clone(),
+        protocol,
+        capabilities: client_capabilities,
+        joined_at: now_ts(),
+        bot,
+        subscriptions: Vec::new(),
     };
     clients.insert(client_id.clone(), client);
 
@@ -219,150 +287,125 @@ pub async fn client_connected(
     metrics.increment_connections();
 
     info!(
-        "New connection: {} (id: {}, name: {}, logged_in={}, room={})",
-        addr, client_id, chosen_name, logged_in, default_room
+        "New connection: {} (id: {}, name: {}, logged_in={}, room={}, device={}, via {})",
+        addr, client_id, chosen_name, logged_in, default_room, device, transport
     );
 
-    // Announce in lobby
-    send_system_to_room(
-        &clients,
-        &histories,
-        &default_room,
-        &format!("-- {} joined the room --", chosen_name),
-    )
-    .await;
+    // Announce in lobby unless another session of this user is already there
+    if !other_session_in_room(&clients, &client_id, &chosen_name, &default_room) {
+        send_system_to_room(
+            &clients,
+            &histories,
+            &default_room,
+            &format!("-- {} joined the room --", chosen_name),
+        )
+        .await;
+        state.outgoing_webhooks.emit(RoomEvent::Join {
+            room: default_room.clone(),
+            user: chosen_name.clone(),
+        });
+    }
     send_history_to_client_room(&tx, &histories, &default_room).await;
-    send_user_list_to_room(&clients, &default_room).await;
+    send_user_list_to_room(&state, &default_room).await;
+
+    // Registered users get their unread counts for every room they've visited
+    mark_current_room_read(&state, &client_id).await;
+    if let Some(acc) = clients
+        .get(&client_id)
+        .and_then(|r| r.value().account.clone())
+    {
+        send_unread_summary(&state, &acc, None).await;
+    }
+    notify_pending(&state, &client_id).await;
 
-    // Main message loop
-    while let Some(result) = ws_rx.next().await {
+    // Presence: show others' statuses and restore this user's saved one
+    if clients
+        .get(&client_id)
+        .map(|r| r.value().supports("presence"))
+        .unwrap_or(false)
+    {
+        send_presence_snapshot(&state, &tx).await;
+    }
+    if presence_key(&clients, &client_id)
+        .map(|k| state.presences.contains_key(&k))
+        .unwrap_or(false)
+    {
+        broadcast_user_presence(&state, &chosen_name).await;
+    }
+
+    // Main message loop. Ends when the client goes away or the server
+    // closes the session (the forward task stops after a close frame).
+    loop {
+        let result = tokio::select! {
+            next = frames.next() => match next {
+                Some(result) => result,
+                None => break,
+            },
+            _ = tx.closed() => break,
+        };
         match result {
             Ok(msg) => {
-                if msg.is_text() {
+                if let Some(frame) = read_frame(&msg, protocol) {
                     // Update activity
                     if let Some(mut r) = clients.get_mut(&client_id) {
                         r.value_mut().last_active = std::time::Instant::now();
                     }
 
-                    if let Ok(text) = msg.to_str() {
-                        match serde_json::from_str::<Incoming>(text) {
-                            Ok(Incoming::Cmd { cmd }) => {
-                                handle_cmd_with_rooms(
-                                    &client_id,
-                                    &cmd,
-                                    &clients,
-                                    &histories,
-                                    &private_histories,
-                                    &users,
-                                )
-                                .await;
-                            }
-                            Ok(Incoming::Msg { text }) => {
-                                if check_rate_limit(&clients, &client_id).await {
-                                    handle_message_with_rooms(
-                                        &client_id, &text, &clients, &histories, &metrics,
-                                    )
-                                    .await;
-                                    set_typing_status(&clients, &client_id, false).await;
-                                }
-                            }
-                            Ok(Incoming::Typing { is_typing }) => {
-                                set_typing_status(&clients, &client_id, is_typing).await;
-                                broadcast_typing_status(&clients, &client_id).await;
-                            }
-                            Ok(Incoming::React { msg_id, emoji }) => {
-                                let (room, name) = {
-                                    clients
-                                        .get(&client_id)
-                                        .map(|r| {
-                                            let c = r.value();
-                                            (c.room.clone(), c.name.clone())
-                                        })
-                                        .unwrap_or_default()
-                                };
-                                crate::room::add_reaction(
-                                    &clients, &histories, &room, &msg_id, &emoji, &name,
-                                )
-                                .await;
-                            }
-                            Ok(Incoming::Edit { msg_id, new_text }) => {
-                                let (room, name) = {
-                                    clients
-                                        .get(&client_id)
-                                        .map(|r| {
-                                            let c = r.value();
-                                            (c.room.clone(), c.name.clone())
-                                        })
-                                        .unwrap_or_default()
-                                };
-                                let edited = crate::room::edit_message(
-                                    &clients, &histories, &room, &msg_id, &new_text, &name,
-                                )
-                                .await;
-                                if !edited {
-                                    if let Some(tx) = client_tx_by_id(&clients, &client_id).await {
-                                        let msg = Outgoing::System {
-                                            text: "Cannot edit this message".to_string(),
-                                        };
-                                        if let Ok(payload) = serde_json::to_string(&msg) {
-                                            let _ = tx.send(warp::ws::Message::text(payload));
-                                        }
-                                    }
-                                }
-                            }
-                            Ok(Incoming::Delete { msg_id }) => {
-                                let (room, name) = {
-                                    clients
-                                        .get(&client_id)
-                                        .map(|r| {
-                                            let c = r.value();
-                                            (c.room.clone(), c.name.clone())
-                                        })
-                                        .unwrap_or_default()
-                                };
-                                let deleted = crate::room::delete_message(
-                                    &clients, &histories, &room, &msg_id, &name,
-                                )
-                                .await;
-                                if !deleted {
-                                    if let Some(tx) = client_tx_by_id(&clients, &client_id).await {
-                                        let msg = Outgoing::System {
-                                            text: "Cannot delete this message".to_string(),
-                                        };
-                                        if let Ok(payload) = serde_json::to_string(&msg) {
-                                            let _ = tx.send(warp::ws::Message::text(payload));
-                                        }
-                                    }
-                                }
-                            }
-                            Ok(Incoming::MarkRead { last_msg_id }) => {
-                                let (room, name) = {
+                    match frame {
+                        // Bots skip the name phase, so they may still say
+                        // hello once before anything else
+                        Frame::Message(Incoming::Hello {
+                            version,
+                            min_version,
+                            capabilities,
+                            ..
+                        }) if bot && !said_hello => {
+                            said_hello = true;
+                            match answer_hello(
+                                &tx,
+                                &config,
+                                transport,
+                                &addr,
+                                version,
+                                min_version,
+                                capabilities,
+                            ) {
+                                Some((agreed, caps)) => {
+                                    protocol = agreed;
+                                    let wants_presence = caps.iter().any(|c| c == "presence");
                                     if let Some(mut r) = clients.get_mut(&client_id) {
                                         let c = r.value_mut();
-                                        c.last_read_msg_id = Some(last_msg_id.clone());
-                                        (c.room.clone(), c.name.clone())
-                                    } else {
-                                        (String::new(), String::new())
+                                        c.protocol = agreed;
+                                        c.capabilities = caps;
+                                    }
+                                    if wants_presence {
+                                        send_presence_snapshot(&state, &tx).await;
                                     }
-                                };
-                                if !room.is_empty() {
-                                    crate::room::broadcast_read_receipt(
-                                        &clients,
-                                        &room,
-                                        &name,
-                                        &last_msg_id,
-                                    )
-                                    .await;
                                 }
+                                None => break,
                             }
-                            Err(_) => {
-                                if check_rate_limit(&clients, &client_id).await {
-                                    handle_message_with_rooms(
-                                        &client_id, text, &clients, &histories, &metrics,
-                                    )
-                                    .await;
+                        }
+                        Frame::Message(msg) => {
+                            said_hello = true;
+                            let req_id = msg.req_id();
+                            let result = handle_incoming(&client_id, msg, &state).await;
+                            send_result(&tx, protocol, req_id, result);
+                        }
+                        Frame::Legacy(text) => {
+                            let result = match state.rate_limiter.check_client(
+                                &clients,
+                                &client_id,
+                                Action::Message,
+                            ) {
+                                Ok(()) => {
+                                    handle_message_with_rooms(&client_id, &text, &state).await
                                 }
-                            }
+                                Err(e) => Err(e),
+                            };
+                            send_result(&tx, protocol, None, result);
+                        }
+                        Frame::Invalid(reason) => {
+                            send(&tx, Outgoing::Invalid { reason });
                         }
                     }
                 } else if msg.is_close() {
@@ -370,30 +413,306 @@ pub async fn client_connected(
                 }
             }
             Err(e) => {
-                warn!("websocket error for {}: {}", addr, e);
+                warn!("{} error for {}: {}", transport, addr, e);
                 break;
             }
         }
     }
 
     // Cleanup
-    let left_room = clients.remove(&client_id).map(|(_, c)| c.room);
+    let (left, subscriptions) = match clients.remove(&client_id) {
+        Some((_, c)) => (Some((c.room, c.name)), c.subscriptions),
+        None => (None, Vec::new()),
+    };
+    for room in &subscriptions {
+        send_user_list_to_room(&state, room).await;
+    }
 
-    if let Some(room) = left_room {
-        send_system_to_room(
-            &clients,
-            &histories,
-            &room,
-            &format!("-- {} left the room --", chosen_name),
-        )
-        .await;
-        send_user_list_to_room(&clients, &room).await;
+    if let Some((_, name)) = &left {
+        if !clients
+            .iter()
+            .any(|r| r.value().name.eq_ignore_ascii_case(name))
+        {
+            forget_guest(&state.presences, name);
+        }
+    }
+
+    if let Some((room, name)) = left {
+        if !other_session_in_room(&clients, &client_id, &name, &room) {
+            send_system_to_room(
+                &clients,
+                &histories,
+                &room,
+                &format!("-- {} left the room --", name),
+            )
+            .await;
+            state.outgoing_webhooks.emit(RoomEvent::Leave {
+                room: room.clone(),
+                user: name.clone(),
+            });
+        }
+        send_user_list_to_room(&state, &room).await;
         info!(
             "Client disconnected: {} (name: {}, room: {})",
-            client_id, chosen_name, room
+            client_id, name, room
         );
     }
 
     drop(tx);
     let _ = forward_task.await;
 }
+
+/// Handle one protocol message from a client that has completed the name
+/// phase.
+async fn handle_incoming(client_id: &str, msg: Incoming, state: &AppState) -> ActionResult {
+    let clients = &state.clients;
+    let histories = &state.histories;
+    let (room, name, account, bot) = clients
+        .get(client_id)
+        .map(|r| {
+            let c = r.value();
+            (c.room.clone(), c.name.clone(), c.account.clone(), c.bot)
+        })
+        .ok_or_else(ActionError::unknown_session)?;
+
+    let limiter = &state.rate_limiter;
+    match msg {
+        Incoming::Cmd { cmd, .. } => {
+            limiter.check_client(clients, client_id, Action::Command)?;
+            let cmd = state.commands.parse(&cmd)?;
+            if bot {
+                bot_may_run(&cmd)?;
+            }
+            handle_command(client_id, cmd, state).await
+        }
+        Incoming::Msg {
+            text, room: target, ..
+        } => {
+            // Bots may post to any room they receive
+            let target = target.filter(|t| *t != room);
+            if let Some(t) = &target {
+                let subscribed = clients
+                    .get(client_id)
+                    .map(|r| r.value().in_room(t))
+                    .unwrap_or(false);
+                if !subscribed {
+                    return Err(ActionError::new(
+                        ErrorCode::Forbidden,
+                        format!("Not subscribed to room '{}'", t),
+                    ));
+                }
+            }
+            limiter.check_client(clients, client_id, Action::Message)?;
+            let result = match target {
+                Some(t) => handle_message_in_room(client_id, &t, &text, state).await,
+                None => handle_message_with_rooms(client_id, &text, state).await,
+            };
+            set_typing_status(clients, client_id, false).await;
+            result
+        }
+        Incoming::Typing { is_typing, .. } => {
+            // Over the limit, typing updates are dropped without an error
+            if limiter
+                .check_client(clients, client_id, Action::Typing)
+                .is_err()
+            {
+                return Ok(());
+            }
+            set_typing_status(clients, client_id, is_typing).await;
+            broadcast_typing_status(clients, client_id).await;
+            Ok(())
+        }
+        Incoming::React { msg_id, emoji, .. } => {
+            limiter.check_client(clients, client_id, Action::Reaction)?;
+            crate::room::add_reaction(
+                clients,
+                histories,
+                &state.outgoing_webhooks,
+                &room,
+                &msg_id,
+                &emoji,
+                &name,
+            )
+            .await
+        }
+        Incoming::Edit {
+            msg_id, new_text, ..
+        } => {
+            limiter.check_client(clients, client_id, Action::Edit)?;
+            let (tx, key) = clients
+                .get(client_id)
+                .map(|r| (r.value().tx.clone(), client_key(r.value())))
+                .unzip();
+            let ctx = MessageContext {
+                state,
+                room: &room,
+                sender_role: client_role(clients, &state.roles, client_id),
+                sender_tx: tx.as_ref(),
+                sender_key: key.as_deref(),
+            };
+            crate::room::edit_message(&ctx, &msg_id, &new_text, &name).await
+        }
+        Incoming::Delete { msg_id, .. } => {
+            limiter.check_client(clients, client_id, Action::Edit)?;
+            crate::room::delete_message(clients, histories, &room, &msg_id, Some(&name)).await
+        }
+        Incoming::MarkRead { last_msg_id, .. } => {
+            // Persist the marker and sync counts to all sessions
+            if let Some(acc) = account {
+                if mark_read(&state.read_markers, &acc, &room, &last_msg_id).await {
+                    send_unread_summary(state, &acc, Some(&room)).await;
+                }
+            }
+            if !room.is_empty() {
+                crate::room::broadcast_read_receipt(clients, &room, &name, &last_msg_id).await;
+            }
+            Ok(())
+        }
+        Incoming::Report { msg_id, reason, .. } => {
+            limiter.check_client(clients, client_id, Action::Command)?;
+            crate::reports::file_report(state, client_id, &room, &msg_id, &reason).await
+        }
+        Incoming::Hello { .. } => Err(ActionError::new(
+            ErrorCode::BadRequest,
+            "hello must be sent before choosing a name",
+        )),
+        cmd => {
+            limiter.check_client(clients, client_id, Action::Command)?;
+            if bot {
+                bot_may_run(&cmd)?;
+            }
+            handle_command(client_id, cmd, state).await
+        }
+    }
+}
+
+/// Answer a `hello`: reply with the agreed version and switch the
+/// connection's encoding and compression. When the versions are
+/// incompatible, tell the client, close the connection and return `None`.
+fn answer_hello(
+    tx: &Tx,
+    config: &Config,
+    transport: Transport,
+    addr: &str,
+    version: u32,
+    min_version: Option<u32>,
+    capabilities: Vec<String>,
+) -> Option<(u32, Vec<String>)> {
+    match negotiate(
+        version,
+        min_version,
+        capabilities,
+        &server_features(config, transport),
+    ) {
+        Ok(agreed) => {
+            send(
+                tx,
+                Outgoing::Hello {
+                    version: agreed.version,
+                    min_version: MIN_PROTOCOL_VERSION,
+                    features: server_features(config, transport),
+                },
+            );
+            // The reply itself still goes out as JSON
+            if agreed.capabilities.iter().any(|c| c == "msgpack") {
+                let _ = tx.send(Outbound::SetEncoding(Encoding::MsgPack));
+            }
+            if agreed.capabilities.iter().any(|c| c == "deflate") {
+                let _ = tx.send(Outbound::SetCompression(config.compression_min_bytes));
+            }
+            Some((agreed.version, agreed.capabilities))
+        }
+        Err(reason) => {
+            info!("Rejecting {}: {}", addr, reason);
+            send(
+                tx,
+                Outgoing::Incompatible {
+                    reason,
+                    min_version: MIN_PROTOCOL_VERSION,
+                    max_version: PROTOCOL_VERSION,
+                },
+            );
+            let _ = tx.send(Outbound::Close(1002, "incompatible protocol"));
+            None
+        }
+    }
+}
+
+/// The bot a `/ws?token=...` connection authenticates as.
+fn bot_for_token(state: &AppState, token: &str) -> Result<String, ActionError> {
+    token_account(&state.api_tokens, &state.users, &state.bots, token)
+        .filter(|account| state.bots.contains_key(account))
+        .ok_or_else(|| ActionError::new(ErrorCode::AuthFailed, "invalid bot token"))
+}
+
+/// Handle a name, register or login request (typed or as a slash command)
+/// sent before the client has a name. Returns the display name, the
+/// logged-in account (if any) and a confirmation for the client.
+async fn auth_command(
+    msg: Incoming,
+    state: &AppState,
+    ip: &str,
+) -> Result<(String, Option<String>, String), ActionError> {
+    let clients = &state.clients;
+    let users = &state.users;
+    let msg = match msg {
+        Incoming::Cmd { cmd, .. } => state.commands.parse(&cmd)?,
+        other => other,
+    };
+    match msg {
+        Incoming::Name { name, .. } => {
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(ActionError::usage("/name <username>"));
+            }
+            let name = make_unique_name(clients, name, None).await;
+            let notice = format!("Your name is '{}'. You are not authenticated.", name);
+            Ok((name, None, notice))
+        }
+        Incoming::Register {
+            username, password, ..
+        } => {
+            let username = username.trim().to_string();
+            state.rate_limiter.check(Action::Register, &ip_key(ip))?;
+            register_user(users, &state.bots, &username, password.trim())
+                .await
+                .map_err(|e| ActionError::new(e.code, format!("Register failed: {}", e)))?;
+            let name = make_unique_name(clients, &username, Some(&username)).await;
+            let notice = format!("Registered and logged in as '{}'", name);
+            Ok((name, Some(username), notice))
+        }
+        Incoming::Login {
+            username, password, ..
+        } => {
+            let username = username.trim().to_string();
+            attempt_login(state, &username, password.trim(), ip)
+                .await
+                .map_err(|e| ActionError::new(e.code, format!("Login failed: {}", e)))?;
+            let name = make_unique_name(clients, &username, Some(&username)).await;
+            let notice = format!("Logged in as '{}'", name);
+            Ok((name, Some(username), notice))
+        }
+        _ => Err(ActionError::new(
+            ErrorCode::NotAuthenticated,
+            "Please choose a name or login/register first.",
+        )),
+    }
+}
+
+/// Bots keep the name and account their token gave them.
+fn bot_may_run(cmd: &Incoming) -> ActionResult {
+    match cmd {
+        Incoming::Name { .. } | Incoming::Register { .. } | Incoming::Login { .. } => Err(
+            ActionError::new(ErrorCode::Forbidden, "Bots cannot change name or account."),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Error for actions attempted before the name phase is over.
+fn name_first() -> ActionError {
+    ActionError::new(
+        ErrorCode::NotAuthenticated,
+        "Please choose a name or login/register before sending messages.",
+    )
+}
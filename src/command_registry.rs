@@ -0,0 +1,221 @@
+//! Slash command registry. Every command, built-in or added in `main.rs`,
+//! is a `CommandHandler` registered by name; slash lines and typed command
+//! messages are both dispatched through it, and `/help` is generated from it.
+
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::errors::{ActionError, ActionResult, ErrorCode};
+use crate::roles::{client_role, Role};
+use crate::types::{AppState, Incoming};
+
+/// What a handler gets to work with: the calling session and shared state.
+#[derive(Clone, Copy)]
+pub struct CommandContext<'a> {
+    pub client_id: &'a str,
+    pub state: &'a AppState,
+}
+
+/// A slash command.
+pub trait CommandHandler: Send + Sync {
+    /// Name without the slash, e.g. `"roll"` for `/roll`.
+    fn name(&self) -> &str;
+
+    /// Other names that run the same command.
+    fn aliases(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Usage line shown in `/help` and usage errors, e.g. `"/roll [NdM]"`.
+    fn usage(&self) -> &str;
+
+    /// One-line description for `/help`.
+    fn summary(&self) -> &str;
+
+    /// Lowest role allowed to run the command.
+    fn required_role(&self) -> Role {
+        Role::Guest
+    }
+
+    /// Turn the text after the command name into a message. The default
+    /// wraps it in `Incoming::Command`, which is what commands without a
+    /// typed message of their own receive.
+    fn parse(&self, args: &str) -> Result<Incoming, ActionError> {
+        Ok(Incoming::Command {
+            req_id: None,
+            name: self.name().to_string(),
+            args: args.to_string(),
+        })
+    }
+
+    /// Run the command.
+    fn handle<'a>(&'a self, ctx: CommandContext<'a>, msg: Incoming) -> BoxFuture<'a, ActionResult>;
+}
+
+/// Signature of a built-in command's implementation.
+pub type BuiltinFn = for<'a> fn(CommandContext<'a>, Incoming) -> BoxFuture<'a, ActionResult>;
+
+/// A built-in command: fixed metadata plus a function. Its slash form is
+/// parsed by `commands::parse_builtin` into the matching typed message.
+pub struct Builtin {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub role: Role,
+    pub run: BuiltinFn,
+}
+
+impl CommandHandler for Builtin {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn aliases(&self) -> &[&str] {
+        self.aliases
+    }
+
+    fn usage(&self) -> &str {
+        self.usage
+    }
+
+    fn summary(&self) -> &str {
+        self.summary
+    }
+
+    fn required_role(&self) -> Role {
+        self.role
+    }
+
+    fn parse(&self, args: &str) -> Result<Incoming, ActionError> {
+        crate::commands::parse_builtin(self.name, args)
+    }
+
+    fn handle<'a>(&'a self, ctx: CommandContext<'a>, msg: Incoming) -> BoxFuture<'a, ActionResult> {
+        (self.run)(ctx, msg)
+    }
+}
+
+/// All registered commands, looked up by name or alias (case-insensitive).
+pub struct CommandRegistry {
+    handlers: Vec<Arc<dyn CommandHandler>>,
+    by_name: HashMap<String, usize>,
+}
+
+impl CommandRegistry {
+    /// A registry with no commands.
+    pub fn new() -> Self {
+        CommandRegistry {
+            handlers: Vec::new(),
+            by_name: HashMap::new(),
+        }
+    }
+
+    /// A registry holding the built-in commands.
+    pub fn with_builtins() -> Self {
+        let mut registry = CommandRegistry::new();
+        for builtin in crate::commands::builtins() {
+            registry.register(builtin);
+        }
+        registry
+    }
+
+    /// Add a command. A name or alias that is already taken is moved to the
+    /// new handler, so built-ins can be replaced.
+    pub fn register(&mut self, handler: impl CommandHandler + 'static) {
+        let index = self.handlers.len();
+        let names: Vec<String> = std::iter::once(handler.name())
+            .chain(handler.aliases().iter().copied())
+            .map(str::to_ascii_lowercase)
+            .collect();
+        for name in names {
+            if self.by_name.insert(name.clone(), index).is_some() {
+                warn!(
+                    "command /{} was registered twice; using the later handler",
+                    name
+                );
+            }
+        }
+        self.handlers.push(Arc::new(handler));
+    }
+
+    /// The handler for a name or alias, without the slash.
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn CommandHandler>> {
+        self.by_name
+            .get(&name.to_ascii_lowercase())
+            .map(|&i| &self.handlers[i])
+    }
+
+    /// Parse a slash command line into the message its handler takes.
+    pub fn parse(&self, line: &str) -> Result<Incoming, ActionError> {
+        let line = line.trim();
+        let (word, args) = match line.split_once(char::is_whitespace) {
+            Some((word, args)) => (word, args.trim()),
+            None => (line, ""),
+        };
+        let handler = word
+            .strip_prefix('/')
+            .and_then(|name| self.get(name))
+            .ok_or_else(|| {
+                ActionError::new(
+                    ErrorCode::UnknownCommand,
+                    "Unknown command. Type /help for available commands.",
+                )
+            })?;
+        handler.parse(args)
+    }
+
+    /// Run a command message for a client, after checking its role.
+    pub async fn dispatch(&self, ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+        let handler = msg
+            .command_name()
+            .and_then(|name| self.get(name))
+            .ok_or_else(|| ActionError::new(ErrorCode::BadRequest, "Not a command"))?;
+        let required = handler.required_role();
+        let role = client_role(&ctx.state.clients, &ctx.state.roles, ctx.client_id);
+        if role < required {
+            return Err(role_error(handler.name(), required));
+        }
+        handler.handle(ctx, msg).await
+    }
+
+    /// `/help` text: the commands `role` may run, in registration order.
+    pub fn help_text(&self, role: Role) -> String {
+        let lines: Vec<String> = self
+            .handlers
+            .iter()
+            .enumerate()
+            // Skip handlers whose every name was taken over by a later one
+            .filter(|(i, h)| self.by_name.get(&h.name().to_ascii_lowercase()) == Some(i))
+            .filter(|(_, h)| role >= h.required_role())
+            .map(|(_, h)| format!("  {} - {}", h.usage(), h.summary()))
+            .collect();
+        format!("Available commands:\n{}", lines.join("\n"))
+    }
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        CommandRegistry::new()
+    }
+}
+
+/// Error for a command run without the role it needs.
+fn role_error(name: &str, required: Role) -> ActionError {
+    match required {
+        Role::Guest | Role::User => ActionError::new(
+            ErrorCode::NotAuthenticated,
+            format!("You must be logged in to use /{}.", name),
+        ),
+        Role::Moderator => ActionError::new(
+            ErrorCode::Forbidden,
+            format!("Only moderators and admins can use /{}.", name),
+        ),
+        Role::Admin => ActionError::new(
+            ErrorCode::Forbidden,
+            format!("Only admins can use /{}.", name),
+        ),
+    }
+}
@@ -1,448 +1,1859 @@
-//! Command handling for chat commands.
+//! Built-in chat commands: slash parsing and one handler per command,
+//! registered through `command_registry.rs`.
 
-use crate::auth::{register_user, verify_login};
-use crate::helpers::{client_name_by_id, client_tx_by_id, make_unique_name, now_ts};
+use crate::audit::actor_name;
+use crate::auth::{register_user, user_name};
+use crate::bots::{bot_name, create_bot, revoke_tokens_for, save_bots_async, valid_bot_name};
+use crate::command_registry::{Builtin, CommandContext};
+use crate::encoding::{send, Payload};
+use crate::errors::{ActionError, ActionResult, ErrorCode};
+use crate::helpers::{
+    client_name_by_id, client_tx_by_id, disconnect_sessions, make_unique_name, now_ts,
+    session_ids_by_account, session_ids_by_name,
+};
+use crate::incoming_webhooks::{create_webhook, hook_path, save_webhooks_async};
+use crate::login_guard::attempt_login;
+use crate::mentions::{clear_inbox, notify_pending, send_inbox};
+use crate::presence::{
+    broadcast_user_presence, forget_guest, is_dnd, rename_guest, set_user_status, snapshot_all,
+};
+use crate::rate_limit::{client_key, ip_key, Action};
+use crate::reports::{open_reports, resolve_report, ReportAction};
+use crate::roles::{client_role, save_roles_async, Role};
 use crate::room::{
-    broadcast_to_room_and_store, generate_msg_id, join_room, send_history_to_client_room,
-    send_system_to_room, send_user_list_to_room,
+    dm_key, join_room, post_to_room, room_list, send_history_to_client_room, send_system_to_room,
+    send_user_list_to_room,
+};
+use crate::room_policy::{claim_post, MAX_SLOW_MODE_SECS};
+use crate::room_settings::{
+    guest_posting, read_only, restrictions, save_room_settings_async, set_filter,
+    set_guest_posting, set_read_only, set_slow_mode, set_word_filter_mode, slow_mode,
+    word_filter_mode,
 };
-use crate::types::{Clients, Histories, HistoryItem, Outgoing, RoomInfo, Users};
+use crate::tokens::{issue_token, save_tokens_async};
+use crate::types::{AppState, Clients, HistoryItem, Incoming, Outgoing, PresenceMode};
+use crate::unread::{mark_current_room_read, send_unread_summary};
+use crate::word_filter::WordFilterMode;
 use std::collections::{HashMap, VecDeque};
 use tracing::info;
 
-/// Handle all `/` commands from a connected client.
-pub async fn handle_cmd_with_rooms(
-    client_id: &str,
-    cmd_line: &str,
-    clients: &Clients,
-    histories: &Histories,
-    private_histories: &crate::types::PrivateHistories,
-    users: &Users,
-) {
-    let mut parts = cmd_line.splitn(3, ' ');
-    let cmd = parts.next().unwrap_or("");
+/// Parse the arguments of a built-in command (`name` without the slash)
+/// into its typed message. The typed `Incoming` variants are the command
+/// API; this is only a frontend for them.
+pub fn parse_builtin(name: &str, rest: &str) -> Result<Incoming, ActionError> {
+    let (first, tail) = split_word(rest);
+    let req_id = None;
 
-    match cmd {
-        "/join" => {
-            if let Some(room) = parts.next() {
-                join_room(client_id, room.trim(), clients, histories).await;
-            } else {
-                send_to_client(clients, client_id, "Usage: /join <room>").await;
-            }
+    let required = |value: &str, usage: &str| {
+        if value.is_empty() {
+            Err(ActionError::usage(usage))
+        } else {
+            Ok(value.to_string())
         }
-        "/rooms" => {
-            let room_list: Vec<RoomInfo> = {
-                let locked_h = histories.read().await;
-                locked_h
-                    .keys()
-                    .map(|room_name| {
-                        let member_count = clients
-                            .iter()
-                            .filter(|r| &r.value().room == room_name)
-                            .count();
-                        RoomInfo {
-                            name: room_name.clone(),
-                            members: member_count,
-                        }
-                    })
-                    .collect()
-            };
-            // Send structured room list
-            let msg = Outgoing::RoomList { rooms: room_list };
-            if let Some(tx) = client_tx_by_id(clients, client_id).await {
-                if let Ok(s) = serde_json::to_string(&msg) {
-                    let _ = tx.send(warp::ws::Message::text(s));
+    };
+    let optional = |value: &str| (!value.is_empty()).then(|| value.to_string());
+
+    let msg = match name {
+        "join" => Incoming::Join {
+            req_id,
+            room: required(rest, "/join <room>")?,
+        },
+        "leave" => Incoming::Leave { req_id },
+        "rooms" => Incoming::Rooms { req_id },
+        "room" => Incoming::Room { req_id },
+        "name" => Incoming::Name {
+            req_id,
+            name: required(first, "/name <new_name>")?,
+        },
+        "list" => Incoming::List { req_id },
+        "register" | "login" => {
+            let usage = format!("/{} <username> <password>", name);
+            let username = required(first, &usage)?;
+            let password = required(tail, &usage)?;
+            if name == "register" {
+                Incoming::Register {
+                    req_id,
+                    username,
+                    password,
+                }
+            } else {
+                Incoming::Login {
+                    req_id,
+                    username,
+                    password,
                 }
             }
         }
-        "/leave" => {
-            join_room(client_id, "lobby", clients, histories).await;
+        "history" => Incoming::History { req_id },
+        "msg" => Incoming::Dm {
+            req_id,
+            to: required(first, "/msg <user> <text>")?,
+            text: required(tail, "/msg <user> <text>")?,
+        },
+        "kick" => Incoming::Kick {
+            req_id,
+            user: required(first, "/kick <user> [reason]")?,
+            reason: optional(tail),
+        },
+        "stats" => Incoming::Stats { req_id },
+        "help" => Incoming::Help { req_id },
+        "who" => Incoming::Who { req_id },
+        "sessions" => Incoming::Sessions { req_id },
+        "mentions" => Incoming::Mentions {
+            req_id,
+            clear: first == "clear",
+        },
+        "role" => Incoming::Role {
+            req_id,
+            user: required(first, "/role <user> [role]")?,
+            role: optional(tail),
+        },
+        "away" => Incoming::Away {
+            req_id,
+            message: optional(rest),
+        },
+        "dnd" => Incoming::Dnd {
+            req_id,
+            message: optional(rest),
+        },
+        "back" => Incoming::Back { req_id },
+        "status" if first == "clear" => Incoming::Status {
+            req_id,
+            emoji: None,
+            text: None,
+        },
+        "status" => Incoming::Status {
+            req_id,
+            emoji: Some(required(first, "/status <emoji> [text] | /status clear")?),
+            text: optional(tail),
+        },
+        "nudge" => Incoming::Nudge { req_id },
+        "webhook" => {
+            const USAGE: &str =
+                "/webhook create <room> [name] | /webhook list | /webhook delete <id>";
+            let (arg, more) = split_word(tail);
+            match first {
+                "create" => Incoming::Webhook {
+                    req_id,
+                    action: first.to_string(),
+                    room: Some(required(arg, USAGE)?),
+                    name: optional(more),
+                    id: None,
+                },
+                "list" => Incoming::Webhook {
+                    req_id,
+                    action: first.to_string(),
+                    room: None,
+                    name: None,
+                    id: None,
+                },
+                "delete" => Incoming::Webhook {
+                    req_id,
+                    action: first.to_string(),
+                    room: None,
+                    name: None,
+                    id: Some(required(arg, USAGE)?),
+                },
+                _ => return Err(ActionError::usage(USAGE)),
+            }
         }
-        "/room" => {
-            let room = clients
-                .get(client_id)
-                .map(|r| r.value().room.clone())
-                .unwrap_or_else(|| "lobby".to_string());
-            send_to_client(clients, client_id, &format!("Current room: {}", room)).await;
+        "bot" => {
+            const USAGE: &str =
+                "/bot create <name> | /bot token <name> | /bot delete <name> | /bot list";
+            match first {
+                "create" | "token" | "delete" => Incoming::Bot {
+                    req_id,
+                    action: first.to_string(),
+                    name: Some(required(tail, USAGE)?),
+                },
+                "list" => Incoming::Bot {
+                    req_id,
+                    action: first.to_string(),
+                    name: None,
+                },
+                _ => return Err(ActionError::usage(USAGE)),
+            }
         }
-        "/name" => {
-            if let Some(newname) = parts.next() {
-                let newname = newname.trim();
-                if newname.is_empty() {
-                    send_to_client(clients, client_id, "Usage: /name <new_name>").await;
-                    return;
-                }
-                let unique_name = make_unique_name(clients, newname).await;
-                let old_name = client_name_by_id(clients, client_id).await;
-                if let Some(mut r) = clients.get_mut(client_id) {
-                    r.value_mut().name = unique_name.clone();
-                }
-                let room = get_client_room(clients, client_id).await;
-                send_system_to_room(
-                    clients,
-                    histories,
-                    &room,
-                    &format!("-- {} is now known as {} --", old_name, unique_name),
-                )
-                .await;
-                send_user_list_to_room(clients, &room).await;
-                send_to_client(
-                    clients,
-                    client_id,
-                    &format!("Your name is now '{}'", unique_name),
-                )
-                .await;
-                info!(
-                    "Client {} (id: {}) changed name to {}",
-                    old_name, client_id, unique_name
-                );
+        "filter" => Incoming::Filter {
+            req_id,
+            name: optional(first),
+            setting: match first {
+                "" => None,
+                _ => Some(required(tail, "/filter [<name> on|off|default]")?),
+            },
+        },
+        "reports" => {
+            const USAGE: &str = "/reports [dismiss|delete <id> | mute <id> [minutes]]";
+            let (arg, more) = split_word(tail);
+            match first {
+                "" => Incoming::Reports {
+                    req_id,
+                    action: None,
+                    id: None,
+                    minutes: None,
+                },
+                "dismiss" | "delete" | "mute" => Incoming::Reports {
+                    req_id,
+                    action: Some(first.to_string()),
+                    id: Some(required(arg, USAGE)?),
+                    minutes: match more {
+                        "" => None,
+                        m => Some(m.parse().map_err(|_| ActionError::usage(USAGE))?),
+                    },
+                },
+                _ => return Err(ActionError::usage(USAGE)),
             }
         }
-        "/list" => {
-            let room = get_client_room(clients, client_id).await;
-            send_user_list_to_room(clients, &room).await;
+        "slowmode" => Incoming::SlowMode {
+            req_id,
+            seconds: match first {
+                "" => None,
+                "off" => Some(0),
+                n => Some(
+                    n.trim_end_matches('s')
+                        .parse()
+                        .map_err(|_| ActionError::usage("/slowmode [<seconds>|off]"))?,
+                ),
+            },
+        },
+        "readonly" => Incoming::ReadOnly {
+            req_id,
+            enabled: on_off(first, "/readonly [on|off]")?,
+        },
+        "guestposting" => Incoming::GuestPosting {
+            req_id,
+            enabled: on_off(first, "/guestposting [on|off]")?,
+        },
+        "audit" => Incoming::Audit {
+            req_id,
+            query: optional(first),
+        },
+        "wordfilter" => Incoming::WordFilter {
+            req_id,
+            mode: optional(first),
+        },
+        "subscribe" => Incoming::Subscribe {
+            req_id,
+            room: required(rest, "/subscribe <room>")?,
+        },
+        "unsubscribe" => Incoming::Unsubscribe {
+            req_id,
+            room: required(rest, "/unsubscribe <room>")?,
+        },
+        _ => {
+            return Err(ActionError::new(
+                ErrorCode::UnknownCommand,
+                "Unknown command. Type /help for available commands.",
+            ));
         }
-        "/register" => {
-            if let (Some(username), Some(password)) = (parts.next(), parts.next()) {
-                match register_user(users, username.trim(), password.trim()).await {
-                    Ok(_) => {
-                        send_to_client(
-                            clients,
-                            client_id,
-                            &format!(
-                                "Registered '{}'. Use /login to authenticate.",
-                                username.trim()
-                            ),
-                        )
-                        .await;
-                    }
-                    Err(e) => {
-                        send_to_client(clients, client_id, &format!("Register failed: {}", e))
-                            .await;
-                    }
-                }
+    };
+    Ok(msg)
+}
+
+/// Parse an optional `on`/`off` argument.
+fn on_off(arg: &str, usage: &str) -> Result<Option<bool>, ActionError> {
+    match arg {
+        "" => Ok(None),
+        "on" => Ok(Some(true)),
+        "off" => Ok(Some(false)),
+        _ => Err(ActionError::usage(usage)),
+    }
+}
+
+/// Execute a typed command from a connected client through the command
+/// registry. Failures are returned to the caller, which reports them with
+/// the request's `req_id`.
+pub async fn handle_command(client_id: &str, cmd: Incoming, state: &AppState) -> ActionResult {
+    state
+        .commands
+        .dispatch(CommandContext { client_id, state }, cmd)
+        .await
+}
+
+/// The built-in commands, in `/help` order.
+pub fn builtins() -> Vec<Builtin> {
+    macro_rules! builtin {
+        ($name:literal, $aliases:expr, $usage:literal, $summary:literal, $role:expr, $run:ident) => {
+            Builtin {
+                name: $name,
+                aliases: $aliases,
+                usage: $usage,
+                summary: $summary,
+                role: $role,
+                run: |ctx, msg| Box::pin($run(ctx, msg)),
             }
+        };
+    }
+    use Role::{Admin, Guest, Moderator, User};
+    vec![
+        builtin!(
+            "name",
+            &[],
+            "/name <name>",
+            "Set your display name",
+            Guest,
+            cmd_name
+        ),
+        builtin!(
+            "register",
+            &[],
+            "/register <u> <p>",
+            "Create an account",
+            Guest,
+            cmd_register
+        ),
+        builtin!(
+            "login",
+            &[],
+            "/login <u> <p>",
+            "Log in to your account",
+            Guest,
+            cmd_login
+        ),
+        builtin!(
+            "msg",
+            &["dm"],
+            "/msg <user> <text>",
+            "Private message a user",
+            Guest,
+            cmd_dm
+        ),
+        builtin!(
+            "join",
+            &[],
+            "/join <room>",
+            "Join or create a room",
+            Guest,
+            cmd_join
+        ),
+        builtin!("leave", &[], "/leave", "Return to lobby", Guest, cmd_leave),
+        builtin!("rooms", &[], "/rooms", "List all rooms", Guest, cmd_rooms),
+        builtin!("room", &[], "/room", "Show current room", Guest, cmd_room),
+        builtin!("list", &[], "/list", "List users in room", Guest, cmd_list),
+        builtin!("who", &[], "/who", "Show users with status", Guest, cmd_who),
+        builtin!(
+            "sessions",
+            &[],
+            "/sessions",
+            "List your connected devices",
+            Guest,
+            cmd_sessions
+        ),
+        builtin!(
+            "away",
+            &[],
+            "/away [msg]",
+            "Mark yourself away",
+            Guest,
+            cmd_away
+        ),
+        builtin!(
+            "dnd",
+            &[],
+            "/dnd [msg]",
+            "Do not disturb (mutes nudges and mention alerts)",
+            Guest,
+            cmd_dnd
+        ),
+        builtin!(
+            "back",
+            &[],
+            "/back",
+            "Clear away / do not disturb",
+            Guest,
+            cmd_back
+        ),
+        builtin!(
+            "status",
+            &[],
+            "/status <emoji> [text]",
+            "Set a custom status (/status clear)",
+            Guest,
+            cmd_status
+        ),
+        builtin!(
+            "mentions",
+            &[],
+            "/mentions [clear]",
+            "Show (or clear) your mention inbox",
+            Guest,
+            cmd_mentions
+        ),
+        builtin!(
+            "role",
+            &[],
+            "/role <user> [r]",
+            "Show a role, or set one (admin only)",
+            Guest,
+            cmd_role
+        ),
+        builtin!(
+            "filter",
+            &[],
+            "/filter [<name> on|off|default]",
+            "Show or switch this room's message filters",
+            Moderator,
+            cmd_filter
+        ),
+        builtin!(
+            "wordfilter",
+            &[],
+            "/wordfilter [off|mask|block|flag]",
+            "Show or set this room's word filter mode",
+            Moderator,
+            cmd_wordfilter
+        ),
+        builtin!(
+            "slowmode",
+            &[],
+            "/slowmode [<seconds>|off]",
+            "Show or set this room's slow mode",
+            Moderator,
+            cmd_slowmode
+        ),
+        builtin!(
+            "readonly",
+            &[],
+            "/readonly [on|off]",
+            "Let only moderators post in this room",
+            Moderator,
+            cmd_readonly
+        ),
+        builtin!(
+            "guestposting",
+            &[],
+            "/guestposting [on|off]",
+            "Allow or forbid guest posts in this room",
+            Moderator,
+            cmd_guestposting
+        ),
+        builtin!(
+            "reports",
+            &[],
+            "/reports [dismiss|delete|mute <id>]",
+            "Review and resolve reported messages",
+            Moderator,
+            cmd_reports
+        ),
+        builtin!(
+            "audit",
+            &[],
+            "/audit [user|room]",
+            "Show recent moderation and admin actions",
+            Admin,
+            cmd_audit
+        ),
+        builtin!(
+            "webhook",
+            &[],
+            "/webhook create <room> [name] | list | delete <id>",
+            "Manage incoming webhooks",
+            Admin,
+            cmd_webhook
+        ),
+        builtin!(
+            "bot",
+            &[],
+            "/bot create|token|delete <name> | list",
+            "Manage bot accounts",
+            Admin,
+            cmd_bot
+        ),
+        builtin!(
+            "subscribe",
+            &[],
+            "/subscribe <room>",
+            "Also receive a room's messages (bots only)",
+            Guest,
+            cmd_subscribe
+        ),
+        builtin!(
+            "unsubscribe",
+            &[],
+            "/unsubscribe <room>",
+            "Stop receiving a subscribed room (bots only)",
+            Guest,
+            cmd_unsubscribe
+        ),
+        builtin!(
+            "kick",
+            &[],
+            "/kick <user> [reason]",
+            "Kick a user",
+            User,
+            cmd_kick
+        ),
+        builtin!(
+            "nudge",
+            &[],
+            "/nudge",
+            "Send a nudge (shake screen)",
+            Guest,
+            cmd_nudge
+        ),
+        builtin!(
+            "history",
+            &[],
+            "/history",
+            "Reload chat history",
+            Guest,
+            cmd_history
+        ),
+        builtin!(
+            "stats",
+            &[],
+            "/stats",
+            "Show server metrics",
+            Guest,
+            cmd_stats
+        ),
+        builtin!("help", &[], "/help", "Show this help", Guest, cmd_help),
+    ]
+}
+
+/// `/join <room>`: join or create a room.
+async fn cmd_join(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Join { room, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let room = room.trim();
+    if room.is_empty() {
+        return Err(ActionError::usage("/join <room>"));
+    }
+    join_room(client_id, room, state).await;
+    mark_current_room_read(state, client_id).await;
+    Ok(())
+}
+
+/// `/rooms`: list all rooms.
+async fn cmd_rooms(ctx: CommandContext<'_>, _msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let clients = &state.clients;
+    let histories = &state.histories;
+    // Send structured room list
+    let msg = Outgoing::RoomList {
+        rooms: room_list(clients, histories).await,
+    };
+    if let Some(tx) = client_tx_by_id(clients, client_id).await {
+        send(&tx, msg);
+    }
+    Ok(())
+}
+
+/// `/leave`: return to lobby.
+async fn cmd_leave(ctx: CommandContext<'_>, _msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    join_room(client_id, "lobby", state).await;
+    mark_current_room_read(state, client_id).await;
+    Ok(())
+}
+
+/// `/room`: show current room.
+async fn cmd_room(ctx: CommandContext<'_>, _msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let clients = &state.clients;
+    let room = clients
+        .get(client_id)
+        .map(|r| r.value().room.clone())
+        .unwrap_or_else(|| "lobby".to_string());
+    let notes = restrictions(&state.room_settings, &room);
+    let text = if notes.is_empty() {
+        format!("Current room: {}", room)
+    } else {
+        format!("Current room: {} ({})", room, notes.join(", "))
+    };
+    send_to_client(clients, client_id, &text).await;
+    Ok(())
+}
+
+/// `/name <name>`: set your display name.
+async fn cmd_name(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Name { name, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let clients = &state.clients;
+    let histories = &state.histories;
+    let newname = name.trim();
+    if newname.is_empty() {
+        return Err(ActionError::usage("/name <new_name>"));
+    }
+    let account = clients
+        .get(client_id)
+        .and_then(|r| r.value().account.clone());
+    let unique_name = make_unique_name(clients, newname, account.as_deref()).await;
+    let old_name = client_name_by_id(clients, client_id).await;
+    // Rename every session of the account so they stay one user
+    for mut r in clients.iter_mut() {
+        let same_user = match &account {
+            Some(acc) => r.value().account.as_ref() == Some(acc),
+            None => r.key() == client_id,
+        };
+        if same_user {
+            r.value_mut().name = unique_name.clone();
         }
-        "/login" => {
-            if let (Some(username), Some(password)) = (parts.next(), parts.next()) {
-                if verify_login(users, username.trim(), password.trim()).await {
-                    let unique_name = make_unique_name(clients, username.trim()).await;
-                    if let Some(mut r) = clients.get_mut(client_id) {
-                        let c = r.value_mut();
-                        c.name = unique_name.clone();
-                        c.logged_in = true;
-                    }
-                    let room = get_client_room(clients, client_id).await;
-                    send_system_to_room(
-                        clients,
-                        histories,
-                        &room,
-                        &format!("-- {} logged in --", unique_name),
-                    )
-                    .await;
-                    send_user_list_to_room(clients, &room).await;
-                    send_to_client(
-                        clients,
-                        client_id,
-                        &format!("Logged in as '{}'", unique_name),
-                    )
-                    .await;
-                    info!("Client {} logged in as {}", client_id, unique_name);
-                } else {
-                    send_to_client(clients, client_id, "Login failed: invalid credentials").await;
-                }
-            }
+    }
+    let room = get_client_room(clients, client_id).await;
+    send_system_to_room(
+        clients,
+        histories,
+        &room,
+        &format!("-- {} is now known as {} --", old_name, unique_name),
+    )
+    .await;
+    send_user_list_to_room(state, &room).await;
+    if account.is_none() && rename_guest(&state.presences, &old_name, &unique_name) {
+        broadcast_user_presence(state, &unique_name).await;
+    }
+    send_to_client(
+        clients,
+        client_id,
+        &format!("Your name is now '{}'", unique_name),
+    )
+    .await;
+    info!(
+        "Client {} (id: {}) changed name to {}",
+        old_name, client_id, unique_name
+    );
+    Ok(())
+}
+
+/// `/list`: list users in room.
+async fn cmd_list(ctx: CommandContext<'_>, _msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let clients = &state.clients;
+    let room = get_client_room(clients, client_id).await;
+    send_user_list_to_room(state, &room).await;
+    Ok(())
+}
+
+/// `/register <u> <p>`: create an account.
+async fn cmd_register(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Register {
+        username, password, ..
+    } = msg
+    else {
+        return Err(wrong_message());
+    };
+    let clients = &state.clients;
+    let users = &state.users;
+    let username = username.trim();
+    // Keyed by IP address, so logging in does not reset the limit
+    let ip = clients
+        .get(client_id)
+        .map(|r| r.value().ip.clone())
+        .unwrap_or_default();
+    state.rate_limiter.check(Action::Register, &ip_key(&ip))?;
+    register_user(users, &state.bots, username, password.trim())
+        .await
+        .map_err(|e| ActionError::new(e.code, format!("Register failed: {}", e)))?;
+    send_to_client(
+        clients,
+        client_id,
+        &format!("Registered '{}'. Use /login to authenticate.", username),
+    )
+    .await;
+    Ok(())
+}
+
+/// `/login <u> <p>`: log in to your account.
+async fn cmd_login(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Login {
+        username, password, ..
+    } = msg
+    else {
+        return Err(wrong_message());
+    };
+    let clients = &state.clients;
+    let histories = &state.histories;
+    let username = username.trim();
+    let ip = clients
+        .get(client_id)
+        .map(|r| r.value().ip.clone())
+        .unwrap_or_default();
+    attempt_login(state, username, password.trim(), &ip)
+        .await
+        .map_err(|e| ActionError::new(e.code, format!("Login failed: {}", e)))?;
+    let unique_name = make_unique_name(clients, username, Some(username)).await;
+    if let Some(mut r) = clients.get_mut(client_id) {
+        let c = r.value_mut();
+        // A guest's status does not carry over to the account
+        if c.account.is_none() {
+            forget_guest(&state.presences, &c.name);
         }
-        "/history" => {
-            if let Some(tx) = client_tx_by_id(clients, client_id).await {
-                let room = get_client_room(clients, client_id).await;
-                send_history_to_client_room(&tx, histories, &room).await;
+        c.name = unique_name.clone();
+        c.logged_in = true;
+        c.account = Some(username.to_string());
+    }
+    let room = get_client_room(clients, client_id).await;
+    send_system_to_room(
+        clients,
+        histories,
+        &room,
+        &format!("-- {} logged in --", unique_name),
+    )
+    .await;
+    send_user_list_to_room(state, &room).await;
+    send_to_client(
+        clients,
+        client_id,
+        &format!("Logged in as '{}'", unique_name),
+    )
+    .await;
+    mark_current_room_read(state, client_id).await;
+    send_unread_summary(state, username, None).await;
+    notify_pending(state, client_id).await;
+    info!("Client {} logged in as {}", client_id, unique_name);
+    Ok(())
+}
+
+/// `/history`: reload chat history.
+async fn cmd_history(ctx: CommandContext<'_>, _msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let clients = &state.clients;
+    let histories = &state.histories;
+    if let Some(tx) = client_tx_by_id(clients, client_id).await {
+        let room = get_client_room(clients, client_id).await;
+        send_history_to_client_room(&tx, histories, &room).await;
+    }
+    Ok(())
+}
+
+/// `/msg <user> <text>`: private message a user.
+async fn cmd_dm(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Dm { to, text, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let clients = &state.clients;
+    let private_histories = &state.private_histories;
+    let target_name = to.trim();
+    if target_name.is_empty() || text.trim().is_empty() {
+        return Err(ActionError::usage("/msg <user> <text>"));
+    }
+    let current_room = get_client_room(clients, client_id).await;
+    let target_in_room = clients.iter().any(|r| {
+        let c = r.value();
+        c.name.eq_ignore_ascii_case(target_name) && c.in_room(&current_room)
+    });
+    if target_in_room {
+        let from = client_name_by_id(clients, client_id).await;
+        let mut stored = HistoryItem::new(from.clone(), text);
+        stored.bot = clients
+            .get(client_id)
+            .map(|r| r.value().bot)
+            .unwrap_or(false);
+        let item = Outgoing::Msg {
+            id: stored.id.clone(),
+            from: from.clone(),
+            text: stored.text.clone(),
+            ts: stored.ts,
+            reactions: HashMap::new(),
+            edited: false,
+            bot: stored.bot,
+            icon: None,
+            room: None,
+        };
+        // Fan out to every session of the recipient and to the
+        // sender's other sessions so all devices see the DM.
+        let mut recipients = session_ids_by_name(clients, target_name);
+        recipients.extend(
+            session_ids_by_name(clients, &from)
+                .into_iter()
+                .filter(|id| id != client_id),
+        );
+        let payload = Payload::new(item);
+        for id in &recipients {
+            if let Some(tx) = client_tx_by_id(clients, id).await {
+                payload.send_to(&tx);
             }
         }
-        "/msg" => {
-            if let (Some(target), Some(text)) = (parts.next(), parts.next()) {
-                let target_name = target.trim();
-                let target_name_key = target_name.to_lowercase();
-                let maybe_tx = {
-                    let current_room = clients
-                        .get(client_id)
-                        .map(|r| r.value().room.clone())
-                        .unwrap_or_else(|| "lobby".to_string());
-                    clients
-                        .iter()
-                        .find(|r| {
-                            let c = r.value();
-                            c.name.eq_ignore_ascii_case(target_name) && c.room == current_room
-                        })
-                        .map(|r| r.value().tx.clone())
-                };
-                if let Some(tx) = maybe_tx {
-                    let from = client_name_by_id(clients, client_id).await;
-                    let msg_id = generate_msg_id();
-                    let item = Outgoing::Msg {
-                        id: msg_id.clone(),
-                        from: from.clone(),
-                        text: text.to_string(),
-                        ts: now_ts(),
-                        reactions: HashMap::new(),
-                        edited: false,
-                    };
-                    if let Ok(s) = serde_json::to_string(&item) {
-                        let _ = tx.send(warp::ws::Message::text(s));
-                    }
 
-                    // Securely store in PrivateHistories
-                    // Key: "alfred,batman" (sorted, case-insensitive)
-                    let mut participants = [from.to_lowercase(), target_name_key];
-                    participants.sort_unstable();
-                    let key = participants.join(",");
-
-                    let mut locked_ph = private_histories.write().await;
-                    let q = locked_ph
-                        .entry(key)
-                        .or_insert_with(|| VecDeque::with_capacity(200));
-                    q.push_back(HistoryItem {
-                        id: msg_id,
-                        from,
-                        text: text.to_string(),
-                        ts: now_ts(),
-                        reactions: HashMap::new(),
-                        edited: false,
-                        deleted: false,
-                    });
-                    while q.len() > 200 {
-                        q.pop_front();
-                    }
-                } else {
-                    send_to_client(
-                        clients,
-                        client_id,
-                        &format!("User '{}' not found in your room", target.trim()),
-                    )
-                    .await;
-                }
-            }
+        // Securely store in PrivateHistories
+        let key = dm_key(&from, target_name);
+
+        let mut locked_ph = private_histories.write().await;
+        let q = locked_ph
+            .entry(key)
+            .or_insert_with(|| VecDeque::with_capacity(200));
+        q.push_back(stored);
+        while q.len() > 200 {
+            q.pop_front();
         }
-        "/kick" => {
-            if let Some(target) = parts.next() {
-                let target_name = target.trim();
-                let is_logged_in = clients
-                    .get(client_id)
-                    .map(|r| r.value().logged_in)
-                    .unwrap_or(false);
-
-                if !is_logged_in {
-                    send_to_client(clients, client_id, "You must be logged in to kick users.")
-                        .await;
-                    return;
-                }
+    } else {
+        return Err(ActionError::new(
+            ErrorCode::NotFound,
+            format!("User '{}' not found in your room", target_name),
+        ));
+    }
+    Ok(())
+}
 
-                let maybe_target_id = clients
-                    .iter()
-                    .find(|r| r.value().name.eq_ignore_ascii_case(target_name))
-                    .map(|r| r.key().clone());
+/// `/kick <user> [reason]`: kick a user.
+async fn cmd_kick(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Kick { user, reason, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let clients = &state.clients;
+    let histories = &state.histories;
+    let target_name = user.trim();
+    if target_name.is_empty() {
+        return Err(ActionError::usage("/kick <user> [reason]"));
+    }
+    let target_ids = session_ids_by_name(clients, target_name);
 
-                if let Some(tid) = maybe_target_id {
-                    if tid == client_id {
-                        send_to_client(clients, client_id, "You cannot kick yourself!").await;
-                        return;
-                    }
-                    let target_disp_name = clients
-                        .get(&tid)
-                        .map(|r| r.value().name.clone())
-                        .unwrap_or_default();
-                    let room = get_client_room(clients, client_id).await;
-
-                    // Notify room
-                    send_system_to_room(
-                        clients,
-                        histories,
-                        &room,
-                        &format!("-- {} has been kicked by an admin --", target_disp_name),
-                    )
-                    .await;
-
-                    // Close connection (by removing from clients)
-                    clients.remove(&tid);
-                    info!("Client {} was kicked by {}", target_disp_name, client_id);
-                } else {
-                    send_to_client(
-                        clients,
-                        client_id,
-                        &format!("User '{}' not found", target.trim()),
-                    )
-                    .await;
-                }
+    if !target_ids.is_empty() {
+        if target_ids.iter().any(|tid| tid == client_id) {
+            return Err(ActionError::new(
+                ErrorCode::Forbidden,
+                "You cannot kick yourself!",
+            ));
+        }
+        let target_disp_name = clients
+            .get(&target_ids[0])
+            .map(|r| r.value().name.clone())
+            .unwrap_or_default();
+        let room = get_client_room(clients, client_id).await;
+
+        // Notify room
+        send_system_to_room(
+            clients,
+            histories,
+            &room,
+            &match reason.as_deref().map(str::trim).filter(|r| !r.is_empty()) {
+                Some(r) => format!(
+                    "-- {} has been kicked by an admin ({}) --",
+                    target_disp_name, r
+                ),
+                None => format!("-- {} has been kicked by an admin --", target_disp_name),
+            },
+        )
+        .await;
+
+        disconnect_sessions(clients, &target_ids, "kicked");
+        state.audit.record(
+            &actor_name(clients, client_id),
+            "kick",
+            &target_disp_name,
+            Some(&room),
+            reason.as_deref().map(str::trim).filter(|r| !r.is_empty()),
+        );
+        info!(
+            "Client {} ({} sessions) was kicked by {}",
+            target_disp_name,
+            target_ids.len(),
+            client_id
+        );
+    } else {
+        return Err(ActionError::new(
+            ErrorCode::NotFound,
+            format!("User '{}' not found", target_name),
+        ));
+    }
+    Ok(())
+}
+
+/// `/stats`: show server metrics.
+async fn cmd_stats(ctx: CommandContext<'_>, _msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let clients = &state.clients;
+    let histories = &state.histories;
+    let total_clients = clients.len();
+    let total_rooms = histories.read().await.len();
+    let total_messages: usize = histories.read().await.values().map(|v| v.len()).sum();
+
+    // Memory Usage (Approximate using /proc/self/statm on Linux)
+    let mem_usage = if let Ok(content) = std::fs::read_to_string("/proc/self/statm") {
+        let parts: Vec<&str> = content.split_whitespace().collect();
+        if let Some(pages) = parts.get(1) {
+            if let Ok(pages_cnt) = pages.parse::<usize>() {
+                format!("{:.2} MB", (pages_cnt * 4) as f64 / 1024.0) // Assuming 4KB pages
             } else {
-                send_to_client(clients, client_id, "Usage: /kick <user>").await;
+                "N/A".to_string()
             }
+        } else {
+            "N/A".to_string()
+        }
+    } else {
+        "N/A".to_string()
+    };
+
+    let stats_msg = format!(
+        "Server Stats:\nClients: {}\nRooms: {}\nMessages: {}\nMem: {}",
+        total_clients, total_rooms, total_messages, mem_usage
+    );
+    send_to_client(clients, client_id, &stats_msg).await;
+    Ok(())
+}
+
+/// `/help`: list the commands the caller may run.
+async fn cmd_help(ctx: CommandContext<'_>, _msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let role = client_role(&state.clients, &state.roles, client_id);
+    send_to_client(&state.clients, client_id, &state.commands.help_text(role)).await;
+    Ok(())
+}
+
+/// `/who`: show users with status.
+async fn cmd_who(ctx: CommandContext<'_>, _msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let clients = &state.clients;
+    let room = get_client_room(clients, client_id).await;
+    // One entry per user, with the number of connected devices
+    let mut seen: Vec<(String, bool, usize)> = Vec::new();
+    for r in clients.iter().filter(|r| r.value().in_room(&room)) {
+        let c = r.value();
+        if let Some(entry) = seen.iter_mut().find(|e| e.0.eq_ignore_ascii_case(&c.name)) {
+            entry.2 += 1;
+        } else {
+            seen.push((c.name.clone(), c.logged_in, 1));
         }
-        "/stats" => {
-            let total_clients = clients.len();
-            let total_rooms = histories.read().await.len();
-            let total_messages: usize = histories.read().await.values().map(|v| v.len()).sum();
-
-            // Memory Usage (Approximate using /proc/self/statm on Linux)
-            let mem_usage = if let Ok(content) = std::fs::read_to_string("/proc/self/statm") {
-                let parts: Vec<&str> = content.split_whitespace().collect();
-                if let Some(pages) = parts.get(1) {
-                    if let Ok(pages_cnt) = pages.parse::<usize>() {
-                        format!("{:.2} MB", (pages_cnt * 4) as f64 / 1024.0) // Assuming 4KB pages
-                    } else {
-                        "N/A".to_string()
+    }
+    let presences = snapshot_all(state);
+    let user_info: Vec<String> = seen
+        .iter()
+        .map(|(name, logged_in, sessions)| {
+            let mut details = vec![if *logged_in { "✓" } else { "guest" }.to_string()];
+            if *sessions > 1 {
+                details.push(format!("{} devices", sessions));
+            }
+            if let Some(p) = presences.get(name) {
+                let custom: Vec<&str> = [p.emoji.as_deref(), p.text.as_deref()]
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                if p.status != "active" || !custom.is_empty() {
+                    let mut status = p.status.clone();
+                    if !custom.is_empty() {
+                        status = format!("{}: {}", status, custom.join(" "));
                     }
-                } else {
-                    "N/A".to_string()
+                    details.push(status);
                 }
+            }
+            format!("{} ({})", name, details.join(", "))
+        })
+        .collect();
+    send_to_client(
+        clients,
+        client_id,
+        &format!("Users in '{}': {}", room, user_info.join(", ")),
+    )
+    .await;
+    Ok(())
+}
+
+/// `/sessions`: list your connected devices.
+async fn cmd_sessions(ctx: CommandContext<'_>, _msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let clients = &state.clients;
+    let name = client_name_by_id(clients, client_id).await;
+    let now = std::time::Instant::now();
+    let lines: Vec<String> = clients
+        .iter()
+        .filter(|r| r.value().name.eq_ignore_ascii_case(&name))
+        .map(|r| {
+            let c = r.value();
+            let status = if now.duration_since(c.last_active).as_secs() > 300 {
+                "idle"
             } else {
-                "N/A".to_string()
+                "active"
             };
+            let marker = if r.key() == client_id {
+                " (this device)"
+            } else {
+                ""
+            };
+            format!("  {} in #{} - {}{}", c.device, c.room, status, marker)
+        })
+        .collect();
+    send_to_client(
+        clients,
+        client_id,
+        &format!("Your sessions:\n{}", lines.join("\n")),
+    )
+    .await;
+    Ok(())
+}
 
-            let stats_msg = format!(
-                "Server Stats:\nClients: {}\nRooms: {}\nMessages: {}\nMem: {}",
-                total_clients, total_rooms, total_messages, mem_usage
-            );
-            send_to_client(clients, client_id, &stats_msg).await;
+/// `/mentions [clear]`: show (or clear) your mention inbox.
+async fn cmd_mentions(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Mentions { clear, .. } = msg else {
+        return Err(wrong_message());
+    };
+    if clear {
+        clear_inbox(state, client_id).await?;
+    } else {
+        send_inbox(state, client_id).await?;
+    }
+    Ok(())
+}
+
+/// `/role <user> [r]`: show a role, or set one (admin only).
+async fn cmd_role(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Role { user, role, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let clients = &state.clients;
+    let users = &state.users;
+    let roles = &state.roles;
+    let target = user.trim();
+    if target.is_empty() {
+        return Err(ActionError::usage("/role <user> [role]"));
+    }
+    let account = user_name(users, target).ok_or_else(|| {
+        ActionError::new(
+            ErrorCode::NotFound,
+            format!("'{}' is not a registered user", target),
+        )
+    })?;
+    let new_role = match role.as_deref().map(|r| r.trim()).filter(|r| !r.is_empty()) {
+        Some(r) => r,
+        None => {
+            let role = crate::roles::account_role(roles, Some(&account));
+            send_to_client(clients, client_id, &format!("{} is {}", account, role)).await;
+            return Ok(());
         }
-        "/help" => {
-            let help_text = r#"Available commands:
-  /name <name>     - Set your display name
-  /register <u> <p> - Create an account
-  /login <u> <p>    - Log in to your account
-  /msg <user> <text> - Private message a user
-  /join <room>     - Join or create a room
-  /leave           - Return to lobby
-  /rooms           - List all rooms
-  /room            - Show current room
-  /list            - List users in room
-  /who             - Show users with status
-  /kick <user>     - Kick a user (logged-in only)
-  /nudge           - Send a nudge (shake screen)
-  /history         - Reload chat history
-  /stats           - Show server metrics
-  /help            - Show this help"#;
-            send_to_client(clients, client_id, help_text).await;
+    };
+    if client_role(clients, roles, client_id) < Role::Admin {
+        return Err(ActionError::new(
+            ErrorCode::Forbidden,
+            "Only admins can change roles.",
+        ));
+    }
+    let role = match Role::parse(new_role) {
+        Some(Role::Guest) | None => {
+            return Err(ActionError::new(
+                ErrorCode::BadRequest,
+                "Roles: user, moderator, admin",
+            ));
         }
-        "/who" => {
-            let room = get_client_room(clients, client_id).await;
-            let user_info: Vec<String> = clients
-                .iter()
-                .filter(|r| r.value().room == room)
-                .map(|r| {
-                    let c = r.value();
-                    let status = if c.logged_in { "✓" } else { "guest" };
-                    format!("{} ({})", c.name, status)
-                })
-                .collect();
+        Some(r) => r,
+    };
+    roles.insert(account.to_lowercase(), role);
+    if let Err(e) = save_roles_async(roles).await {
+        tracing::error!("failed to save roles: {}", e);
+    }
+    state.audit.record(
+        &actor_name(clients, client_id),
+        "role",
+        &account,
+        None,
+        Some(&format!("set to {}", role)),
+    );
+    send_to_client(clients, client_id, &format!("{} is now {}", account, role)).await;
+    for id in session_ids_by_account(clients, &account) {
+        send_to_client(clients, &id, &format!("Your role is now {}", role)).await;
+    }
+    info!("{} set role of {} to {}", client_id, account, role);
+    Ok(())
+}
+
+/// `/away [msg]`: mark yourself away.
+async fn cmd_away(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Away { message, .. } = msg else {
+        return Err(wrong_message());
+    };
+    set_presence_mode(state, client_id, PresenceMode::Away, message).await;
+    Ok(())
+}
+
+/// `/dnd [msg]`: do not disturb (mutes nudges and mention alerts).
+async fn cmd_dnd(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Dnd { message, .. } = msg else {
+        return Err(wrong_message());
+    };
+    set_presence_mode(state, client_id, PresenceMode::Dnd, message).await;
+    Ok(())
+}
+
+/// `/back`: clear away / do not disturb.
+async fn cmd_back(ctx: CommandContext<'_>, _msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let clients = &state.clients;
+    set_user_status(state, client_id, |s| {
+        s.mode = None;
+        s.text = None;
+    })
+    .await;
+    send_to_client(clients, client_id, "Welcome back!").await;
+    Ok(())
+}
+
+/// `/status <emoji> [text]`: set a custom status (/status clear).
+async fn cmd_status(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Status { emoji, text, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let clients = &state.clients;
+    match emoji.as_deref().map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        None => {
+            set_user_status(state, client_id, |s| {
+                s.emoji = None;
+                s.text = None;
+            })
+            .await;
+            send_to_client(clients, client_id, "Status cleared.").await;
+        }
+        Some(emoji) => {
+            let emoji = truncate_chars(emoji, 16);
+            let text = text
+                .as_deref()
+                .map(|t| t.trim())
+                .filter(|t| !t.is_empty())
+                .map(|t| truncate_chars(t, MAX_STATUS_TEXT));
+            set_user_status(state, client_id, |s| {
+                s.emoji = Some(emoji);
+                s.text = text;
+            })
+            .await;
+            send_to_client(clients, client_id, "Status updated.").await;
+        }
+    }
+    Ok(())
+}
+
+/// `/nudge`: send a nudge (shake screen).
+async fn cmd_nudge(ctx: CommandContext<'_>, _msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let clients = &state.clients;
+    let histories = &state.histories;
+    state
+        .rate_limiter
+        .check_client(clients, client_id, Action::Nudge)?;
+    let from = client_name_by_id(clients, client_id).await;
+    let room = get_client_room(clients, client_id).await;
+
+    // Broadcast Nudge (users in do-not-disturb are skipped)
+    let msg = Outgoing::Nudge { from: from.clone() };
+    let recipients: Vec<String> = clients
+        .iter()
+        .filter(|r| r.value().in_room(&room))
+        .map(|r| r.key().clone())
+        .collect();
+    let payload = Payload::new(msg);
+    for id in recipients.iter().filter(|id| !is_dnd(state, id)) {
+        if let Some(tx) = client_tx_by_id(clients, id).await {
+            payload.send_to(&tx);
+        }
+    }
+
+    // System message announcement
+    send_system_to_room(
+        clients,
+        histories,
+        &room,
+        &format!("{} sent a nudge!", from),
+    )
+    .await;
+    Ok(())
+}
+
+/// `/webhook create <room> [name] | list | delete <id>`: manage incoming webhooks.
+async fn cmd_webhook(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Webhook {
+        action,
+        room,
+        name,
+        id,
+        ..
+    } = msg
+    else {
+        return Err(wrong_message());
+    };
+    let clients = &state.clients;
+    let histories = &state.histories;
+    let hooks = &state.incoming_webhooks;
+    match action.as_str() {
+        "create" => {
+            let room = room.as_deref().map(str::trim).unwrap_or_default();
+            if room.is_empty() {
+                return Err(ActionError::usage("/webhook create <room> [name]"));
+            }
+            if !histories.read().await.contains_key(room) {
+                return Err(ActionError::new(
+                    ErrorCode::NotFound,
+                    format!("Room '{}' does not exist", room),
+                ));
+            }
+            let name = name
+                .as_deref()
+                .map(str::trim)
+                .filter(|n| !n.is_empty())
+                .unwrap_or("webhook");
+            // Admins are always logged in, so the account is set
+            let creator = clients
+                .get(client_id)
+                .and_then(|c| c.account.clone())
+                .unwrap_or_default();
+            let hook = create_webhook(hooks, room, name, &creator);
+            if let Err(e) = save_webhooks_async(hooks).await {
+                tracing::error!("failed to save webhooks: {}", e);
+            }
+            state
+                .audit
+                .record(&creator, "webhook_create", &hook.id, Some(room), None);
             send_to_client(
                 clients,
                 client_id,
-                &format!("Users in '{}': {}", room, user_info.join(", ")),
+                &format!(
+                    "Webhook {} posts to #{} as {}. POST JSON {{\"text\": ...}} to {} — keep this URL secret.",
+                    hook.id,
+                    hook.room,
+                    hook.name,
+                    hook_path(&hook)
+                ),
             )
             .await;
         }
-        "/nudge" => {
-            let from = client_name_by_id(clients, client_id).await;
-            let room = get_client_room(clients, client_id).await;
-
-            // Broadcast Nudge
-            let msg = Outgoing::Nudge { from: from.clone() };
-            if let Ok(json) = serde_json::to_string(&msg) {
-                for r in clients.iter() {
-                    if r.value().room == room {
-                        let _ = r.value().tx.send(warp::ws::Message::text(json.clone()));
-                    }
-                }
+        "list" => {
+            let mut lines: Vec<String> = hooks
+                .iter()
+                .map(|r| {
+                    let h = r.value();
+                    format!(
+                        "  {} #{} as {} (by {}) {}",
+                        h.id,
+                        h.room,
+                        h.name,
+                        h.created_by,
+                        hook_path(h)
+                    )
+                })
+                .collect();
+            lines.sort();
+            let text = if lines.is_empty() {
+                "No webhooks.".to_string()
+            } else {
+                format!("Webhooks:\n{}", lines.join("\n"))
+            };
+            send_to_client(clients, client_id, &text).await;
+        }
+        "delete" => {
+            let id = id.as_deref().map(str::trim).unwrap_or_default();
+            if hooks.remove(id).is_none() {
+                return Err(ActionError::new(
+                    ErrorCode::NotFound,
+                    format!("No webhook '{}'", id),
+                ));
+            }
+            if let Err(e) = save_webhooks_async(hooks).await {
+                tracing::error!("failed to save webhooks: {}", e);
             }
+            state.audit.record(
+                &actor_name(clients, client_id),
+                "webhook_delete",
+                id,
+                None,
+                None,
+            );
+            send_to_client(clients, client_id, &format!("Webhook {} deleted.", id)).await;
+            info!("{} deleted incoming webhook {}", client_id, id);
+        }
+        _ => {
+            return Err(ActionError::usage(
+                "/webhook create <room> [name] | /webhook list | /webhook delete <id>",
+            ));
+        }
+    }
+    Ok(())
+}
 
-            // System message announcement
-            send_system_to_room(
+/// `/bot create|token|delete <name> | list`: manage bot accounts.
+async fn cmd_bot(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Bot { action, name, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let clients = &state.clients;
+    let users = &state.users;
+    let name = name.as_deref().map(str::trim).unwrap_or_default();
+    let bots = &state.bots;
+    let tokens = &state.api_tokens;
+    match action.as_str() {
+        "create" => {
+            if !valid_bot_name(name) {
+                return Err(ActionError::new(
+                    ErrorCode::BadRequest,
+                    "Bot names use letters, digits, - and _ (at most 32).",
+                ));
+            }
+            if bot_name(bots, name).is_some()
+                || users.iter().any(|r| r.key().eq_ignore_ascii_case(name))
+            {
+                return Err(ActionError::new(
+                    ErrorCode::Conflict,
+                    format!("The name '{}' is already taken", name),
+                ));
+            }
+            let creator = clients
+                .get(client_id)
+                .and_then(|c| c.account.clone())
+                .unwrap_or_default();
+            let token = create_bot(bots, tokens, name, &creator);
+            save_bots(state).await;
+            state.audit.record(&creator, "bot_create", name, None, None);
+            send_to_client(
                 clients,
-                histories,
-                &room,
-                &format!("{} sent a nudge!", from),
+                client_id,
+                &format!(
+                    "Bot '{}' created. Token: {} — connect to /ws?token=<token>; keep it secret.",
+                    name, token
+                ),
             )
             .await;
         }
-        _ => {
+        "token" => {
+            let name = bot_name(bots, name).ok_or_else(|| unknown_bot(name))?;
+            revoke_tokens_for(tokens, &name);
+            let token = issue_token(tokens, &name);
+            save_bots(state).await;
+            state.audit.record(
+                &actor_name(clients, client_id),
+                "bot_token",
+                &name,
+                None,
+                None,
+            );
             send_to_client(
                 clients,
                 client_id,
-                "Unknown command. Type /help for available commands.",
+                &format!(
+                    "New token for bot '{}': {} (older tokens are revoked)",
+                    name, token
+                ),
             )
             .await;
         }
+        "delete" => {
+            let name = bot_name(bots, name).ok_or_else(|| unknown_bot(name))?;
+            bots.remove(&name);
+            revoke_tokens_for(tokens, &name);
+            save_bots(state).await;
+            disconnect_sessions(
+                clients,
+                &session_ids_by_account(clients, &name),
+                "bot deleted",
+            );
+            state.audit.record(
+                &actor_name(clients, client_id),
+                "bot_delete",
+                &name,
+                None,
+                None,
+            );
+            send_to_client(clients, client_id, &format!("Bot '{}' deleted.", name)).await;
+            info!("{} deleted bot account {}", client_id, name);
+        }
+        "list" => {
+            let mut lines: Vec<String> = bots
+                .iter()
+                .map(|r| {
+                    let online = !session_ids_by_account(clients, r.key()).is_empty();
+                    format!(
+                        "  {} (by {}){}",
+                        r.key(),
+                        r.value().created_by,
+                        if online { " - online" } else { "" }
+                    )
+                })
+                .collect();
+            lines.sort();
+            let text = if lines.is_empty() {
+                "No bots.".to_string()
+            } else {
+                format!("Bots:\n{}", lines.join("\n"))
+            };
+            send_to_client(clients, client_id, &text).await;
+        }
+        _ => {
+            return Err(ActionError::usage(
+                "/bot create <name> | /bot token <name> | /bot delete <name> | /bot list",
+            ));
+        }
     }
+    Ok(())
+}
+
+/// `/subscribe <room>`: also receive a room's messages (bots only).
+async fn cmd_subscribe(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Subscribe { room, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let clients = &state.clients;
+    let histories = &state.histories;
+    let room = room.trim();
+    if room.is_empty() {
+        return Err(ActionError::usage("/subscribe <room>"));
+    }
+    let added = match clients.get_mut(client_id) {
+        Some(mut r) => {
+            let c = r.value_mut();
+            if !c.bot {
+                return Err(ActionError::new(
+                    ErrorCode::Forbidden,
+                    "Only bots can subscribe to rooms; use /join.",
+                ));
+            }
+            if c.in_room(room) {
+                false
+            } else {
+                c.subscriptions.push(room.to_string());
+                true
+            }
+        }
+        None => false,
+    };
+    if added {
+        histories
+            .write()
+            .await
+            .entry(room.to_string())
+            .or_insert_with(|| VecDeque::with_capacity(200));
+        send_user_list_to_room(state, room).await;
+    }
+    send_to_client(clients, client_id, &format!("Subscribed to '{}'", room)).await;
+    Ok(())
+}
+
+/// `/unsubscribe <room>`: stop receiving a subscribed room (bots only).
+async fn cmd_unsubscribe(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Unsubscribe { room, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let clients = &state.clients;
+    let room = room.trim();
+    let removed = clients
+        .get_mut(client_id)
+        .map(|mut r| {
+            let subs = &mut r.value_mut().subscriptions;
+            let before = subs.len();
+            subs.retain(|s| s != room);
+            subs.len() < before
+        })
+        .unwrap_or(false);
+    if !removed {
+        return Err(ActionError::new(
+            ErrorCode::NotFound,
+            format!("Not subscribed to '{}'", room),
+        ));
+    }
+    send_user_list_to_room(state, room).await;
+    send_to_client(clients, client_id, &format!("Unsubscribed from '{}'", room)).await;
+    Ok(())
+}
+
+/// `/filter [<name> on|off|default]`: show or switch this room's message filters.
+async fn cmd_filter(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Filter { name, setting, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let room = get_client_room(&state.clients, client_id).await;
+    let Some(name) = name else {
+        let lines: Vec<String> = state
+            .filters
+            .filters()
+            .map(|f| {
+                let on = state.filters.enabled_in(state, &room, f);
+                format!(
+                    "  {} [{}] - {}",
+                    f.name(),
+                    if on { "on" } else { "off" },
+                    f.summary()
+                )
+            })
+            .collect();
+        let text = format!("Message filters in '{}':\n{}", room, lines.join("\n"));
+        send_to_client(&state.clients, client_id, &text).await;
+        return Ok(());
+    };
+    let filter = state.filters.get(name.trim()).ok_or_else(|| {
+        ActionError::new(ErrorCode::NotFound, format!("No filter named '{}'", name))
+    })?;
+    let enabled = match setting.as_deref().map(str::trim) {
+        Some("on") => Some(true),
+        Some("off") => Some(false),
+        Some("default") => None,
+        _ => return Err(ActionError::usage("/filter [<name> on|off|default]")),
+    };
+    set_filter(&state.room_settings, &room, filter.name(), enabled);
+    if let Err(e) = save_room_settings_async(&state.room_settings).await {
+        tracing::error!("failed to save room settings: {}", e);
+    }
+    let on = state.filters.enabled_in(state, &room, filter);
+    state.audit.record(
+        &actor_name(&state.clients, client_id),
+        "filter",
+        filter.name(),
+        Some(&room),
+        Some(if on { "on" } else { "off" }),
+    );
+    let from = client_name_by_id(&state.clients, client_id).await;
+    info!(
+        "{} switched filter {} {} in '{}'",
+        from,
+        filter.name(),
+        if on { "on" } else { "off" },
+        room
+    );
+    send_system_to_room(
+        &state.clients,
+        &state.histories,
+        &room,
+        &format!(
+            "{} turned the {} filter {}",
+            from,
+            filter.name(),
+            if on { "on" } else { "off" }
+        ),
+    )
+    .await;
+    Ok(())
+}
+
+/// `/wordfilter [off|mask|block|flag]`: show or set this room's word filter mode.
+async fn cmd_wordfilter(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::WordFilter { mode, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let room = get_client_room(&state.clients, client_id).await;
+    let Some(mode) = mode else {
+        let text = format!(
+            "Word filter in '{}': {} ({} words listed)",
+            room,
+            word_filter_mode(&state.room_settings, &room),
+            state.word_list.word_count()
+        );
+        send_to_client(&state.clients, client_id, &text).await;
+        return Ok(());
+    };
+    let mode = WordFilterMode::parse(mode.trim())
+        .ok_or_else(|| ActionError::usage("/wordfilter [off|mask|block|flag]"))?;
+    set_word_filter_mode(&state.room_settings, &room, mode);
+    if let Err(e) = save_room_settings_async(&state.room_settings).await {
+        tracing::error!("failed to save room settings: {}", e);
+    }
+    state.audit.record(
+        &actor_name(&state.clients, client_id),
+        "wordfilter",
+        &mode.to_string(),
+        Some(&room),
+        None,
+    );
+    let from = client_name_by_id(&state.clients, client_id).await;
+    info!("{} set the word filter in '{}' to {}", from, room, mode);
+    send_system_to_room(
+        &state.clients,
+        &state.histories,
+        &room,
+        &format!("{} set the word filter to {}", from, mode),
+    )
+    .await;
+    Ok(())
+}
+
+/// `/slowmode [<seconds>|off]`: show or set this room's slow mode.
+async fn cmd_slowmode(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::SlowMode { seconds, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let room = get_client_room(&state.clients, client_id).await;
+    let Some(secs) = seconds else {
+        let text = match slow_mode(&state.room_settings, &room) {
+            0 => format!("Slow mode is off in '{}'.", room),
+            secs => format!("Slow mode in '{}': one message per {} seconds.", room, secs),
+        };
+        send_to_client(&state.clients, client_id, &text).await;
+        return Ok(());
+    };
+    if secs > MAX_SLOW_MODE_SECS {
+        return Err(ActionError::new(
+            ErrorCode::BadRequest,
+            format!("Slow mode can be at most {} seconds.", MAX_SLOW_MODE_SECS),
+        ));
+    }
+    set_slow_mode(&state.room_settings, &room, secs);
+    let (setting, announcement) = match secs {
+        0 => ("off".to_string(), "turned slow mode off".to_string()),
+        s => (
+            format!("{}s", s),
+            format!("turned slow mode on: one message per {} seconds", s),
+        ),
+    };
+    room_setting_changed(state, client_id, &room, "slowmode", &setting, &announcement).await;
+    Ok(())
+}
+
+/// `/readonly [on|off]`: show or switch whether only moderators may post here.
+async fn cmd_readonly(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::ReadOnly { enabled, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let room = get_client_room(&state.clients, client_id).await;
+    let Some(on) = enabled else {
+        let text = if read_only(&state.room_settings, &room) {
+            format!("'{}' is read-only: only moderators can post.", room)
+        } else {
+            format!("'{}' is open: everyone can post.", room)
+        };
+        send_to_client(&state.clients, client_id, &text).await;
+        return Ok(());
+    };
+    set_read_only(&state.room_settings, &room, on);
+    let announcement = if on {
+        "made the room read-only: only moderators can post, everyone can react"
+    } else {
+        "opened the room: everyone can post"
+    };
+    let setting = if on { "on" } else { "off" };
+    room_setting_changed(state, client_id, &room, "readonly", setting, announcement).await;
+    Ok(())
+}
+
+/// `/guestposting [on|off]`: show or switch whether guests may post here.
+async fn cmd_guestposting(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::GuestPosting { enabled, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let room = get_client_room(&state.clients, client_id).await;
+    let Some(on) = enabled else {
+        let text = if guest_posting(&state.room_settings, &room) {
+            format!("Guests can post in '{}'.", room)
+        } else {
+            format!("Guests cannot post in '{}'.", room)
+        };
+        send_to_client(&state.clients, client_id, &text).await;
+        return Ok(());
+    };
+    set_guest_posting(&state.room_settings, &room, on);
+    let announcement = if on {
+        "allowed guests to post"
+    } else {
+        "stopped guests from posting; register or log in to post"
+    };
+    let setting = if on { "on" } else { "off" };
+    room_setting_changed(
+        state,
+        client_id,
+        &room,
+        "guestposting",
+        setting,
+        announcement,
+    )
+    .await;
+    Ok(())
+}
+
+/// Save room settings after a moderator changed one, record it in the
+/// audit log and tell the room "<name> <announcement>".
+async fn room_setting_changed(
+    state: &AppState,
+    client_id: &str,
+    room: &str,
+    action: &str,
+    setting: &str,
+    announcement: &str,
+) {
+    if let Err(e) = save_room_settings_async(&state.room_settings).await {
+        tracing::error!("failed to save room settings: {}", e);
+    }
+    state.audit.record(
+        &actor_name(&state.clients, client_id),
+        action,
+        setting,
+        Some(room),
+        None,
+    );
+    let from = client_name_by_id(&state.clients, client_id).await;
+    info!("{} set {} to {} in '{}'", from, action, setting, room);
+    send_system_to_room(
+        &state.clients,
+        &state.histories,
+        room,
+        &format!("{} {}", from, announcement),
+    )
+    .await;
+}
+
+/// `/reports [dismiss|delete <id> | mute <id> [minutes]]`: list open
+/// reports, or resolve one.
+async fn cmd_reports(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Reports {
+        action,
+        id,
+        minutes,
+        ..
+    } = msg
+    else {
+        return Err(wrong_message());
+    };
+    let Some(action) = action else {
+        let reports = open_reports(state).await;
+        if let Some(tx) = client_tx_by_id(&state.clients, client_id).await {
+            send(&tx, Outgoing::Reports { reports });
+        }
+        return Ok(());
+    };
+    const USAGE: &str = "/reports [dismiss|delete <id> | mute <id> [minutes]]";
+    let action = ReportAction::parse(&action, minutes).ok_or_else(|| ActionError::usage(USAGE))?;
+    let id = id.ok_or_else(|| ActionError::usage(USAGE))?;
+    let by = client_name_by_id(&state.clients, client_id).await;
+    resolve_report(state, id.trim(), action, &by).await
+}
+
+/// Entries shown by `/audit`.
+const AUDIT_LINES: usize = 20;
+
+/// `/audit [user|room]`: show recent audit log entries.
+async fn cmd_audit(ctx: CommandContext<'_>, msg: Incoming) -> ActionResult {
+    let CommandContext { client_id, state } = ctx;
+    let Incoming::Audit { query, .. } = msg else {
+        return Err(wrong_message());
+    };
+    let query = query.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let entries = state.audit.search(query, AUDIT_LINES);
+    let text = if entries.is_empty() {
+        "No audit entries.".to_string()
+    } else {
+        let now = now_ts();
+        let lines: Vec<String> = entries.iter().map(|e| e.describe(now)).collect();
+        format!(
+            "Audit log{}:\n{}",
+            query.map(|q| format!(" for '{}'", q)).unwrap_or_default(),
+            lines.join("\n")
+        )
+    };
+    send_to_client(&state.clients, client_id, &text).await;
+    Ok(())
+}
+
+/// Save bots and API tokens after a bot change.
+async fn save_bots(state: &AppState) {
+    if let Err(e) = save_bots_async(&state.bots).await {
+        tracing::error!("failed to save bots: {}", e);
+    }
+    if let Err(e) = save_tokens_async(&state.api_tokens).await {
+        tracing::error!("failed to save API tokens: {}", e);
+    }
+}
+
+/// Error for a built-in handed a message of another kind, typically a
+/// typed `command` message naming a built-in that has its own type.
+fn wrong_message() -> ActionError {
+    ActionError::new(
+        ErrorCode::BadRequest,
+        "This command has its own message type; send that instead",
+    )
+}
+
+fn unknown_bot(name: &str) -> ActionError {
+    ActionError::new(ErrorCode::NotFound, format!("No bot named '{}'", name))
 }
 
 /// Handle regular chat messages.
 pub async fn handle_message_with_rooms(
     client_id: &str,
     text: &str,
-    clients: &Clients,
-    histories: &Histories,
-    metrics: &std::sync::Arc<crate::metrics::ServerMetrics>,
+    state: &AppState,
+) -> ActionResult {
+    let room = get_client_room(&state.clients, client_id).await;
+    handle_message_in_room(client_id, &room, text, state).await
+}
+
+/// Post a chat message from a client to `room` (a bot's subscribed room,
+/// or the client's current one).
+pub async fn handle_message_in_room(
+    client_id: &str,
+    room: &str,
+    text: &str,
+    state: &AppState,
+) -> ActionResult {
+    let from = client_name_by_id(&state.clients, client_id).await;
+    let role = client_role(&state.clients, &state.roles, client_id);
+    let tx = client_tx_by_id(&state.clients, client_id).await;
+    let key = state
+        .clients
+        .get(client_id)
+        .map(|r| client_key(r.value()))
+        .ok_or_else(ActionError::unknown_session)?;
+    let claim = claim_post(state, room, &key, role)?;
+    let mut item = HistoryItem::new(from.clone(), text);
+    item.bot = state
+        .clients
+        .get(client_id)
+        .map(|r| r.value().bot)
+        .unwrap_or(false);
+    post_to_room(state, room, item, role, tx.as_ref(), Some(&key)).await?;
+    claim.commit();
+    Ok(())
+}
+
+/// Maximum length of a status message.
+const MAX_STATUS_TEXT: usize = 100;
+
+/// Set away or do-not-disturb with an optional message.
+async fn set_presence_mode(
+    state: &AppState,
+    client_id: &str,
+    mode: PresenceMode,
+    message: Option<String>,
 ) {
-    let from = client_name_by_id(clients, client_id).await;
-    let room = get_client_room(clients, client_id).await;
-    let filtered_text = crate::helpers::censor_profanity(text);
-    let item = HistoryItem {
-        id: generate_msg_id(),
-        from,
-        text: filtered_text,
-        ts: now_ts(),
-        reactions: HashMap::new(),
-        edited: false,
-        deleted: false,
-    };
-    broadcast_to_room_and_store(clients, histories, &room, item.clone()).await;
-
-    // Increment message counter
-    metrics.increment_messages();
-
-    // Check for URLs and fetch previews
-    static URL_RE: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
-    let url_regex = URL_RE.get_or_init(|| regex::Regex::new(r"(https?://\S+)").unwrap());
-    if let Some(captures) = url_regex.captures(text) {
-        if let Some(match_) = captures.get(0) {
-            let url = match_.as_str().to_string();
-            let clients_clone = clients.clone();
-            let room_clone = room.clone();
-            let msg_id = item.id.clone();
-
-            // Spawn background task to fetch metadata
-            tokio::spawn(async move {
-                if let Some((title, desc, image)) = crate::helpers::fetch_preview(&url).await {
-                    let preview_msg = Outgoing::LinkPreview {
-                        msg_id,
-                        title,
-                        description: desc,
-                        image,
-                        url,
-                    };
-                    // Broadcast preview to proper room
-                    if let Ok(json) = serde_json::to_string(&preview_msg) {
-                        for r in clients_clone.iter() {
-                            if r.value().room == room_clone {
-                                let _ = r.value().tx.send(warp::ws::Message::text(json.clone()));
-                            }
-                        }
-                    }
-                }
-            });
+    let message = message
+        .as_deref()
+        .map(|m| m.trim())
+        .filter(|m| !m.is_empty())
+        .map(|m| truncate_chars(m, MAX_STATUS_TEXT));
+    set_user_status(state, client_id, |s| {
+        s.mode = Some(mode);
+        s.text = message;
+    })
+    .await;
+    let notice = match mode {
+        PresenceMode::Away => "You are now away. Use /back to return.",
+        PresenceMode::Dnd => {
+            "Do not disturb is on: nudges and mention alerts are muted. Use /back to turn it off."
         }
+    };
+    send_to_client(&state.clients, client_id, notice).await;
+}
+
+/// Helper: split off the first word; the rest is trimmed.
+fn split_word(s: &str) -> (&str, &str) {
+    match s.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (s, ""),
     }
 }
 
+/// Helper: limit a string to `max` characters.
+fn truncate_chars(s: &str, max: usize) -> String {
+    s.chars().take(max).collect()
+}
+
 /// Helper: send system message to a single client.
 async fn send_to_client(clients: &Clients, client_id: &str, text: &str) {
     if let Some(tx) = client_tx_by_id(clients, client_id).await {
         let msg = Outgoing::System {
             text: text.to_string(),
         };
-        if let Ok(payload) = serde_json::to_string(&msg) {
-            let _ = tx.send(warp::ws::Message::text(payload));
-        }
+        send(&tx, msg);
     }
 }
 
@@ -0,0 +1,160 @@
+//! Server settings read from environment variables at startup.
+
+use tracing::warn;
+
+use crate::rate_limit::RateLimits;
+
+/// One megabyte, for the upload size defaults.
+const MB: u64 = 1024 * 1024;
+
+/// Runtime configuration. Every field has a default, so the server runs
+/// without any environment set.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Offer compressed frames to clients that negotiate `deflate`
+    /// (`COMPRESSION`, default on).
+    pub compression: bool,
+    /// Frames smaller than this many bytes are sent uncompressed
+    /// (`COMPRESSION_MIN_BYTES`, default 512).
+    pub compression_min_bytes: usize,
+    /// Banned words, one per line; reloaded when it changes
+    /// (`WORD_FILTER_FILE`, default `word_filter.txt`).
+    pub word_filter_file: String,
+    /// Audit log of moderation and admin actions, as JSON lines
+    /// (`AUDIT_LOG_FILE`, default `audit.jsonl`).
+    pub audit_log_file: String,
+    /// Token-bucket limits per action, each `<burst>/<seconds>`
+    /// (`RATE_MESSAGE` 5/10, `RATE_BOT_MESSAGE` 30/10, `RATE_COMMAND` 10/10,
+    /// `RATE_EDIT` 5/10, `RATE_REACTION` 10/10, `RATE_TYPING` 20/10,
+    /// `RATE_NUDGE` 1/30, `RATE_REGISTER` 3/600, `RATE_UPLOAD` 10/60,
+    /// `RATE_TOKEN` 5/60; a burst of 0 disables one).
+    pub rate_limits: RateLimits,
+    /// Open `/ws` and `/sse` connections allowed per remote IP address;
+    /// 0 for no cap (`MAX_CONNECTIONS_PER_IP`, default 20).
+    pub max_connections_per_ip: usize,
+    /// Failed logins in a row that lock an account or address out
+    /// (`LOGIN_MAX_FAILURES`, default 10).
+    pub login_max_failures: u32,
+    /// How long a lockout lasts, in seconds (`LOGIN_LOCKOUT_SECS`, default 900).
+    pub login_lockout_secs: u64,
+    /// Largest single uploaded file, in bytes (`UPLOAD_MAX_FILE_BYTES`,
+    /// default 100 MB).
+    pub upload_max_file_bytes: u64,
+    /// Largest total of files in one upload request, in bytes
+    /// (`UPLOAD_MAX_REQUEST_BYTES`, default 200 MB).
+    pub upload_max_request_bytes: u64,
+    /// Bytes each uploader may store; 0 for no quota
+    /// (`UPLOAD_USER_QUOTA_BYTES`, default 1 GB).
+    pub upload_user_quota_bytes: u64,
+    /// Bytes `uploads/` may hold in total; 0 for no quota
+    /// (`UPLOAD_TOTAL_QUOTA_BYTES`, default 10 GB).
+    pub upload_total_quota_bytes: u64,
+    /// Free disk space uploads must leave; 0 for no floor
+    /// (`UPLOAD_MIN_FREE_BYTES`, default 1 GB).
+    pub upload_min_free_bytes: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            compression: true,
+            compression_min_bytes: 512,
+            word_filter_file: "word_filter.txt".to_string(),
+            audit_log_file: "audit.jsonl".to_string(),
+            rate_limits: RateLimits::default(),
+            max_connections_per_ip: 20,
+            login_max_failures: 10,
+            login_lockout_secs: 900,
+            upload_max_file_bytes: 100 * MB,
+            upload_max_request_bytes: 200 * MB,
+            upload_user_quota_bytes: 1024 * MB,
+            upload_total_quota_bytes: 10 * 1024 * MB,
+            upload_min_free_bytes: 1024 * MB,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let defaults = Config::default();
+        Config {
+            compression: env_flag("COMPRESSION", defaults.compression),
+            compression_min_bytes: env_parse(
+                "COMPRESSION_MIN_BYTES",
+                defaults.compression_min_bytes,
+            ),
+            word_filter_file: std::env::var("WORD_FILTER_FILE")
+                .unwrap_or(defaults.word_filter_file),
+            audit_log_file: std::env::var("AUDIT_LOG_FILE").unwrap_or(defaults.audit_log_file),
+            rate_limits: rate_limits_from_env(defaults.rate_limits),
+            max_connections_per_ip: env_parse(
+                "MAX_CONNECTIONS_PER_IP",
+                defaults.max_connections_per_ip,
+            ),
+            login_max_failures: env_parse("LOGIN_MAX_FAILURES", defaults.login_max_failures),
+            login_lockout_secs: env_parse("LOGIN_LOCKOUT_SECS", defaults.login_lockout_secs),
+            upload_max_file_bytes: env_parse(
+                "UPLOAD_MAX_FILE_BYTES",
+                defaults.upload_max_file_bytes,
+            ),
+            upload_max_request_bytes: env_parse(
+                "UPLOAD_MAX_REQUEST_BYTES",
+                defaults.upload_max_request_bytes,
+            ),
+            upload_user_quota_bytes: env_parse(
+                "UPLOAD_USER_QUOTA_BYTES",
+                defaults.upload_user_quota_bytes,
+            ),
+            upload_total_quota_bytes: env_parse(
+                "UPLOAD_TOTAL_QUOTA_BYTES",
+                defaults.upload_total_quota_bytes,
+            ),
+            upload_min_free_bytes: env_parse(
+                "UPLOAD_MIN_FREE_BYTES",
+                defaults.upload_min_free_bytes,
+            ),
+        }
+    }
+}
+
+/// Read each `RATE_*` variable over the defaults.
+fn rate_limits_from_env(defaults: RateLimits) -> RateLimits {
+    RateLimits {
+        message: env_parse("RATE_MESSAGE", defaults.message),
+        bot_message: env_parse("RATE_BOT_MESSAGE", defaults.bot_message),
+        command: env_parse("RATE_COMMAND", defaults.command),
+        edit: env_parse("RATE_EDIT", defaults.edit),
+        reaction: env_parse("RATE_REACTION", defaults.reaction),
+        typing: env_parse("RATE_TYPING", defaults.typing),
+        nudge: env_parse("RATE_NUDGE", defaults.nudge),
+        register: env_parse("RATE_REGISTER", defaults.register),
+        upload: env_parse("RATE_UPLOAD", defaults.upload),
+        token: env_parse("RATE_TOKEN", defaults.token),
+    }
+}
+
+/// Read a boolean variable: `1/true/on/yes` or `0/false/off/no`.
+fn env_flag(name: &str, default: bool) -> bool {
+    match std::env::var(name) {
+        Ok(v) => match v.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "on" | "yes" => true,
+            "0" | "false" | "off" | "no" => false,
+            _ => {
+                warn!("ignoring invalid {}={:?}", name, v);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// Read and parse a variable, falling back to `default` when unset or invalid.
+fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(v) => v.trim().parse().unwrap_or_else(|_| {
+            warn!("ignoring invalid {}={:?}", name, v);
+            default
+        }),
+        Err(_) => default,
+    }
+}
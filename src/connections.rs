@@ -0,0 +1,66 @@
+//! Per-address connection caps for the `/ws` and `/sse` transports.
+
+use dashmap::DashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::errors::{ActionError, ErrorCode};
+
+/// Open connections per remote IP address, held in `AppState::connections`.
+pub struct ConnectionCaps {
+    max_per_ip: usize,
+    open: Arc<DashMap<IpAddr, usize>>,
+}
+
+/// One counted connection; the count drops when the permit does.
+pub struct ConnectionPermit {
+    open: Arc<DashMap<IpAddr, usize>>,
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionCaps {
+    /// Allow `max_per_ip` open connections per address (0 for no cap).
+    pub fn new(max_per_ip: usize) -> Self {
+        ConnectionCaps {
+            max_per_ip,
+            open: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Count a new connection from `remote`, or refuse it with
+    /// `rate_limited` when the address is at its cap. Connections without
+    /// a known address are never capped.
+    pub fn acquire(&self, remote: Option<SocketAddr>) -> Result<ConnectionPermit, ActionError> {
+        let ip = remote.map(|a| a.ip());
+        if let Some(ip) = ip {
+            let mut count = self.open.entry(ip).or_insert(0);
+            if self.max_per_ip > 0 && *count >= self.max_per_ip {
+                warn!("Refusing connection from {}: {} already open", ip, *count);
+                return Err(ActionError::new(
+                    ErrorCode::RateLimited,
+                    format!(
+                        "Too many connections from your address (max {}).",
+                        self.max_per_ip
+                    ),
+                ));
+            }
+            *count += 1;
+        }
+        Ok(ConnectionPermit {
+            open: self.open.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(ip) = self.ip {
+            self.open.remove_if_mut(&ip, |_, count| {
+                *count = count.saturating_sub(1);
+                *count == 0
+            });
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Wire encodings for outgoing messages: JSON text frames (the default) or
+//! MessagePack binary frames for clients that negotiate the `msgpack`
+//! capability, optionally zlib-compressed for clients that negotiate
+//! `deflate`.
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::Write;
+use std::sync::{Arc, OnceLock};
+use tracing::error;
+
+use crate::types::{Incoming, Outgoing, Tx};
+
+/// How a connection's messages are put on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MsgPack,
+}
+
+/// An outgoing message shared by every recipient of a broadcast. Each
+/// encoding (and its compressed form) is produced at most once, by the first
+/// connection that needs it.
+pub struct Payload {
+    msg: Outgoing,
+    json: OnceLock<Option<String>>,
+    msgpack: OnceLock<Option<Vec<u8>>>,
+    json_deflate: OnceLock<Option<Vec<u8>>>,
+    msgpack_deflate: OnceLock<Option<Vec<u8>>>,
+}
+
+/// A frame ready for the socket, with its size before and after compression.
+pub struct WireFrame {
+    pub message: warp::ws::Message,
+    pub raw_len: usize,
+    pub wire_len: usize,
+}
+
+impl Payload {
+    pub fn new(msg: Outgoing) -> Arc<Payload> {
+        Arc::new(Payload {
+            msg,
+            json: OnceLock::new(),
+            msgpack: OnceLock::new(),
+            json_deflate: OnceLock::new(),
+            msgpack_deflate: OnceLock::new(),
+        })
+    }
+
+    /// Queue this payload on a client's channel.
+    pub fn send_to(self: &Arc<Self>, tx: &Tx) {
+        let _ = tx.send(Outbound::Payload(self.clone()));
+    }
+
+    /// The WebSocket frame for `encoding`, serializing on first use.
+    /// With `deflate_min` set, payloads of at least that many bytes are sent
+    /// as zlib-compressed binary frames when that makes them smaller.
+    pub fn frame(&self, encoding: Encoding, deflate_min: Option<usize>) -> Option<WireFrame> {
+        let raw = self.encoded(encoding)?;
+        let raw_len = raw.len();
+        if let Some(min) = deflate_min {
+            if raw_len >= min {
+                if let Some(packed) = self.deflated(encoding, raw) {
+                    return Some(WireFrame {
+                        message: warp::ws::Message::binary(packed.to_vec()),
+                        raw_len,
+                        wire_len: packed.len(),
+                    });
+                }
+            }
+        }
+        let message = match encoding {
+            // Already initialized by `encoded`
+            Encoding::Json => warp::ws::Message::text(self.json.get()?.clone()?),
+            Encoding::MsgPack => warp::ws::Message::binary(raw.to_vec()),
+        };
+        Some(WireFrame {
+            message,
+            raw_len,
+            wire_len: raw_len,
+        })
+    }
+
+    /// The serialized message in `encoding`.
+    fn encoded(&self, encoding: Encoding) -> Option<&[u8]> {
+        match encoding {
+            Encoding::Json => self
+                .json
+                .get_or_init(|| {
+                    serde_json::to_string(&self.msg)
+                        .map_err(|e| error!("failed to encode JSON: {}", e))
+                        .ok()
+                })
+                .as_deref()
+                .map(str::as_bytes),
+            Encoding::MsgPack => self
+                .msgpack
+                .get_or_init(|| {
+                    rmp_serde::to_vec_named(&self.msg)
+                        .map_err(|e| error!("failed to encode MessagePack: {}", e))
+                        .ok()
+                })
+                .as_deref(),
+        }
+    }
+
+    /// The zlib-compressed form of `raw`, or `None` if it would not be smaller.
+    fn deflated(&self, encoding: Encoding, raw: &[u8]) -> Option<&[u8]> {
+        let cell = match encoding {
+            Encoding::Json => &self.json_deflate,
+            Encoding::MsgPack => &self.msgpack_deflate,
+        };
+        cell.get_or_init(|| {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            let packed = encoder
+                .write_all(raw)
+                .and_then(|_| encoder.finish())
+                .map_err(|e| error!("failed to compress frame: {}", e))
+                .ok()?;
+            (packed.len() < raw.len()).then_some(packed)
+        })
+        .as_deref()
+    }
+}
+
+/// Item on a client's send channel.
+pub enum Outbound {
+    Payload(Arc<Payload>),
+    /// Switch encoding for every message queued after this one.
+    SetEncoding(Encoding),
+    /// Compress messages of at least this many bytes queued after this one.
+    SetCompression(usize),
+    /// Send a close frame and stop.
+    Close(u16, &'static str),
+}
+
+/// Send a single message to one connection.
+pub fn send(tx: &Tx, msg: Outgoing) {
+    Payload::new(msg).send_to(tx);
+}
+
+/// Decode a binary (MessagePack) frame from a client.
+pub fn decode_binary(bytes: &[u8]) -> Result<Incoming, String> {
+    rmp_serde::from_slice(bytes).map_err(|e| format!("invalid message: {}", e))
+}
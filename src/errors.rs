@@ -0,0 +1,126 @@
+//! Stable error codes and the ack/error replies to client requests.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+use warp::http::StatusCode;
+
+use crate::encoding::send;
+use crate::types::{Outgoing, Tx, PROTOCOL_STRICT};
+
+/// Machine-readable reason an action failed. The serialized names are part
+/// of the protocol and must not change.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Missing or malformed arguments.
+    BadRequest,
+    /// The command does not exist.
+    UnknownCommand,
+    /// The action needs a name, or a logged-in account.
+    NotAuthenticated,
+    /// Wrong username or password.
+    AuthFailed,
+    /// The caller's role does not allow the action.
+    Forbidden,
+    /// The target user, room or message does not exist.
+    NotFound,
+    /// The name or account is already taken.
+    Conflict,
+    /// Too many requests in a short time.
+    RateLimited,
+    /// An upload exceeded the size limit.
+    PayloadTooLarge,
+    /// An upload would exceed a disk quota or the free-space floor.
+    QuotaExceeded,
+    /// Something went wrong on the server.
+    Internal,
+}
+
+impl ErrorCode {
+    /// HTTP status used when the error is returned from an HTTP route.
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest | ErrorCode::UnknownCommand => StatusCode::BAD_REQUEST,
+            ErrorCode::NotAuthenticated | ErrorCode::AuthFailed => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// A failed client action: a stable code plus a human-readable message.
+#[derive(Debug, Clone)]
+pub struct ActionError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ActionError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ActionError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Shorthand for a `BadRequest` carrying a usage line.
+    pub fn usage(usage: &str) -> Self {
+        ActionError::new(ErrorCode::BadRequest, format!("Usage: {}", usage))
+    }
+
+    /// A `NotAuthenticated` error for a session that is no longer in
+    /// `Clients`, e.g. one that was kicked and is closing.
+    pub fn unknown_session() -> Self {
+        ActionError::new(ErrorCode::NotAuthenticated, "Unknown session")
+    }
+}
+
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// JSON error body for an HTTP route, with the code's status.
+pub fn error_reply(
+    e: &ActionError,
+    req_id: Option<String>,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&json!({
+            "ok": false,
+            "code": e.code,
+            "message": e.message,
+            "req_id": req_id,
+        })),
+        e.code.status(),
+    )
+}
+
+/// Outcome of handling one client request.
+pub type ActionResult = Result<(), ActionError>;
+
+/// Report the outcome of a request to the connection that sent it.
+///
+/// Successful requests are acknowledged only when they carried a `req_id`.
+/// Failures become `Error` messages, except for pre-v3 clients that sent no
+/// `req_id`: they don't know the `error` type and get a `System` line.
+pub fn send_result(tx: &Tx, protocol: u32, req_id: Option<String>, result: ActionResult) {
+    let msg = match (result, req_id) {
+        (Ok(()), Some(req_id)) => Outgoing::Ack { req_id },
+        (Ok(()), None) => return,
+        (Err(e), None) if protocol < PROTOCOL_STRICT => Outgoing::System { text: e.message },
+        (Err(e), req_id) => Outgoing::Error {
+            req_id,
+            code: e.code,
+            message: e.message,
+        },
+    };
+    send(tx, msg);
+}
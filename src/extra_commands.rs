@@ -0,0 +1,117 @@
+//! Add-on slash commands that live outside the core, registered in
+//! `main.rs`. They double as examples of `CommandHandler`.
+
+use futures::future::BoxFuture;
+use uuid::Uuid;
+
+use crate::command_registry::{CommandContext, CommandHandler};
+use crate::errors::{ActionError, ActionResult};
+use crate::helpers::client_name_by_id;
+use crate::rate_limit::client_key;
+use crate::roles::client_role;
+use crate::room::send_system_to_room;
+use crate::room_policy::check_post;
+use crate::types::Incoming;
+
+/// Most dice in one roll.
+const MAX_DICE: u32 = 20;
+
+/// Most sides on one die.
+const MAX_SIDES: u32 = 1000;
+
+/// `/roll [NdM]`: roll dice and announce the result to the room.
+pub struct Roll;
+
+impl Roll {
+    const USAGE: &'static str = "/roll [NdM]";
+
+    /// Parse `NdM` (`d6`, `2d20`, ...); empty means one six-sided die.
+    fn parse_dice(spec: &str) -> Option<(u32, u32)> {
+        if spec.is_empty() {
+            return Some((1, 6));
+        }
+        let (count, sides) = spec.to_ascii_lowercase().split_once('d').map(|(c, s)| {
+            let count = if c.is_empty() { Ok(1) } else { c.parse() };
+            (count, s.parse())
+        })?;
+        let (count, sides) = (count.ok()?, sides.ok()?);
+        ((1..=MAX_DICE).contains(&count) && (2..=MAX_SIDES).contains(&sides))
+            .then_some((count, sides))
+    }
+}
+
+impl CommandHandler for Roll {
+    fn name(&self) -> &str {
+        "roll"
+    }
+
+    fn aliases(&self) -> &[&str] {
+        &["dice"]
+    }
+
+    fn usage(&self) -> &str {
+        Self::USAGE
+    }
+
+    fn summary(&self) -> &str {
+        "Roll dice (default 1d6)"
+    }
+
+    fn handle<'a>(&'a self, ctx: CommandContext<'a>, msg: Incoming) -> BoxFuture<'a, ActionResult> {
+        Box::pin(async move {
+            let Incoming::Command { args, .. } = msg else {
+                return Err(ActionError::usage(Self::USAGE));
+            };
+            let (count, sides) =
+                Self::parse_dice(args.trim()).ok_or_else(|| ActionError::usage(Self::USAGE))?;
+            let state = ctx.state;
+            let (room, key) = state
+                .clients
+                .get(ctx.client_id)
+                .map(|r| (r.value().room.clone(), client_key(r.value())))
+                .ok_or_else(ActionError::unknown_session)?;
+            // The result goes to the whole room, so it obeys the same rules
+            // as a chat message there
+            let role = client_role(&state.clients, &state.roles, ctx.client_id);
+            check_post(state, &room, &key, role)?;
+            state.spam.check_muted(&key)?;
+            // v4 UUIDs are random; good enough for dice
+            let rolls: Vec<u32> = (0..count)
+                .map(|_| (Uuid::new_v4().as_u128() % sides as u128) as u32 + 1)
+                .collect();
+            let total: u32 = rolls.iter().sum();
+            let name = client_name_by_id(&state.clients, ctx.client_id).await;
+            let detail = if count == 1 {
+                String::new()
+            } else {
+                let parts: Vec<String> = rolls.iter().map(u32::to_string).collect();
+                format!("{} = ", parts.join(" + "))
+            };
+            let text = format!("{} rolled {}d{}: {}{}", name, count, sides, detail, total);
+            send_system_to_room(&state.clients, &state.histories, &room, &text).await;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dice() {
+        assert_eq!(Roll::parse_dice(""), Some((1, 6)));
+        assert_eq!(Roll::parse_dice("d8"), Some((1, 8)));
+        assert_eq!(Roll::parse_dice("2D20"), Some((2, 20)));
+        assert_eq!(Roll::parse_dice("20d1000"), Some((20, 1000)));
+    }
+
+    #[test]
+    fn rejects_bad_dice() {
+        for spec in [
+            "6", "0d6", "21d6", "2d1", "2d1001", "2d", "xdy", "-1d6", "2d6d6",
+        ] {
+            assert_eq!(Roll::parse_dice(spec), None, "{}", spec);
+        }
+    }
+}
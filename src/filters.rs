@@ -0,0 +1,79 @@
+//! Built-in message filters. The word filter lives in `word_filter.rs`.
+
+use futures::future::BoxFuture;
+use regex::Regex;
+use std::sync::OnceLock;
+
+use crate::encoding::Payload;
+use crate::errors::ActionResult;
+use crate::helpers::fetch_preview;
+use crate::message_filter::{MessageContext, MessageFilter};
+use crate::types::{HistoryItem, Outgoing};
+
+/// Flag added to messages that contain a URL.
+pub const LINK_FLAG: &str = "link";
+
+/// First `http(s)://` URL in `text`.
+pub fn first_url(text: &str) -> Option<&str> {
+    static URL_RE: OnceLock<Regex> = OnceLock::new();
+    let re = URL_RE.get_or_init(|| Regex::new(r"(https?://\S+)").unwrap());
+    re.find(text).map(|m| m.as_str())
+}
+
+/// Flags messages containing a URL and, once stored, fetches a preview of
+/// the first one in the background and broadcasts it to the room.
+pub struct LinkPreview;
+
+impl MessageFilter for LinkPreview {
+    fn name(&self) -> &str {
+        "links"
+    }
+
+    fn summary(&self) -> &str {
+        "Fetch previews for links"
+    }
+
+    fn before<'a>(
+        &'a self,
+        _ctx: &'a MessageContext<'a>,
+        item: &'a mut HistoryItem,
+    ) -> BoxFuture<'a, ActionResult> {
+        if first_url(&item.text).is_some() {
+            item.flags.push(LINK_FLAG.to_string());
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn after<'a>(
+        &'a self,
+        ctx: &'a MessageContext<'a>,
+        item: &'a HistoryItem,
+    ) -> BoxFuture<'a, ()> {
+        if let Some(url) = first_url(&item.text) {
+            let url = url.to_string();
+            let clients = ctx.state.clients.clone();
+            let room = ctx.room.to_string();
+            let msg_id = item.id.clone();
+
+            // Fetch in the background so posting never waits on the network
+            tokio::spawn(async move {
+                if let Some((title, desc, image)) = fetch_preview(&url).await {
+                    let preview_msg = Outgoing::LinkPreview {
+                        msg_id,
+                        title,
+                        description: desc,
+                        image,
+                        url,
+                    };
+                    let payload = Payload::new(preview_msg);
+                    for r in clients.iter() {
+                        if r.value().in_room(&room) {
+                            payload.send_to(&r.value().tx);
+                        }
+                    }
+                }
+            });
+        }
+        Box::pin(async {})
+    }
+}
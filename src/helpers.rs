@@ -1,7 +1,8 @@
 //! Helper functions for client operations.
 
+use crate::encoding::Outbound;
+use crate::rate_limit::client_key;
 use crate::types::{Clients, Tx};
-use regex::Regex;
 use std::sync::OnceLock;
 use std::time::{Duration, SystemTime};
 
@@ -26,22 +27,17 @@ pub fn now_ts() -> u64 {
         .unwrap_or(0)
 }
 
-pub fn censor_profanity(text: &str) -> String {
-    static PROFANITY_RE: OnceLock<Regex> = OnceLock::new();
-    let re = PROFANITY_RE.get_or_init(|| {
-        Regex::new(r"(?i)\b(badword1|badword2|badword3)\b").unwrap() // Placeholder list
-    });
-    re.replace_all(text, "****").to_string()
-}
-
 /// Make a username unique among currently connected clients.
-pub async fn make_unique_name(clients: &Clients, desired: &str) -> String {
+/// Sessions belonging to the same `account` share its name and never collide.
+pub async fn make_unique_name(clients: &Clients, desired: &str, account: Option<&str>) -> String {
     let mut candidate = desired.to_string();
     let mut suffix = 1usize;
     loop {
-        let collision = clients
-            .iter()
-            .any(|r| r.value().name.eq_ignore_ascii_case(&candidate));
+        let collision = clients.iter().any(|r| {
+            let c = r.value();
+            c.name.eq_ignore_ascii_case(&candidate)
+                && (account.is_none() || c.account.as_deref() != account)
+        });
         if !collision {
             return candidate;
         }
@@ -50,6 +46,77 @@ pub async fn make_unique_name(clients: &Clients, desired: &str) -> String {
     }
 }
 
+/// Get the IDs of all sessions using a display name (all devices of one user).
+pub fn session_ids_by_name(clients: &Clients, name: &str) -> Vec<String> {
+    clients
+        .iter()
+        .filter(|r| r.value().name.eq_ignore_ascii_case(name))
+        .map(|r| r.key().clone())
+        .collect()
+}
+
+/// Get the IDs of all sessions logged in to `account`.
+pub fn session_ids_by_account(clients: &Clients, account: &str) -> Vec<String> {
+    clients
+        .iter()
+        .filter(|r| {
+            r.value()
+                .account
+                .as_deref()
+                .map(|a| a.eq_ignore_ascii_case(account))
+                .unwrap_or(false)
+        })
+        .map(|r| r.key().clone())
+        .collect()
+}
+
+/// Get the IDs of all sessions with the rate-limit key `key` (see
+/// `rate_limit::client_key`): an account's sessions, or a guest address's.
+pub fn session_ids_by_key(clients: &Clients, key: &str) -> Vec<String> {
+    clients
+        .iter()
+        .filter(|r| client_key(r.value()) == key)
+        .map(|r| r.key().clone())
+        .collect()
+}
+
+/// Close the sessions `ids` with close code 1008 and `reason`. Each one's
+/// main loop then ends, and its cleanup removes it from `clients` and tells
+/// the room it left.
+pub fn disconnect_sessions(clients: &Clients, ids: &[String], reason: &'static str) {
+    for id in ids {
+        if let Some(c) = clients.get(id) {
+            let _ = c.tx.send(Outbound::Close(1008, reason));
+        }
+    }
+}
+
+/// Check whether another session of the same user is already in `room`.
+pub fn other_session_in_room(clients: &Clients, client_id: &str, name: &str, room: &str) -> bool {
+    clients.iter().any(|r| {
+        let c = r.value();
+        r.key() != client_id && c.room == room && c.name.eq_ignore_ascii_case(name)
+    })
+}
+
+/// Derive a coarse device label from a User-Agent header.
+pub fn device_label(user_agent: Option<&str>) -> String {
+    let ua = match user_agent {
+        Some(ua) => ua.to_ascii_lowercase(),
+        None => return "unknown".to_string(),
+    };
+    let label = if ua.contains("ipad") || ua.contains("tablet") {
+        "tablet"
+    } else if ua.contains("mobile") || ua.contains("android") || ua.contains("iphone") {
+        "phone"
+    } else if ua.contains("mozilla") {
+        "desktop"
+    } else {
+        "other"
+    };
+    label.to_string()
+}
+
 /// Fetch URL preview (OG tags)
 pub async fn fetch_preview(url: &str) -> Option<(String, String, String)> {
     const MAX_PREVIEW_BYTES: usize = 256 * 1024;
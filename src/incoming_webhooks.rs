@@ -0,0 +1,202 @@
+//! Incoming webhooks: admin-created URLs (`POST /hooks/{secret}`) that let
+//! scripts and CI jobs post into one room as a bot.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+use crate::errors::{error_reply, ActionError, ErrorCode};
+use crate::helpers::now_ts;
+use crate::roles::Role;
+use crate::room::post_to_room;
+use crate::types::{AppState, HistoryItem, IncomingWebhooks};
+
+/// Posts allowed per hook within `HOOK_WINDOW`.
+const HOOK_RATE_LIMIT: usize = 20;
+
+/// Window for `HOOK_RATE_LIMIT`.
+const HOOK_WINDOW: Duration = Duration::from_secs(60);
+
+/// Longest accepted message text, in characters.
+const MAX_HOOK_TEXT: usize = 4000;
+
+/// Longest display name a post may set.
+const MAX_HOOK_USERNAME: usize = 32;
+
+/// Largest accepted request body.
+pub const MAX_HOOK_BODY: u64 = 64 * 1024;
+
+/// One incoming webhook.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IncomingWebhook {
+    /// Short ID used to list and delete the hook.
+    pub id: String,
+    /// Secret part of the URL; whoever knows it can post.
+    pub secret: String,
+    pub room: String,
+    /// Default display name for posts.
+    pub name: String,
+    pub created_by: String,
+    pub created: u64,
+    /// Recent post times, for rate limiting
+    #[serde(skip)]
+    pub recent: Vec<Instant>,
+}
+
+/// Body of `POST /hooks/{secret}`.
+#[derive(Deserialize)]
+struct HookPost {
+    text: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    icon: Option<String>,
+}
+
+/// Load hooks from `webhooks.json` (synchronous, done at startup).
+pub fn load_webhooks() -> anyhow::Result<HashMap<String, IncomingWebhook>> {
+    let path = "webhooks.json";
+    if Path::new(path).exists() {
+        let s = fs::read_to_string(path)?;
+        let m: HashMap<String, IncomingWebhook> = serde_json::from_str(&s)?;
+        info!("Loaded {} incoming webhooks from disk", m.len());
+        Ok(m)
+    } else {
+        Ok(HashMap::new())
+    }
+}
+
+/// Save hooks to disk (async-friendly via spawn_blocking).
+pub async fn save_webhooks_async(hooks: &IncomingWebhooks) -> anyhow::Result<()> {
+    let map: HashMap<String, IncomingWebhook> = hooks
+        .iter()
+        .map(|r| (r.key().clone(), r.value().clone()))
+        .collect();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let s = serde_json::to_string_pretty(&map)?;
+        fs::write("webhooks.json", s)?;
+        Ok(())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Create a hook posting into `room` and return it.
+pub fn create_webhook(
+    hooks: &IncomingWebhooks,
+    room: &str,
+    name: &str,
+    created_by: &str,
+) -> IncomingWebhook {
+    let hook = IncomingWebhook {
+        id: Uuid::new_v4().simple().to_string()[..8].to_string(),
+        secret: format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()),
+        room: room.to_string(),
+        name: name.to_string(),
+        created_by: created_by.to_string(),
+        created: now_ts(),
+        recent: Vec::new(),
+    };
+    hooks.insert(hook.id.clone(), hook.clone());
+    info!(
+        "{} created incoming webhook {} for '{}'",
+        created_by, hook.id, room
+    );
+    hook
+}
+
+/// URL path clients post to for `hook`.
+pub fn hook_path(hook: &IncomingWebhook) -> String {
+    format!("/hooks/{}", hook.secret)
+}
+
+/// `POST /hooks/{secret}`: post `{text, username, icon}` into the hook's
+/// room as a bot.
+pub async fn handle_hook_post(
+    secret: String,
+    body: Bytes,
+    state: AppState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match post_via_hook(&secret, &body, &state).await {
+        Ok(item) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"ok": true, "id": item.id})),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(error_reply(&e, None)),
+    }
+}
+
+async fn post_via_hook(
+    secret: &str,
+    body: &[u8],
+    state: &AppState,
+) -> Result<HistoryItem, ActionError> {
+    let post: HookPost = serde_json::from_slice(body)
+        .map_err(|e| ActionError::new(ErrorCode::BadRequest, format!("invalid body: {}", e)))?;
+    let text = post.text.trim();
+    if text.is_empty() {
+        return Err(ActionError::new(
+            ErrorCode::BadRequest,
+            "text must not be empty",
+        ));
+    }
+    if text.chars().count() > MAX_HOOK_TEXT {
+        return Err(ActionError::new(
+            ErrorCode::PayloadTooLarge,
+            format!("text is longer than {} characters", MAX_HOOK_TEXT),
+        ));
+    }
+
+    // Find the hook and record the post against its rate limit
+    let (room, name) = {
+        let mut hook = state
+            .incoming_webhooks
+            .iter_mut()
+            .find(|r| r.value().secret == secret)
+            .ok_or_else(|| ActionError::new(ErrorCode::NotFound, "unknown webhook"))?;
+        let hook = hook.value_mut();
+        let now = Instant::now();
+        hook.recent.retain(|t| now.duration_since(*t) < HOOK_WINDOW);
+        if hook.recent.len() >= HOOK_RATE_LIMIT {
+            warn!("Incoming webhook {} is rate limited", hook.id);
+            return Err(ActionError::new(
+                ErrorCode::RateLimited,
+                format!(
+                    "Rate limited: max {} posts per {} seconds.",
+                    HOOK_RATE_LIMIT,
+                    HOOK_WINDOW.as_secs()
+                ),
+            ));
+        }
+        hook.recent.push(now);
+        (hook.room.clone(), hook.name.clone())
+    };
+    if !state.histories.read().await.contains_key(&room) {
+        return Err(ActionError::new(
+            ErrorCode::NotFound,
+            "the webhook's room no longer exists",
+        ));
+    }
+
+    let username = post
+        .username
+        .as_deref()
+        .map(str::trim)
+        .filter(|u| !u.is_empty())
+        .map(|u| u.chars().take(MAX_HOOK_USERNAME).collect::<String>())
+        .unwrap_or(name);
+    let mut item = HistoryItem::new(username, text);
+    item.bot = true;
+    item.icon = post
+        .icon
+        .map(|i| i.trim().to_string())
+        .filter(|i| !i.is_empty());
+    post_to_room(state, &room, item, Role::User, None, None).await
+}
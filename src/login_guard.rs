@@ -0,0 +1,284 @@
+//! Failed-login tracking: exponential backoff and temporary lockout per
+//! account and per IP address, shared by `/login`, the `login` message and
+//! `POST /api/tokens`.
+
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::auth::verify_login;
+use crate::errors::{ActionError, ActionResult, ErrorCode};
+use crate::rate_limit::{account_key, ip_key};
+use crate::types::AppState;
+
+/// Failures allowed before any delay.
+const FREE_FAILURES: u32 = 3;
+
+/// Delay after the first failure past the free ones; doubles with each
+/// further failure.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest backoff delay short of a lockout.
+const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Failure counts are forgotten after this long without a new failure.
+const FORGET_AFTER: Duration = Duration::from_secs(3600);
+
+/// Records are pruned once this many are tracked.
+const PRUNE_AT: usize = 10_000;
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    blocked_until: Instant,
+    /// Attempts started but not yet decided
+    in_flight: u32,
+}
+
+impl Failures {
+    fn new(now: Instant) -> Self {
+        Failures {
+            count: 0,
+            last: now,
+            blocked_until: now,
+            in_flight: 0,
+        }
+    }
+}
+
+/// Failed logins per `user:<account>` and `ip:<address>` key, held in
+/// `AppState::login_guard`.
+pub struct LoginGuard {
+    max_failures: u32,
+    lockout: Duration,
+    records: DashMap<String, Failures>,
+}
+
+impl LoginGuard {
+    /// Lock a key out for `lockout` after `max_failures` failures in a row.
+    pub fn new(max_failures: u32, lockout: Duration) -> Self {
+        LoginGuard {
+            max_failures: max_failures.max(FREE_FAILURES + 1),
+            lockout,
+            records: DashMap::new(),
+        }
+    }
+
+    /// Drop stale records once too many are tracked.
+    fn prune(&self, now: Instant) {
+        if self.records.len() >= PRUNE_AT {
+            self.records.retain(|_, r| {
+                now.duration_since(r.last) < FORGET_AFTER
+                    || r.blocked_until > now
+                    || r.in_flight > 0
+            });
+        }
+    }
+
+    /// Start an attempt on `key`. Refused while the key is backing off or
+    /// locked out, and, once its pending attempts could use up the free
+    /// failures, while another attempt is still being checked; otherwise a
+    /// burst of parallel guesses would all start before any failed.
+    fn begin(&self, key: &str) -> ActionResult {
+        let now = Instant::now();
+        self.prune(now);
+        let mut r = self
+            .records
+            .entry(key.to_string())
+            .or_insert_with(|| Failures::new(now));
+        let wait = r.blocked_until.saturating_duration_since(now);
+        if !wait.is_zero() {
+            return Err(ActionError::new(
+                ErrorCode::RateLimited,
+                format!(
+                    "Too many failed logins. Try again in {} seconds.",
+                    wait.as_secs().max(1)
+                ),
+            ));
+        }
+        if r.in_flight > 0 && r.count + r.in_flight >= FREE_FAILURES {
+            return Err(ActionError::new(
+                ErrorCode::RateLimited,
+                "Another login attempt is still being checked. Try again in a moment.",
+            ));
+        }
+        r.in_flight += 1;
+        Ok(())
+    }
+
+    /// Finish an attempt begun on `key`, forgetting the record if nothing
+    /// is left in it.
+    fn end(&self, key: &str) {
+        if let Some(mut r) = self.records.get_mut(key) {
+            r.in_flight = r.in_flight.saturating_sub(1);
+        }
+        let now = Instant::now();
+        self.records.remove_if(key, |_, r| {
+            r.in_flight == 0 && r.count == 0 && r.blocked_until <= now
+        });
+    }
+
+    /// Start a login attempt for `account` from `ip`, or refuse it with
+    /// `rate_limited`. The attempt counts as pending until the returned
+    /// guard is dropped.
+    fn start(&self, account: &str, ip: &str) -> Result<Attempt<'_>, ActionError> {
+        let keys = [account_key(account), ip_key(ip)];
+        self.begin(&keys[0])?;
+        if let Err(e) = self.begin(&keys[1]) {
+            self.end(&keys[0]);
+            return Err(e);
+        }
+        Ok(Attempt { guard: self, keys })
+    }
+
+    /// Count a failure against `key`. Returns the lockout length when this
+    /// failure locked it out.
+    fn fail(&self, key: String) -> Option<Duration> {
+        let now = Instant::now();
+        self.prune(now);
+        let mut r = self
+            .records
+            .entry(key)
+            .or_insert_with(|| Failures::new(now));
+        if now.duration_since(r.last) >= FORGET_AFTER {
+            r.count = 0;
+        }
+        r.count += 1;
+        r.last = now;
+        if r.count >= self.max_failures {
+            // Start over after the lockout, free attempts and all
+            r.count = 0;
+            r.blocked_until = now + self.lockout;
+            return Some(self.lockout);
+        }
+        if r.count > FREE_FAILURES {
+            let delay = BASE_DELAY * 2u32.saturating_pow(r.count - FREE_FAILURES - 1);
+            r.blocked_until = now + delay.min(MAX_DELAY);
+        }
+        None
+    }
+
+    /// Count a failed login for `account` from `ip`. Returns the keys that
+    /// were locked out by it.
+    fn record_failure(&self, account: &str, ip: &str) -> Vec<(String, Duration)> {
+        [account_key(account), ip_key(ip)]
+            .into_iter()
+            .filter_map(|key| self.fail(key.clone()).map(|d| (key, d)))
+            .collect()
+    }
+
+    /// Forget the account's failures after a successful login. The
+    /// address's count is left to expire, so guessing at other accounts
+    /// stays slowed down.
+    fn record_success(&self, account: &str) {
+        if let Some(mut r) = self.records.get_mut(&account_key(account)) {
+            r.count = 0;
+            r.blocked_until = Instant::now();
+        }
+    }
+}
+
+/// A login attempt in progress, from `LoginGuard::start` until dropped.
+struct Attempt<'a> {
+    guard: &'a LoginGuard,
+    keys: [String; 2],
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        for key in &self.keys {
+            self.guard.end(key);
+        }
+    }
+}
+
+/// Check `username`'s password for a login from `ip`, applying backoff and
+/// lockout. Fails with `auth_failed` for wrong credentials and
+/// `rate_limited` while blocked.
+pub async fn attempt_login(
+    state: &AppState,
+    username: &str,
+    password: &str,
+    ip: &str,
+) -> ActionResult {
+    let guard = &state.login_guard;
+    // Held across the bcrypt check, so parallel requests see this attempt
+    let _attempt = guard.start(username, ip)?;
+    if verify_login(&state.users, username, password).await {
+        guard.record_success(username);
+        return Ok(());
+    }
+    for (key, lockout) in guard.record_failure(username, ip) {
+        warn!("Locking out {} for {:?} after failed logins", key, lockout);
+        state.audit.record(
+            "system",
+            "lockout",
+            &key,
+            None,
+            Some(&format!(
+                "{} minutes after failed logins",
+                lockout.as_secs() / 60
+            )),
+        );
+    }
+    Err(ActionError::new(
+        ErrorCode::AuthFailed,
+        "invalid username or password",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_then_lockout() {
+        let guard = LoginGuard::new(6, Duration::from_secs(900));
+        let key = || "user:alice".to_string();
+        for _ in 0..FREE_FAILURES {
+            assert_eq!(guard.fail(key()), None);
+            assert!(guard.begin("user:alice").is_ok());
+            guard.end("user:alice");
+        }
+        assert_eq!(guard.fail(key()), None);
+        let err = guard.begin("user:alice").unwrap_err();
+        assert_eq!(err.code, ErrorCode::RateLimited);
+        assert_eq!(guard.fail(key()), None);
+        assert_eq!(guard.fail(key()), Some(Duration::from_secs(900)));
+        let r = guard.records.get("user:alice").unwrap();
+        assert_eq!(r.count, 0);
+        assert!(r.blocked_until > Instant::now() + Duration::from_secs(890));
+    }
+
+    #[test]
+    fn pending_attempts_count_before_they_fail() {
+        let guard = LoginGuard::new(10, Duration::from_secs(900));
+        let attempts: Vec<_> = (0..FREE_FAILURES)
+            .map(|_| guard.start("alice", "10.0.0.1").unwrap())
+            .collect();
+        let Err(err) = guard.start("alice", "10.0.0.2") else {
+            panic!("a fourth parallel attempt was let through");
+        };
+        assert_eq!(err.code, ErrorCode::RateLimited);
+        drop(attempts);
+        assert!(guard.records.is_empty());
+        assert!(guard.start("alice", "10.0.0.2").is_ok());
+    }
+
+    #[test]
+    fn success_clears_the_account_but_not_the_address() {
+        let guard = LoginGuard::new(10, Duration::from_secs(900));
+        for _ in 0..FREE_FAILURES {
+            guard.record_failure("alice", "10.0.0.1");
+        }
+        {
+            let _attempt = guard.start("alice", "10.0.0.1").unwrap();
+            guard.record_success("alice");
+        }
+        assert!(!guard.records.contains_key("user:alice"));
+        assert_eq!(
+            guard.records.get("ip:10.0.0.1").unwrap().count,
+            FREE_FAILURES
+        );
+    }
+}
@@ -2,51 +2,146 @@
 //!
 //! Modular structure:
 //! - types.rs: Core data structures
+//! - api.rs: HTTP REST API
+//! - tokens.rs: API bearer tokens
+//! - audit.rs: Audit log of moderation and admin actions
 //! - auth.rs: User authentication
+//! - bots.rs: Bot accounts
 //! - room.rs: Room management
+//! - room_settings.rs: Per-room settings
+//! - room_policy.rs: Slow mode, read-only rooms and guest posting
+//! - command_registry.rs: Slash command trait and registry
 //! - commands.rs: Command handling
+//! - config.rs: Settings from environment variables
+//! - connections.rs: Per-address connection caps
 //! - client.rs: WebSocket client lifecycle
+//! - extra_commands.rs: Example add-on commands (/roll)
+//! - filters.rs: Built-in message filters (censor, link previews)
+//! - encoding.rs: JSON and MessagePack wire encodings
+//! - errors.rs: Error codes and request acks
 //! - helpers.rs: Client helper functions
-//! - rate_limit.rs: Rate limiting
+//! - incoming_webhooks.rs: Incoming webhook URLs
+//! - login_guard.rs: Failed-login backoff and lockout
+//! - mentions.rs: Mention routing and inbox
+//! - message_filter.rs: Message pipeline of per-room filters
+//! - outgoing_webhooks.rs: Room events POSTed to configured URLs
+//! - reports.rs: Reported messages and the moderation queue
+//! - roles.rs: User roles and permissions
+//! - presence.rs: Presence and user-set statuses
+//! - protocol.rs: Protocol versioning and handshake
+//! - rate_limit.rs: Token-bucket rate limits per action and account/IP
+//! - spam.rs: Spam scoring, mutes and kicks
+//! - sse.rs: Server-Sent Events + HTTP POST fallback transport
 //! - typing.rs: Typing indicators
+//! - unread.rs: Read markers and unread counts
 //! - upload.rs: File uploads
+//! - upload_quota.rs: Upload size limits, disk quotas and free-space floor
+//! - word_filter.rs: Banned-word list and per-room word filter
 
+mod api;
+mod audit;
 mod auth;
+mod bots;
 mod client;
+mod command_registry;
 mod commands;
+mod config;
+mod connections;
+mod encoding;
+mod errors;
+mod extra_commands;
+mod filters;
 mod helpers;
+mod incoming_webhooks;
+mod login_guard;
+mod mentions;
+mod message_filter;
 mod metrics;
+mod outgoing_webhooks;
+mod presence;
+mod protocol;
 mod rate_limit;
+mod reports;
+mod roles;
 mod room;
+mod room_policy;
+mod room_settings;
+mod spam;
+mod sse;
+mod tokens;
 mod types;
 mod typing;
+mod unread;
 mod upload;
+mod upload_quota;
+mod word_filter;
 
 use dashmap::DashMap;
 use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tracing::{info, warn};
-use warp::Filter;
+use warp::{Filter, Reply};
 
 use crate::auth::load_users;
 use crate::client::client_connected;
-use crate::types::{Clients, Histories, PrivateHistories, Users};
-use crate::upload::handle_upload;
+use crate::command_registry::CommandRegistry;
+use crate::errors::error_reply;
+use crate::message_filter::MessagePipeline;
+use crate::outgoing_webhooks::OutgoingWebhooks;
+use crate::types::{
+    ApiTokens, AppState, Bots, Clients, Histories, IncomingWebhooks, Mentions, Presences,
+    PrivateHistories, ReadMarkers, Reports, Roles, RoomSettingsMap, UploadIndex, Users, WsParams,
+};
+use crate::upload::{handle_upload, handle_upload_rejection};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Initialize tracing
     tracing_subscriber::fmt::init();
     info!("Starting Rust Chat Server...");
+    let config = Arc::new(crate::config::Config::from_env());
 
     // Load users from disk
     let users_map = load_users().unwrap_or_default();
     let users: Users = Arc::new(dashmap::DashMap::from_iter(users_map));
+    let roles_map = crate::roles::load_roles().unwrap_or_default();
+    let roles: Roles = Arc::new(dashmap::DashMap::from_iter(roles_map));
+    let tokens_map = crate::tokens::load_tokens().unwrap_or_default();
+    let api_tokens: ApiTokens = Arc::new(DashMap::from_iter(tokens_map));
+    let webhooks_map = crate::incoming_webhooks::load_webhooks().unwrap_or_default();
+    let incoming_webhooks: IncomingWebhooks = Arc::new(DashMap::from_iter(webhooks_map));
+    let bots_map = crate::bots::load_bots().unwrap_or_default();
+    let bots: Bots = Arc::new(DashMap::from_iter(bots_map));
+    let room_settings_map = crate::room_settings::load_room_settings().unwrap_or_default();
+    let room_settings: RoomSettingsMap = Arc::new(DashMap::from_iter(room_settings_map));
+    let reports_map = crate::reports::load_reports().unwrap_or_default();
+    let reports: Reports = Arc::new(DashMap::from_iter(reports_map));
+    let uploads_map = crate::upload_quota::load_uploads().unwrap_or_default();
+    let upload_usage = Arc::new(crate::upload_quota::UploadUsage::load(&uploads_map));
+    let uploads: UploadIndex = Arc::new(DashMap::from_iter(uploads_map));
+    let audit = Arc::new(crate::audit::AuditLog::open(&config.audit_log_file));
+    let outgoing_hooks = crate::outgoing_webhooks::load_outgoing_webhooks().unwrap_or_else(|e| {
+        tracing::error!("failed to load outgoing_webhooks.json: {}", e);
+        Vec::new()
+    });
+    let outgoing_webhooks = Arc::new(OutgoingWebhooks::start(outgoing_hooks));
+
+    // Slash commands: the built-ins plus any add-ons registered here
+    let mut commands = CommandRegistry::with_builtins();
+    commands.register(crate::extra_commands::Roll);
+
+    // Message filters, in the order they run
+    let word_list = Arc::new(crate::word_filter::WordList::load(&config.word_filter_file));
+    tokio::spawn(crate::word_filter::watch(word_list.clone()));
+    let filters = MessagePipeline::with_builtins(word_list.clone());
 
     let clients: Clients = Arc::new(DashMap::new());
     let histories: Histories = Arc::new(RwLock::new(HashMap::new()));
     let private_histories: PrivateHistories = Arc::new(RwLock::new(HashMap::new()));
+    let read_markers: ReadMarkers = Arc::new(RwLock::new(HashMap::new()));
+    let mentions: Mentions = Arc::new(RwLock::new(HashMap::new()));
+    let presences: Presences = Arc::new(DashMap::new());
 
     // Initialize server metrics
     let server_metrics = Arc::new(crate::metrics::ServerMetrics::new());
@@ -54,6 +149,9 @@ async fn main() -> anyhow::Result<()> {
     // Load history from disk
     crate::room::load_history(&histories).await;
     crate::room::load_private_history(&private_histories).await;
+    crate::unread::load_read_markers(&read_markers).await;
+    crate::mentions::load_mentions(&mentions).await;
+    crate::presence::load_presences(&presences).await;
 
     // Ensure persistent rooms exist
     {
@@ -64,19 +162,57 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
+    let state = AppState {
+        clients: clients.clone(),
+        histories: histories.clone(),
+        private_histories: private_histories.clone(),
+        users: users.clone(),
+        roles: roles.clone(),
+        read_markers: read_markers.clone(),
+        mentions: mentions.clone(),
+        presences: presences.clone(),
+        metrics: server_metrics.clone(),
+        config: config.clone(),
+        sse_sessions: Arc::new(DashMap::new()),
+        api_tokens,
+        incoming_webhooks,
+        outgoing_webhooks,
+        bots,
+        commands: Arc::new(commands),
+        filters: Arc::new(filters),
+        room_settings,
+        last_posts: Arc::new(DashMap::new()),
+        word_list,
+        spam: Arc::new(crate::spam::SpamDetector::new()),
+        reports,
+        audit,
+        rate_limiter: Arc::new(crate::rate_limit::RateLimiter::new(
+            config.rate_limits.clone(),
+        )),
+        connections: Arc::new(crate::connections::ConnectionCaps::new(
+            config.max_connections_per_ip,
+        )),
+        login_guard: Arc::new(crate::login_guard::LoginGuard::new(
+            config.login_max_failures,
+            std::time::Duration::from_secs(config.login_lockout_secs),
+        )),
+        uploads,
+        upload_usage,
+    };
+
     // Warp filters for shared state
+    let state_c = state.clone();
+    let state_filter = warp::any().map(move || state_c.clone());
     let clients_c = clients.clone();
     let clients_filter = warp::any().map(move || clients_c.clone());
     let histories_c = histories.clone();
     let histories_filter = warp::any().map(move || histories_c.clone());
-    let private_histories_c = private_histories.clone();
-    let private_histories_filter = warp::any().map(move || private_histories_c.clone());
-    let users_c = users.clone();
-    let users_filter = warp::any().map(move || users_c.clone());
 
     // Metrics filter
     let metrics_c = server_metrics.clone();
     let metrics_filter = warp::any().map(move || metrics_c.clone());
+    let config_c = config.clone();
+    let config_filter = warp::any().map(move || config_c.clone());
 
     // Health endpoint
     let health_route = warp::path("health")
@@ -100,10 +236,12 @@ async fn main() -> anyhow::Result<()> {
         .and(clients_filter.clone())
         .and(histories_filter.clone())
         .and(metrics_filter.clone())
+        .and(config_filter)
         .and_then(
             |clients: crate::types::Clients,
              histories: crate::types::Histories,
-             metrics: Arc<crate::metrics::ServerMetrics>| async move {
+             metrics: Arc<crate::metrics::ServerMetrics>,
+             config: Arc<crate::config::Config>| async move {
                 let room_count = histories.read().await.len();
                 let response = serde_json::json!({
                     "uptime_seconds": metrics.uptime_secs(),
@@ -111,7 +249,15 @@ async fn main() -> anyhow::Result<()> {
                     "total_messages": metrics.get_total_messages(),
                     "active_clients": clients.len(),
                     "active_rooms": room_count,
-                    "total_connections": metrics.get_total_connections()
+                    "total_connections": metrics.get_total_connections(),
+                    "compression": {
+                        "enabled": config.compression,
+                        "min_bytes": config.compression_min_bytes,
+                        "raw_bytes": metrics.get_deflate_raw_bytes(),
+                        "wire_bytes": metrics.get_deflate_wire_bytes(),
+                        "compressed_frames": metrics.get_compressed_frames(),
+                        "ratio": metrics.compression_ratio()
+                    }
                 });
                 Ok::<_, warp::Rejection>(warp::reply::json(&response))
             },
@@ -121,27 +267,57 @@ async fn main() -> anyhow::Result<()> {
     let ws_route = warp::path("ws")
         .and(warp::ws())
         .and(warp::addr::remote())
-        .and(clients_filter)
-        .and(histories_filter)
-        .and(private_histories_filter)
-        .and(users_filter)
-        .and(metrics_filter)
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::query::<WsParams>())
+        .and(state_filter.clone())
         .map(
-            |ws: warp::ws::Ws, remote, clients, histories, private_histories, users, metrics| {
-                ws.on_upgrade(move |socket| {
-                    client_connected(
-                        socket,
-                        remote,
-                        clients,
-                        histories,
-                        private_histories,
-                        users,
-                        metrics,
-                    )
-                })
+            |ws: warp::ws::Ws,
+             remote,
+             user_agent: Option<String>,
+             params: WsParams,
+             state: AppState| {
+                // Refuse before upgrading; the permit lives as long as the socket
+                match state.connections.acquire(remote) {
+                    Ok(permit) => ws
+                        .on_upgrade(move |socket| async move {
+                            client_connected(socket, remote, user_agent, params, state).await;
+                            drop(permit);
+                        })
+                        .into_response(),
+                    Err(e) => error_reply(&e, None).into_response(),
+                }
             },
         );
 
+    // SSE fallback transport: event stream down, POSTs up
+    let sse_route = warp::path("sse")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::query::<WsParams>())
+        .and(state_filter.clone())
+        .map(crate::sse::sse_connected);
+    let sse_post_route = warp::path!("sse" / String)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(crate::sse::MAX_POST_BYTES))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and_then(crate::sse::sse_post);
+
+    // REST API
+    let api_route = crate::api::routes(state.clone());
+
+    // Incoming webhooks
+    let hooks_route = warp::path!("hooks" / String)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            crate::incoming_webhooks::MAX_HOOK_BODY,
+        ))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and_then(crate::incoming_webhooks::handle_hook_post);
+
     // Static file routes
     let index_route = warp::path::end().and(warp::fs::file("static/index.html"));
     let static_route = warp::fs::dir("static");
@@ -150,12 +326,26 @@ async fn main() -> anyhow::Result<()> {
     // File upload route
     let upload_route = warp::path("upload")
         .and(warp::post())
-        // 5 GB limit
-        .and(warp::multipart::form().max_length(5_368_709_120))
-        .and_then(handle_upload);
+        .and(
+            warp::multipart::form().max_length(
+                config
+                    .upload_max_request_bytes
+                    .saturating_add(crate::upload::MULTIPART_OVERHEAD),
+            ),
+        )
+        .and(warp::header::optional::<String>("x-request-id"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::addr::remote())
+        .and(state_filter)
+        .and_then(handle_upload)
+        .recover(handle_upload_rejection);
 
     // Combine routes
     let routes = ws_route
+        .or(sse_route)
+        .or(sse_post_route)
+        .or(api_route)
+        .or(hooks_route)
         .or(health_route)
         .or(metrics_route)
         .or(index_route)
@@ -203,45 +393,15 @@ async fn main() -> anyhow::Result<()> {
     }
 
     // Background task for idle detection
-    let clients_idle = clients.clone();
+    let state_idle = state.clone();
     tokio::task::spawn(async move {
         let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
-        let mut statuses: HashMap<String, String> = HashMap::new(); // client_id -> status
+        // user -> presence as last broadcast
+        let mut last: HashMap<String, crate::presence::Presence> = HashMap::new();
 
         loop {
             interval.tick().await;
-            let now = std::time::Instant::now();
-            let mut updates = Vec::new();
-
-            for r in clients_idle.iter() {
-                let client_id = r.key().clone();
-                let client = r.value();
-                let last_active = client.last_active;
-                let diff = now.duration_since(last_active);
-
-                let current_status = if diff.as_secs() > 300 {
-                    // 5 minutes
-                    "idle"
-                } else {
-                    "active"
-                };
-
-                let prev_status = statuses
-                    .get(&client_id)
-                    .map(|s| s.as_str())
-                    .unwrap_or("active");
-                if current_status != prev_status {
-                    updates.push((client.name.clone(), current_status.to_string()));
-                    statuses.insert(client_id, current_status.to_string());
-                }
-            }
-
-            for (name, status) in updates {
-                crate::room::broadcast_status(&clients_idle, &name, &status).await;
-            }
-
-            // Cleanup statuses for disconnected clients
-            statuses.retain(|id, _| clients_idle.contains_key(id));
+            crate::presence::refresh_presence(&state_idle, &mut last).await;
         }
     });
 
@@ -265,6 +425,26 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
+    // Background task for periodic read-marker saving (every 5 minutes)
+    let read_markers_saver = read_markers.clone();
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(300));
+        loop {
+            interval.tick().await;
+            crate::unread::save_read_markers(&read_markers_saver).await;
+        }
+    });
+
+    // Background task for periodic mention-inbox saving (every 5 minutes)
+    let mentions_saver = mentions.clone();
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(300));
+        loop {
+            interval.tick().await;
+            crate::mentions::save_mentions(&mentions_saver).await;
+        }
+    });
+
     // Print LAN Connection Info (QR Code)
     if let Ok(ip) = local_ip_address::local_ip() {
         let address = format!("http://{}:{}", ip, port);
@@ -295,6 +475,8 @@ async fn main() -> anyhow::Result<()> {
             // Save history
             crate::room::save_history(&histories).await;
             crate::room::save_private_history(&private_histories).await;
+            crate::unread::save_read_markers(&read_markers).await;
+            crate::mentions::save_mentions(&mentions).await;
 
             // Force save users before exit
             let users_map: HashMap<String, String> = users.iter()
@@ -0,0 +1,328 @@
+//! Mention routing, broadcast mentions (@here, @room), and the per-user inbox.
+
+use crate::encoding::{send, Payload};
+use crate::errors::{ActionError, ActionResult, ErrorCode};
+use crate::helpers::{client_tx_by_id, session_ids_by_account};
+use crate::presence::is_dnd;
+use crate::roles::Role;
+use crate::types::{AppState, HistoryItem, MentionItem, Mentions, Outgoing, Tx};
+use std::collections::{HashMap, VecDeque};
+use std::sync::OnceLock;
+use std::time::Instant;
+use tracing::{error, info};
+
+/// Maximum stored mentions per user.
+const MAX_INBOX: usize = 100;
+
+/// Sessions idle for longer than this are not reached by `@here`.
+const HERE_ACTIVE_SECS: u64 = 300;
+
+/// Extract @mentions from text.
+pub fn extract_mentions(text: &str) -> Vec<String> {
+    static MENTION_RE: OnceLock<regex::Regex> = OnceLock::new();
+    let re = MENTION_RE.get_or_init(|| regex::Regex::new(r"@(\w+)").expect("valid mention regex"));
+    re.captures_iter(text)
+        .filter_map(|cap| cap.get(1).map(|m| m.as_str().to_string()))
+        .collect()
+}
+
+/// Whether `text`, sent by someone with `sender_role`, mentions the user
+/// known by `names` (account and display names) under the rules
+/// `process_mentions` notifies by: `@name`, `@here` from registered users
+/// and `@room` / `@channel` from moderators. Used for unread mention counts.
+pub fn mentions_user(text: &str, sender_role: Role, names: &[&str]) -> bool {
+    extract_mentions(text)
+        .iter()
+        .any(|m| match m.to_lowercase().as_str() {
+            "here" => sender_role >= Role::User,
+            "room" | "channel" => sender_role >= Role::Moderator,
+            m => names.iter().any(|n| n.eq_ignore_ascii_case(m)),
+        })
+}
+
+/// Someone to notify: a registered account (stored in the inbox) or a
+/// guest session (live notification only).
+enum Recipient {
+    Account(String),
+    Guest(String),
+}
+
+/// Resolve the mentions in a new room message, store them in the inbox of
+/// each mentioned account and notify any live sessions. Refused `@here` /
+/// `@room` mentions are explained on `sender_tx`, if the sender is connected.
+pub async fn process_mentions(
+    state: &AppState,
+    sender_role: Role,
+    sender_tx: Option<&Tx>,
+    room: &str,
+    item: &HistoryItem,
+) {
+    let mentions = extract_mentions(&item.text);
+    if mentions.is_empty() {
+        return;
+    }
+
+    // key (lowercase) -> (recipient, mention text that reached them)
+    let mut recipients: HashMap<String, (Recipient, String)> = HashMap::new();
+    let mut add = |recipient: Recipient, mentioned: &str| {
+        let key = match &recipient {
+            Recipient::Account(a) => a.to_lowercase(),
+            Recipient::Guest(n) => format!("guest:{}", n.to_lowercase()),
+        };
+        recipients
+            .entry(key)
+            .or_insert_with(|| (recipient, mentioned.to_string()));
+    };
+
+    for mentioned in &mentions {
+        match mentioned.to_lowercase().as_str() {
+            "here" => {
+                if sender_role < Role::User {
+                    send_notice(sender_tx, "Only registered users can use @here.");
+                    continue;
+                }
+                let now = Instant::now();
+                for r in state.clients.iter() {
+                    let c = r.value();
+                    if !c.in_room(room)
+                        || now.duration_since(c.last_active).as_secs() > HERE_ACTIVE_SECS
+                    {
+                        continue;
+                    }
+                    match &c.account {
+                        Some(acc) => add(Recipient::Account(acc.clone()), mentioned),
+                        None => add(Recipient::Guest(c.name.clone()), mentioned),
+                    }
+                }
+            }
+            "room" | "channel" => {
+                if sender_role < Role::Moderator {
+                    send_notice(
+                        sender_tx,
+                        &format!("Only moderators can use @{}.", mentioned),
+                    );
+                    continue;
+                }
+                // Everyone who has joined the room, online or not
+                let joined: Vec<String> = {
+                    let markers = state.read_markers.read().await;
+                    markers
+                        .iter()
+                        .filter(|(_, rooms)| rooms.contains_key(room))
+                        .map(|(acc, _)| acc.clone())
+                        .collect()
+                };
+                for acc in joined {
+                    add(Recipient::Account(acc), mentioned);
+                }
+                for r in state.clients.iter() {
+                    let c = r.value();
+                    if !c.in_room(room) {
+                        continue;
+                    }
+                    match &c.account {
+                        Some(acc) => add(Recipient::Account(acc.clone()), mentioned),
+                        None => add(Recipient::Guest(c.name.clone()), mentioned),
+                    }
+                }
+            }
+            _ => {
+                let account = state
+                    .users
+                    .iter()
+                    .find(|r| r.key().eq_ignore_ascii_case(mentioned))
+                    .map(|r| r.key().clone());
+                if let Some(acc) = account {
+                    add(Recipient::Account(acc), mentioned);
+                } else if state.clients.iter().any(|r| {
+                    r.value().in_room(room) && r.value().name.eq_ignore_ascii_case(mentioned)
+                }) {
+                    add(Recipient::Guest(mentioned.clone()), mentioned);
+                }
+            }
+        }
+    }
+
+    for (recipient, mentioned) in recipients.into_values() {
+        let session_ids = match &recipient {
+            Recipient::Account(acc) => {
+                if acc.eq_ignore_ascii_case(&item.from) {
+                    continue;
+                }
+                store_mention(&state.mentions, acc, room, item).await;
+                session_ids_by_account(&state.clients, acc)
+            }
+            Recipient::Guest(name) => {
+                if name.eq_ignore_ascii_case(&item.from) {
+                    continue;
+                }
+                state
+                    .clients
+                    .iter()
+                    .filter(|r| {
+                        r.value().in_room(room) && r.value().name.eq_ignore_ascii_case(name)
+                    })
+                    .map(|r| r.key().clone())
+                    .collect()
+            }
+        };
+
+        let mention_msg = Outgoing::Mention {
+            msg_id: item.id.clone(),
+            room: room.to_string(),
+            from: item.from.clone(),
+            text: item.text.clone(),
+            mentioned,
+        };
+        let payload = Payload::new(mention_msg);
+        // Do-not-disturb mutes the live alert; the inbox still has it
+        for id in session_ids.into_iter().filter(|id| !is_dnd(state, id)) {
+            if let Some(tx) = client_tx_by_id(&state.clients, &id).await {
+                payload.send_to(&tx);
+            }
+        }
+    }
+}
+
+/// Append a mention to an account's inbox.
+async fn store_mention(mentions: &Mentions, account: &str, room: &str, item: &HistoryItem) {
+    let mut locked = mentions.write().await;
+    let q = locked
+        .entry(account.to_lowercase())
+        .or_insert_with(|| VecDeque::with_capacity(MAX_INBOX));
+    q.push_back(MentionItem {
+        msg_id: item.id.clone(),
+        room: room.to_string(),
+        from: item.from.clone(),
+        text: item.text.clone(),
+        ts: item.ts,
+        read: false,
+    });
+    while q.len() > MAX_INBOX {
+        q.pop_front();
+    }
+}
+
+/// Number of unread mentions in an account's inbox.
+pub async fn unread_mentions(mentions: &Mentions, account: &str) -> usize {
+    let locked = mentions.read().await;
+    locked
+        .get(&account.to_lowercase())
+        .map(|q| q.iter().filter(|m| !m.read).count())
+        .unwrap_or(0)
+}
+
+/// Send the client's mention inbox and mark it as read.
+pub async fn send_inbox(state: &AppState, client_id: &str) -> ActionResult {
+    let account = inbox_account(state, client_id)?;
+    let items: Vec<MentionItem> = {
+        let mut locked = state.mentions.write().await;
+        match locked.get_mut(&account.to_lowercase()) {
+            Some(q) => {
+                let items = q.iter().cloned().collect();
+                for m in q.iter_mut() {
+                    m.read = true;
+                }
+                items
+            }
+            None => Vec::new(),
+        }
+    };
+    let msg = Outgoing::Mentions { items };
+    if let Some(tx) = client_tx_by_id(&state.clients, client_id).await {
+        send(&tx, msg);
+    }
+    Ok(())
+}
+
+/// Empty the client's mention inbox.
+pub async fn clear_inbox(state: &AppState, client_id: &str) -> ActionResult {
+    let account = inbox_account(state, client_id)?;
+    state.mentions.write().await.remove(&account.to_lowercase());
+    send_system(state, client_id, "Mention inbox cleared.").await;
+    Ok(())
+}
+
+/// Account whose inbox a client may use; guests don't have one.
+fn inbox_account(state: &AppState, client_id: &str) -> Result<String, ActionError> {
+    state
+        .clients
+        .get(client_id)
+        .and_then(|r| r.value().account.clone())
+        .ok_or_else(|| {
+            ActionError::new(
+                ErrorCode::NotAuthenticated,
+                "Log in to keep a mention inbox.",
+            )
+        })
+}
+
+/// Tell a freshly logged-in client about mentions received while away.
+pub async fn notify_pending(state: &AppState, client_id: &str) {
+    if let Some(acc) = state
+        .clients
+        .get(client_id)
+        .and_then(|r| r.value().account.clone())
+    {
+        let count = unread_mentions(&state.mentions, &acc).await;
+        if count > 0 {
+            send_system(
+                state,
+                client_id,
+                &format!(
+                    "You have {} unread mention(s). Type /mentions to view.",
+                    count
+                ),
+            )
+            .await;
+        }
+    }
+}
+
+async fn send_system(state: &AppState, client_id: &str, text: &str) {
+    if let Some(tx) = client_tx_by_id(&state.clients, client_id).await {
+        let msg = Outgoing::System {
+            text: text.to_string(),
+        };
+        send(&tx, msg);
+    }
+}
+
+/// Send a system line to the sender of a message, if connected.
+fn send_notice(sender_tx: Option<&Tx>, text: &str) {
+    if let Some(tx) = sender_tx {
+        send(
+            tx,
+            Outgoing::System {
+                text: text.to_string(),
+            },
+        );
+    }
+}
+
+pub async fn save_mentions(mentions: &Mentions) {
+    let m = mentions.read().await;
+    match serde_json::to_string(&*m) {
+        Ok(json) => {
+            if let Err(e) = tokio::fs::write("mentions.json", json).await {
+                error!("Failed to save mentions.json: {}", e);
+            } else {
+                info!("Mentions saved to mentions.json");
+            }
+        }
+        Err(e) => error!("Failed to serialize mentions: {}", e),
+    }
+}
+
+pub async fn load_mentions(mentions: &Mentions) {
+    if let Ok(json) = tokio::fs::read_to_string("mentions.json").await {
+        match serde_json::from_str::<HashMap<String, VecDeque<MentionItem>>>(&json) {
+            Ok(loaded) => {
+                let mut m = mentions.write().await;
+                *m = loaded;
+                info!("Loaded mentions from mentions.json");
+            }
+            Err(e) => error!("Failed to parse mentions.json: {}", e),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Message pipeline. Every new room message passes through an ordered list
+//! of `MessageFilter`s: once before it is stored and broadcast, where a
+//! filter may rewrite it, annotate it or reject it, and once after, where a
+//! filter may react to it. Edits of stored messages pass through them too.
+//! Moderators switch filters on or off per room.
+
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::errors::ActionResult;
+use crate::roles::Role;
+use crate::room_settings::filter_enabled;
+use crate::types::{AppState, HistoryItem, Tx};
+use crate::word_filter::{WordFilter, WordList};
+
+/// The message being posted and where it comes from.
+pub struct MessageContext<'a> {
+    pub state: &'a AppState,
+    pub room: &'a str,
+    pub sender_role: Role,
+    /// The sender's session, when the message came over a connection
+    /// rather than the HTTP API or a webhook.
+    pub sender_tx: Option<&'a Tx>,
+    /// The sender's `rate_limit` key (account, or IP address for guests),
+    /// which spam scores and mutes are kept under. `None` for webhooks.
+    pub sender_key: Option<&'a str>,
+}
+
+/// One step of the message pipeline.
+pub trait MessageFilter: Send + Sync {
+    /// Name used by `/filter` and in `room_settings.json`.
+    fn name(&self) -> &str;
+
+    /// One-line description for `/filter`.
+    fn summary(&self) -> &str;
+
+    /// Whether the filter runs in rooms that have not switched it.
+    fn enabled_by_default(&self) -> bool {
+        true
+    }
+
+    /// Runs before the message is stored. May change `item` (its text, or
+    /// `flags` to annotate it); an error rejects the message and is
+    /// returned to the sender.
+    fn before<'a>(
+        &'a self,
+        _ctx: &'a MessageContext<'a>,
+        _item: &'a mut HistoryItem,
+    ) -> BoxFuture<'a, ActionResult> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Runs after the message was stored and broadcast.
+    fn after<'a>(
+        &'a self,
+        _ctx: &'a MessageContext<'a>,
+        _item: &'a HistoryItem,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// Runs before an edit is stored, on a copy of the message carrying
+    /// the new text. Like `before`, it may change the copy or reject the
+    /// edit.
+    fn before_edit<'a>(
+        &'a self,
+        _ctx: &'a MessageContext<'a>,
+        _item: &'a mut HistoryItem,
+    ) -> BoxFuture<'a, ActionResult> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// The filters in the order they run.
+pub struct MessagePipeline {
+    filters: Vec<Arc<dyn MessageFilter>>,
+}
+
+impl MessagePipeline {
+    /// A pipeline with no filters.
+    pub fn new() -> Self {
+        MessagePipeline {
+            filters: Vec::new(),
+        }
+    }
+
+    /// A pipeline holding the built-in filters: the spam filter, then the
+    /// word filter over `words`, then link previews.
+    pub fn with_builtins(words: Arc<WordList>) -> Self {
+        let mut pipeline = MessagePipeline::new();
+        pipeline.add(crate::spam::SpamFilter);
+        pipeline.add(WordFilter::new(words));
+        pipeline.add(crate::filters::LinkPreview);
+        pipeline
+    }
+
+    /// Append a filter; it runs after the ones already added.
+    pub fn add(&mut self, filter: impl MessageFilter + 'static) {
+        self.filters.push(Arc::new(filter));
+    }
+
+    /// All filters, in order.
+    pub fn filters(&self) -> impl Iterator<Item = &dyn MessageFilter> {
+        self.filters.iter().map(|f| f.as_ref())
+    }
+
+    /// The filter called `name` (case-insensitive).
+    pub fn get(&self, name: &str) -> Option<&dyn MessageFilter> {
+        self.filters().find(|f| f.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Whether `filter` is switched on in `room`.
+    pub fn enabled_in(&self, state: &AppState, room: &str, filter: &dyn MessageFilter) -> bool {
+        filter_enabled(
+            &state.room_settings,
+            room,
+            filter.name(),
+            filter.enabled_by_default(),
+        )
+    }
+
+    /// Run the `before` step of every filter enabled in the room, stopping
+    /// at the first rejection.
+    pub async fn before_store(
+        &self,
+        ctx: &MessageContext<'_>,
+        item: &mut HistoryItem,
+    ) -> ActionResult {
+        for filter in self.filters() {
+            if self.enabled_in(ctx.state, ctx.room, filter) {
+                if let Err(e) = filter.before(ctx, item).await {
+                    debug!(
+                        "filter {} rejected a message in '{}': {}",
+                        filter.name(),
+                        ctx.room,
+                        e
+                    );
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the `before_edit` step of every filter enabled in the room,
+    /// stopping at the first rejection.
+    pub async fn before_edit(
+        &self,
+        ctx: &MessageContext<'_>,
+        item: &mut HistoryItem,
+    ) -> ActionResult {
+        for filter in self.filters() {
+            if self.enabled_in(ctx.state, ctx.room, filter) {
+                filter.before_edit(ctx, item).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the `after` step of every filter enabled in the room.
+    pub async fn after_store(&self, ctx: &MessageContext<'_>, item: &HistoryItem) {
+        for filter in self.filters() {
+            if self.enabled_in(ctx.state, ctx.room, filter) {
+                filter.after(ctx, item).await;
+            }
+        }
+    }
+}
+
+impl Default for MessagePipeline {
+    fn default() -> Self {
+        MessagePipeline::new()
+    }
+}
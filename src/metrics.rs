@@ -6,6 +6,12 @@ pub struct ServerMetrics {
     pub start_time: Instant,
     pub total_messages: AtomicU64,
     pub total_connections: AtomicUsize,
+    /// Bytes of frames sent to `deflate` clients, before compression
+    pub deflate_raw_bytes: AtomicU64,
+    /// Bytes of those frames actually written to the socket
+    pub deflate_wire_bytes: AtomicU64,
+    /// Frames that went out compressed
+    pub compressed_frames: AtomicU64,
 }
 
 impl ServerMetrics {
@@ -14,6 +20,9 @@ impl ServerMetrics {
             start_time: Instant::now(),
             total_messages: AtomicU64::new(0),
             total_connections: AtomicUsize::new(0),
+            deflate_raw_bytes: AtomicU64::new(0),
+            deflate_wire_bytes: AtomicU64::new(0),
+            compressed_frames: AtomicU64::new(0),
         }
     }
 
@@ -37,6 +46,38 @@ impl ServerMetrics {
         self.total_connections.load(Ordering::Relaxed) as u64
     }
 
+    /// Record a frame sent to a client that negotiated compression.
+    pub fn record_deflate_frame(&self, raw_len: usize, wire_len: usize) {
+        self.deflate_raw_bytes
+            .fetch_add(raw_len as u64, Ordering::Relaxed);
+        self.deflate_wire_bytes
+            .fetch_add(wire_len as u64, Ordering::Relaxed);
+        if wire_len < raw_len {
+            self.compressed_frames.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get_deflate_raw_bytes(&self) -> u64 {
+        self.deflate_raw_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn get_deflate_wire_bytes(&self) -> u64 {
+        self.deflate_wire_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn get_compressed_frames(&self) -> u64 {
+        self.compressed_frames.load(Ordering::Relaxed)
+    }
+
+    /// Wire bytes per raw byte for compressing clients (1.0 = no savings).
+    pub fn compression_ratio(&self) -> f64 {
+        let raw = self.get_deflate_raw_bytes();
+        if raw == 0 {
+            return 1.0;
+        }
+        self.get_deflate_wire_bytes() as f64 / raw as f64
+    }
+
     /// Get memory usage in MB (approximation)
     pub fn memory_usage_mb(&self) -> usize {
         #[cfg(target_os = "linux")]
@@ -0,0 +1,269 @@
+//! Outgoing webhooks: POST a signed JSON event to configured URLs when
+//! something happens in a room (new message, join, leave, reaction).
+//!
+//! Each hook has its own bounded queue drained by a background task, so
+//! emitting an event never waits on the network. Events that find a hook's
+//! queue full are dropped with a warning.
+
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::helpers::now_ts;
+use crate::types::HistoryItem;
+
+/// Events waiting per hook before new ones are dropped.
+const QUEUE_CAPACITY: usize = 256;
+
+/// Delivery attempts per event, including the first.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry; doubled after each failed attempt.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Timeout for one delivery attempt.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Header carrying `sha256=<hex HMAC of the body>` when the hook has a secret.
+const SIGNATURE_HEADER: &str = "X-Chat-Signature";
+
+/// Kinds of room event a hook can subscribe to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Message,
+    Join,
+    Leave,
+    Reaction,
+}
+
+impl EventKind {
+    fn all() -> Vec<EventKind> {
+        vec![
+            EventKind::Message,
+            EventKind::Join,
+            EventKind::Leave,
+            EventKind::Reaction,
+        ]
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Message => "message",
+            EventKind::Join => "join",
+            EventKind::Leave => "leave",
+            EventKind::Reaction => "reaction",
+        }
+    }
+}
+
+/// One configured hook, as written in `outgoing_webhooks.json`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct OutgoingWebhook {
+    pub url: String,
+    /// Key for the signature header; unsigned when absent.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Rooms to report on; empty means every room.
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    /// Events to report; defaults to all of them.
+    #[serde(default = "EventKind::all")]
+    pub events: Vec<EventKind>,
+}
+
+impl OutgoingWebhook {
+    fn wants(&self, room: &str, kind: EventKind) -> bool {
+        self.events.contains(&kind)
+            && (self.rooms.is_empty() || self.rooms.iter().any(|r| r == room))
+    }
+}
+
+/// Something that happened in a room.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum RoomEvent {
+    Message {
+        room: String,
+        message: HistoryItem,
+    },
+    Join {
+        room: String,
+        user: String,
+    },
+    Leave {
+        room: String,
+        user: String,
+    },
+    Reaction {
+        room: String,
+        msg_id: String,
+        emoji: String,
+        user: String,
+        added: bool,
+    },
+}
+
+impl RoomEvent {
+    fn kind(&self) -> EventKind {
+        match self {
+            RoomEvent::Message { .. } => EventKind::Message,
+            RoomEvent::Join { .. } => EventKind::Join,
+            RoomEvent::Leave { .. } => EventKind::Leave,
+            RoomEvent::Reaction { .. } => EventKind::Reaction,
+        }
+    }
+
+    fn room(&self) -> &str {
+        match self {
+            RoomEvent::Message { room, .. }
+            | RoomEvent::Join { room, .. }
+            | RoomEvent::Leave { room, .. }
+            | RoomEvent::Reaction { room, .. } => room,
+        }
+    }
+}
+
+/// A serialized event ready to send.
+struct Delivery {
+    id: String,
+    kind: EventKind,
+    body: Bytes,
+}
+
+/// The configured hooks and their delivery queues.
+pub struct OutgoingWebhooks {
+    hooks: Vec<(OutgoingWebhook, mpsc::Sender<Arc<Delivery>>)>,
+}
+
+impl OutgoingWebhooks {
+    /// Start one delivery task per hook. Must be called inside the runtime.
+    pub fn start(hooks: Vec<OutgoingWebhook>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("valid reqwest client");
+        let hooks = hooks
+            .into_iter()
+            .map(|hook| {
+                let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+                tokio::spawn(deliver_loop(hook.clone(), client.clone(), rx));
+                (hook, tx)
+            })
+            .collect();
+        OutgoingWebhooks { hooks }
+    }
+
+    /// Queue `event` for every hook that subscribes to it. Never waits.
+    pub fn emit(&self, event: RoomEvent) {
+        let kind = event.kind();
+        let mut delivery = None;
+        for (hook, tx) in &self.hooks {
+            if !hook.wants(event.room(), kind) {
+                continue;
+            }
+            // Serialize once, on the first interested hook
+            let delivery = delivery.get_or_insert_with(|| {
+                let id = Uuid::new_v4().simple().to_string();
+                let mut body = serde_json::to_value(&event).unwrap_or_default();
+                body["id"] = json!(id);
+                body["ts"] = json!(now_ts());
+                Arc::new(Delivery {
+                    id,
+                    kind,
+                    body: Bytes::from(serde_json::to_vec(&body).unwrap_or_default()),
+                })
+            });
+            if tx.try_send(delivery.clone()).is_err() {
+                warn!(
+                    "Outgoing webhook queue for {} is full; dropping {} event",
+                    hook.url,
+                    kind.as_str()
+                );
+            }
+        }
+    }
+}
+
+/// Load hooks from `outgoing_webhooks.json` (synchronous, done at startup).
+pub fn load_outgoing_webhooks() -> anyhow::Result<Vec<OutgoingWebhook>> {
+    let path = "outgoing_webhooks.json";
+    if Path::new(path).exists() {
+        let s = fs::read_to_string(path)?;
+        let hooks: Vec<OutgoingWebhook> = serde_json::from_str(&s)?;
+        info!("Loaded {} outgoing webhooks from disk", hooks.len());
+        Ok(hooks)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// Hex HMAC-SHA256 of `body` under `secret`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Drain one hook's queue in order, retrying each event with backoff.
+async fn deliver_loop(
+    hook: OutgoingWebhook,
+    client: reqwest::Client,
+    mut rx: mpsc::Receiver<Arc<Delivery>>,
+) {
+    while let Some(delivery) = rx.recv().await {
+        let mut delay = FIRST_RETRY_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match deliver(&hook, &client, &delivery).await {
+                Ok(()) => break,
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    warn!(
+                        "Outgoing webhook {} failed (attempt {}/{}): {}; retrying in {:?}",
+                        hook.url, attempt, MAX_ATTEMPTS, e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => warn!(
+                    "Outgoing webhook {} failed, giving up on event {}: {}",
+                    hook.url, delivery.id, e
+                ),
+            }
+        }
+    }
+}
+
+/// One delivery attempt. Any non-2xx status counts as a failure.
+async fn deliver(
+    hook: &OutgoingWebhook,
+    client: &reqwest::Client,
+    delivery: &Delivery,
+) -> anyhow::Result<()> {
+    let mut req = client
+        .post(&hook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Chat-Event", delivery.kind.as_str())
+        .header("X-Chat-Delivery", &delivery.id)
+        .body(delivery.body.clone());
+    if let Some(secret) = &hook.secret {
+        req = req.header(
+            SIGNATURE_HEADER,
+            format!("sha256={}", sign(secret, &delivery.body)),
+        );
+    }
+    let resp = req.send().await?;
+    if !resp.status().is_success() {
+        anyhow::bail!("HTTP {}", resp.status());
+    }
+    Ok(())
+}
@@ -0,0 +1,262 @@
+//! Presence: automatic active/idle detection combined with user-set
+//! statuses (away, do-not-disturb, custom emoji/text).
+
+use crate::encoding::send;
+use crate::types::{
+    AppState, Clients, DeviceStatus, Outgoing, PresenceMode, Presences, Tx, UserStatus,
+};
+use std::collections::HashMap;
+use std::time::Instant;
+use tracing::{error, info};
+
+/// Sessions without activity for this long are reported as idle.
+const IDLE_AFTER_SECS: u64 = 300;
+
+/// Computed presence of one user, as broadcast in `Status` messages.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Presence {
+    pub status: String,
+    pub devices: Vec<DeviceStatus>,
+    pub emoji: Option<String>,
+    pub text: Option<String>,
+}
+
+/// Key of a guest's status in `Presences`.
+fn guest_key(name: &str) -> String {
+    format!("guest:{}", name.to_lowercase())
+}
+
+/// Key under which a client's user-set status is stored: the account for
+/// registered users (persisted), or the display name for guests.
+pub fn presence_key(clients: &Clients, client_id: &str) -> Option<String> {
+    clients.get(client_id).map(|r| {
+        let c = r.value();
+        match &c.account {
+            Some(acc) => acc.to_lowercase(),
+            None => guest_key(&c.name),
+        }
+    })
+}
+
+/// Compute the presence of every connected user, keyed by display name.
+pub fn snapshot_all(state: &AppState) -> HashMap<String, Presence> {
+    let now = Instant::now();
+    let mut keys: HashMap<String, String> = HashMap::new();
+    let mut current: HashMap<String, Presence> = HashMap::new();
+    for r in state.clients.iter() {
+        let client = r.value();
+        let device_status = if now.duration_since(client.last_active).as_secs() > IDLE_AFTER_SECS {
+            "idle"
+        } else {
+            "active"
+        };
+        let entry = current
+            .entry(client.name.clone())
+            .or_insert_with(|| Presence {
+                status: "idle".to_string(),
+                devices: Vec::new(),
+                emoji: None,
+                text: None,
+            });
+        // A user is active while any of their devices is active
+        if device_status == "active" {
+            entry.status = "active".to_string();
+        }
+        entry.devices.push(DeviceStatus {
+            device: client.device.clone(),
+            status: device_status.to_string(),
+        });
+        let key = match &client.account {
+            Some(acc) => acc.to_lowercase(),
+            None => format!("guest:{}", client.name.to_lowercase()),
+        };
+        keys.insert(client.name.clone(), key);
+    }
+
+    for (name, presence) in current.iter_mut() {
+        presence
+            .devices
+            .sort_by(|a, b| (&a.device, &a.status).cmp(&(&b.device, &b.status)));
+        if let Some(user_status) = keys.get(name).and_then(|k| state.presences.get(k)) {
+            let s = user_status.value();
+            match s.mode {
+                Some(PresenceMode::Away) => presence.status = "away".to_string(),
+                Some(PresenceMode::Dnd) => presence.status = "dnd".to_string(),
+                None => {}
+            }
+            presence.emoji = s.emoji.clone();
+            presence.text = s.text.clone();
+        }
+    }
+    current
+}
+
+/// Compute the presence of a single connected user.
+pub fn user_presence(state: &AppState, name: &str) -> Option<Presence> {
+    snapshot_all(state)
+        .into_iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, p)| p)
+}
+
+/// Recompute presence and broadcast changes since the last call.
+pub async fn refresh_presence(state: &AppState, last: &mut HashMap<String, Presence>) {
+    let current = snapshot_all(state);
+    let mut updates = Vec::new();
+    for (name, presence) in &current {
+        let changed = match last.get(name) {
+            Some(prev) => prev != presence,
+            // New users start out active; only report if that's not the case
+            None => {
+                presence.status != "active" || presence.emoji.is_some() || presence.text.is_some()
+            }
+        };
+        if changed {
+            updates.push((name.clone(), presence.clone()));
+        }
+    }
+    for (name, presence) in updates {
+        crate::room::broadcast_status(&state.clients, &name, presence).await;
+    }
+    *last = current;
+}
+
+/// Broadcast a user's presence right away (after a status command).
+pub async fn broadcast_user_presence(state: &AppState, name: &str) {
+    if let Some(presence) = user_presence(state, name) {
+        crate::room::broadcast_status(&state.clients, name, presence).await;
+    }
+}
+
+/// Send every user's non-default presence to a newly connected client.
+pub async fn send_presence_snapshot(state: &AppState, tx: &Tx) {
+    for (name, p) in snapshot_all(state) {
+        if p.status == "active" && p.emoji.is_none() && p.text.is_none() {
+            continue;
+        }
+        let msg = Outgoing::Status {
+            user: name,
+            status: p.status,
+            devices: p.devices,
+            emoji: p.emoji,
+            text: p.text,
+        };
+        send(tx, msg);
+    }
+}
+
+/// Whether the user behind `client_id` has do-not-disturb enabled.
+pub fn is_dnd(state: &AppState, client_id: &str) -> bool {
+    presence_key(&state.clients, client_id)
+        .and_then(|k| state.presences.get(&k).map(|s| s.value().mode))
+        .flatten()
+        == Some(PresenceMode::Dnd)
+}
+
+/// Update the user-set status of a client and broadcast the result.
+pub async fn set_user_status<F>(state: &AppState, client_id: &str, update: F)
+where
+    F: FnOnce(&mut UserStatus),
+{
+    let key = match presence_key(&state.clients, client_id) {
+        Some(k) => k,
+        None => return,
+    };
+    let persist = !key.starts_with("guest:");
+    {
+        let mut entry = state.presences.entry(key.clone()).or_default();
+        update(entry.value_mut());
+    }
+    // Drop entries that no longer carry anything
+    state
+        .presences
+        .remove_if(&key, |_, s| *s == UserStatus::default());
+    if persist {
+        save_presences(&state.presences).await;
+    }
+    let name = crate::helpers::client_name_by_id(&state.clients, client_id).await;
+    broadcast_user_presence(state, &name).await;
+}
+
+/// Forget a guest's status once their last session is gone.
+pub fn forget_guest(presences: &Presences, name: &str) {
+    presences.remove(&guest_key(name));
+}
+
+/// Move a guest's status from `old` to `new` after a rename, so it neither
+/// stays behind for the next guest to take `old` nor is lost. Anything left
+/// under `new` is dropped. Returns whether there was a status to move.
+pub fn rename_guest(presences: &Presences, old: &str, new: &str) -> bool {
+    let status = presences.remove(&guest_key(old)).map(|(_, s)| s);
+    presences.remove(&guest_key(new));
+    match status {
+        Some(status) => {
+            presences.insert(guest_key(new), status);
+            true
+        }
+        None => false,
+    }
+}
+
+pub async fn save_presences(presences: &Presences) {
+    let map: HashMap<String, UserStatus> = presences
+        .iter()
+        .filter(|r| !r.key().starts_with("guest:"))
+        .map(|r| (r.key().clone(), r.value().clone()))
+        .collect();
+    match serde_json::to_string(&map) {
+        Ok(json) => {
+            if let Err(e) = tokio::fs::write("statuses.json", json).await {
+                error!("Failed to save statuses.json: {}", e);
+            }
+        }
+        Err(e) => error!("Failed to serialize statuses: {}", e),
+    }
+}
+
+pub async fn load_presences(presences: &Presences) {
+    if let Ok(json) = tokio::fs::read_to_string("statuses.json").await {
+        match serde_json::from_str::<HashMap<String, UserStatus>>(&json) {
+            Ok(loaded) => {
+                for (k, v) in loaded {
+                    presences.insert(k, v);
+                }
+                info!("Loaded user statuses from statuses.json");
+            }
+            Err(e) => error!("Failed to parse statuses.json: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dashmap::DashMap;
+    use std::sync::Arc;
+
+    fn status(text: &str) -> UserStatus {
+        UserStatus {
+            text: Some(text.to_string()),
+            ..UserStatus::default()
+        }
+    }
+
+    #[test]
+    fn rename_moves_a_guest_status() {
+        let presences: Presences = Arc::new(DashMap::new());
+        presences.insert(guest_key("Bob"), status("lunch"));
+        presences.insert(guest_key("robert"), status("stale"));
+
+        assert!(rename_guest(&presences, "Bob", "Robert"));
+        assert!(!presences.contains_key(&guest_key("bob")));
+        assert_eq!(
+            presences.get(&guest_key("robert")).map(|s| s.clone()),
+            Some(status("lunch"))
+        );
+
+        // Nothing to move still clears a stale status under the new name
+        presences.insert(guest_key("carol"), status("stale"));
+        assert!(!rename_guest(&presences, "dave", "carol"));
+        assert!(!presences.contains_key(&guest_key("carol")));
+    }
+}
@@ -0,0 +1,178 @@
+//! Protocol versioning: the `hello` handshake and frame parsing.
+
+use crate::config::Config;
+use crate::encoding::decode_binary;
+use crate::types::{Incoming, MIN_PROTOCOL_VERSION, PROTOCOL_STRICT, PROTOCOL_VERSION};
+
+/// Features this server knows, reported in the `hello` reply unless
+/// switched off in the config.
+pub const SERVER_FEATURES: &[&str] = &[
+    "userlist", "unread", "mentions", "presence", "sessions", "msgpack", "deflate",
+];
+
+/// How a client session is carried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    WebSocket,
+    /// Server-Sent Events down, HTTP POST up.
+    EventStream,
+}
+
+impl Transport {
+    /// Whether binary frames (MessagePack, compressed) can reach the client.
+    pub fn binary(self) -> bool {
+        match self {
+            Transport::WebSocket => true,
+            Transport::EventStream => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Transport::WebSocket => "websocket",
+            Transport::EventStream => "sse",
+        })
+    }
+}
+
+/// Result of parsing one text frame from a client.
+pub enum Frame {
+    /// A well-formed protocol message.
+    Message(Incoming),
+    /// Plain text from a legacy (pre-v3) client, treated as a chat message.
+    Legacy(String),
+    /// A frame the server cannot accept; the reason is sent back.
+    Invalid(String),
+}
+
+/// Parse a text frame according to the client's protocol version.
+pub fn parse_frame(text: &str, protocol: u32) -> Frame {
+    match serde_json::from_str::<Incoming>(text) {
+        Ok(msg) => Frame::Message(msg),
+        Err(e) => {
+            let looks_like_json = text.trim_start().starts_with('{');
+            if looks_like_json || protocol >= PROTOCOL_STRICT {
+                Frame::Invalid(format!("invalid message: {}", e))
+            } else {
+                Frame::Legacy(text.to_string())
+            }
+        }
+    }
+}
+
+/// Parse a WebSocket frame: text frames as JSON (or legacy plain text),
+/// binary frames as MessagePack. Returns `None` for control frames.
+pub fn read_frame(msg: &warp::ws::Message, protocol: u32) -> Option<Frame> {
+    if msg.is_text() {
+        msg.to_str().ok().map(|text| parse_frame(text, protocol))
+    } else if msg.is_binary() {
+        Some(match decode_binary(msg.as_bytes()) {
+            Ok(incoming) => Frame::Message(incoming),
+            Err(reason) => Frame::Invalid(reason),
+        })
+    } else {
+        None
+    }
+}
+
+/// Agreed protocol parameters after a successful handshake.
+pub struct Negotiated {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+/// Negotiate a protocol version with a client that speaks
+/// `min_version..=version`, keeping the capabilities in `offered`.
+/// Returns the reason on failure.
+pub fn negotiate(
+    version: u32,
+    min_version: Option<u32>,
+    capabilities: Vec<String>,
+    offered: &[String],
+) -> Result<Negotiated, String> {
+    let client_min = min_version.unwrap_or(version).min(version);
+    let agreed = version.min(PROTOCOL_VERSION);
+    if agreed < MIN_PROTOCOL_VERSION || agreed < client_min {
+        return Err(format!(
+            "client speaks protocol {}..={}, server speaks {}..={}",
+            client_min, version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    // Only keep capabilities the server offers
+    let capabilities = capabilities
+        .into_iter()
+        .filter(|c| offered.contains(c))
+        .collect();
+    Ok(Negotiated {
+        version: agreed,
+        capabilities,
+    })
+}
+
+/// Features offered under `config` over `transport`, as owned strings for
+/// the `hello` reply. Binary-only features are left out for text transports.
+pub fn server_features(config: &Config, transport: Transport) -> Vec<String> {
+    SERVER_FEATURES
+        .iter()
+        .filter(|f| config.compression || **f != "deflate")
+        .filter(|f| transport.binary() || !matches!(**f, "msgpack" | "deflate"))
+        .map(|f| f.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caps(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn agrees_on_the_highest_common_version() {
+        let agreed = negotiate(
+            PROTOCOL_VERSION + 5,
+            Some(MIN_PROTOCOL_VERSION),
+            vec![],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(agreed.version, PROTOCOL_VERSION);
+        let agreed = negotiate(MIN_PROTOCOL_VERSION, None, vec![], &[]).unwrap();
+        assert_eq!(agreed.version, MIN_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn refuses_versions_without_overlap() {
+        assert!(negotiate(MIN_PROTOCOL_VERSION - 1, None, vec![], &[]).is_err());
+        let newer = PROTOCOL_VERSION + 1;
+        assert!(negotiate(newer + 1, Some(newer), vec![], &[]).is_err());
+    }
+
+    #[test]
+    fn keeps_only_offered_capabilities() {
+        let agreed = negotiate(
+            PROTOCOL_VERSION,
+            None,
+            caps(&["msgpack", "teleport"]),
+            &caps(&["msgpack", "deflate"]),
+        )
+        .unwrap();
+        assert_eq!(agreed.capabilities, ["msgpack"]);
+    }
+
+    #[test]
+    fn plain_text_is_legacy_only_before_strict_versions() {
+        assert!(matches!(
+            parse_frame("hello", PROTOCOL_STRICT - 1),
+            Frame::Legacy(_)
+        ));
+        assert!(matches!(
+            parse_frame("hello", PROTOCOL_STRICT),
+            Frame::Invalid(_)
+        ));
+        assert!(matches!(parse_frame("{oops", 1), Frame::Invalid(_)));
+    }
+}
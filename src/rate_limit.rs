@@ -1,35 +1,301 @@
-//! Rate limiting for message spam prevention.
+//! Token-bucket rate limiting, with one bucket per action type and per
+//! account (or IP address for guests).
 
-use crate::types::{Clients, Outgoing};
-use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use std::str::FromStr;
+use std::time::Instant;
 use tracing::warn;
 
-/// Check rate limit: max 5 messages in 10 seconds.
-/// Returns true if message is allowed, false if rate limited.
-pub async fn check_rate_limit(clients: &Clients, client_id: &str) -> bool {
-    if let Some(mut r) = clients.get_mut(client_id) {
-        let client = r.value_mut();
+use crate::errors::{ActionError, ActionResult, ErrorCode};
+use crate::types::{Client, Clients};
+
+/// Buckets are pruned once this many are tracked.
+const PRUNE_AT: usize = 10_000;
+
+/// What is being limited. Each action has its own bucket per key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    /// Chat messages from people
+    Message,
+    /// Chat messages from bots
+    BotMessage,
+    /// Slash commands and other requests
+    Command,
+    /// Edits and deletions
+    Edit,
+    Reaction,
+    Typing,
+    Nudge,
+    /// `/register` attempts, keyed by IP address
+    Register,
+    /// `POST /upload` requests, keyed by IP address
+    Upload,
+    /// `POST /api/tokens` requests, keyed by IP address
+    Token,
+}
+
+impl Action {
+    /// Plural noun for error messages.
+    fn noun(self) -> &'static str {
+        match self {
+            Action::Message | Action::BotMessage => "messages",
+            Action::Command => "commands",
+            Action::Edit => "edits",
+            Action::Reaction => "reactions",
+            Action::Typing => "typing events",
+            Action::Nudge => "nudges",
+            Action::Register => "registration attempts",
+            Action::Upload => "uploads",
+            Action::Token => "token requests",
+        }
+    }
+}
+
+/// A bucket of `burst` tokens that refills completely every `per_secs`
+/// seconds. Written `burst/per_secs`, e.g. `5/10`; a burst of 0 turns the
+/// limit off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateRule {
+    pub burst: u32,
+    pub per_secs: u32,
+}
+
+impl RateRule {
+    pub const fn new(burst: u32, per_secs: u32) -> Self {
+        RateRule { burst, per_secs }
+    }
+
+    /// Tokens regained per second.
+    fn refill(&self) -> f64 {
+        self.burst as f64 / self.per_secs.max(1) as f64
+    }
+}
+
+impl FromStr for RateRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (burst, per) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected <burst>/<seconds>, got '{}'", s))?;
+        let burst = burst.trim().parse().map_err(|_| "bad burst".to_string())?;
+        let per_secs: u32 = per.trim().parse().map_err(|_| "bad seconds".to_string())?;
+        if per_secs == 0 {
+            return Err("seconds must be positive".to_string());
+        }
+        Ok(RateRule::new(burst, per_secs))
+    }
+}
+
+/// The limit for each action, read from `RATE_*` variables by `Config`.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub message: RateRule,
+    pub bot_message: RateRule,
+    pub command: RateRule,
+    pub edit: RateRule,
+    pub reaction: RateRule,
+    pub typing: RateRule,
+    pub nudge: RateRule,
+    pub register: RateRule,
+    pub upload: RateRule,
+    pub token: RateRule,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            message: RateRule::new(5, 10),
+            bot_message: RateRule::new(30, 10),
+            command: RateRule::new(10, 10),
+            edit: RateRule::new(5, 10),
+            reaction: RateRule::new(10, 10),
+            typing: RateRule::new(20, 10),
+            nudge: RateRule::new(1, 30),
+            register: RateRule::new(3, 600),
+            upload: RateRule::new(10, 60),
+            token: RateRule::new(5, 60),
+        }
+    }
+}
+
+impl RateLimits {
+    fn rule(&self, action: Action) -> RateRule {
+        match action {
+            Action::Message => self.message,
+            Action::BotMessage => self.bot_message,
+            Action::Command => self.command,
+            Action::Edit => self.edit,
+            Action::Reaction => self.reaction,
+            Action::Typing => self.typing,
+            Action::Nudge => self.nudge,
+            Action::Register => self.register,
+            Action::Upload => self.upload,
+            Action::Token => self.token,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Add the tokens regained since the last update, up to the burst.
+    fn refill(&mut self, rule: RateRule, now: Instant) {
+        let secs = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + secs * rule.refill()).min(rule.burst as f64);
+        self.updated = now;
+    }
+}
+
+/// The limiter, held in `AppState::rate_limiter`.
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: DashMap<(Action, String), Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            limits,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Take a token from `key`'s bucket for `action`, or return a
+    /// `RateLimited` error saying how long to wait.
+    pub fn check(&self, action: Action, key: &str) -> ActionResult {
+        let rule = self.limits.rule(action);
+        if rule.burst == 0 {
+            return Ok(());
+        }
         let now = Instant::now();
-        let window = Duration::from_secs(10);
+        if self.buckets.len() >= PRUNE_AT {
+            self.prune(now);
+        }
+        let mut bucket = self
+            .buckets
+            .entry((action, key.to_string()))
+            .or_insert_with(|| Bucket {
+                tokens: rule.burst as f64,
+                updated: now,
+            });
+        bucket.refill(rule, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = ((1.0 - bucket.tokens) / rule.refill()).ceil().max(1.0);
+        warn!("{} is rate limited for {:?}", key, action);
+        Err(ActionError::new(
+            ErrorCode::RateLimited,
+            format!(
+                "Rate limited: too many {} (max {} per {} seconds). Try again in {} seconds.",
+                action.noun(),
+                rule.burst,
+                rule.per_secs,
+                wait
+            ),
+        ))
+    }
+
+    /// `check` for a connected client, keyed by its account or IP address.
+    /// Bots' messages use the bot message limit. A session missing from
+    /// `clients` is refused rather than let through unlimited.
+    pub fn check_client(&self, clients: &Clients, client_id: &str, action: Action) -> ActionResult {
+        let (key, bot) = clients
+            .get(client_id)
+            .map(|r| (client_key(r.value()), r.value().bot))
+            .ok_or_else(ActionError::unknown_session)?;
+        let action = match action {
+            Action::Message if bot => Action::BotMessage,
+            other => other,
+        };
+        self.check(action, &key)
+    }
 
-        // Remove old timestamps outside 10-second window
-        client
-            .last_message_times
-            .retain(|t| now.duration_since(*t) < window);
+    /// Drop buckets that have refilled completely.
+    fn prune(&self, now: Instant) {
+        self.buckets.retain(|(action, _), bucket| {
+            let rule = self.limits.rule(*action);
+            bucket.refill(rule, now);
+            bucket.tokens < rule.burst as f64
+        });
+    }
+}
+
+/// The bucket key for a client: its account, or its IP address for guests,
+/// so a guest cannot reset its limits by reconnecting under a new name.
+pub fn client_key(client: &Client) -> String {
+    match &client.account {
+        Some(account) => account_key(account),
+        None => ip_key(&client.ip),
+    }
+}
+
+/// The bucket key for an account.
+pub fn account_key(account: &str) -> String {
+    format!("user:{}", account.to_lowercase())
+}
 
-        if client.last_message_times.len() >= 5 {
-            // Rate limited - send warning to client
-            warn!("Client {} is rate limited", client.name);
-            let msg = Outgoing::System {
-                text: "Rate limited: slow down! Max 5 messages per 10 seconds.".to_string(),
-            };
-            if let Ok(s) = serde_json::to_string(&msg) {
-                let _ = client.tx.send(warp::ws::Message::text(s));
-            }
-            return false;
+/// The bucket key for an IP address.
+pub fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!("5/10".parse(), Ok(RateRule::new(5, 10)));
+        assert_eq!(" 0 / 30 ".parse(), Ok(RateRule::new(0, 30)));
+        assert!("5".parse::<RateRule>().is_err());
+        assert!("5/0".parse::<RateRule>().is_err());
+        assert!("-1/10".parse::<RateRule>().is_err());
+        assert!("five/10".parse::<RateRule>().is_err());
+    }
+
+    #[test]
+    fn bucket_refills_up_to_the_burst() {
+        let rule = RateRule::new(4, 10);
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            updated: start,
+        };
+        bucket.refill(rule, start + Duration::from_secs(5));
+        assert!((bucket.tokens - 2.0).abs() < 1e-9);
+        bucket.refill(rule, start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 4.0);
+    }
+
+    #[test]
+    fn limits_each_action_and_key_separately() {
+        let limits = RateLimits {
+            message: RateRule::new(2, 60),
+            typing: RateRule::new(0, 10),
+            ..RateLimits::default()
+        };
+        let limiter = RateLimiter::new(limits);
+        assert!(limiter.check(Action::Message, "user:alice").is_ok());
+        assert!(limiter.check(Action::Message, "user:alice").is_ok());
+        let err = limiter.check(Action::Message, "user:alice").unwrap_err();
+        assert_eq!(err.code, ErrorCode::RateLimited);
+        assert!(limiter.check(Action::Message, "user:bob").is_ok());
+        assert!(limiter.check(Action::Edit, "user:alice").is_ok());
+        for _ in 0..100 {
+            assert!(limiter.check(Action::Typing, "user:alice").is_ok());
         }
+    }
 
-        client.last_message_times.push(now);
+    #[test]
+    fn account_keys_ignore_case() {
+        assert_eq!(account_key("Alice"), account_key("alice"));
+        assert_eq!(ip_key("10.0.0.1"), "ip:10.0.0.1");
     }
-    true
 }
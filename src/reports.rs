@@ -0,0 +1,358 @@
+//! Message reports: users flag abusive messages, moderators review the open
+//! reports with `/reports` and resolve them. Persisted to `reports.json`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
+
+use crate::encoding::send;
+use crate::errors::{ActionError, ActionResult, ErrorCode};
+use crate::helpers::{client_name_by_id, client_tx_by_id, now_ts, session_ids_by_name};
+use crate::rate_limit::{account_key, client_key};
+use crate::roles::notify_moderators;
+use crate::room::{delete_message, generate_msg_id};
+use crate::spam::tell_user;
+use crate::types::{AppState, HistoryItem, Outgoing, Reports};
+
+/// Longest stored reason, in characters.
+const MAX_REASON: usize = 200;
+
+/// Messages shown before the reported one.
+const CONTEXT_BEFORE: usize = 2;
+
+/// Mute length when `/reports mute` is given no minutes.
+pub const DEFAULT_MUTE_MINUTES: u64 = 10;
+
+/// Longest mute `/reports mute` accepts (one day).
+const MAX_MUTE_MINUTES: u64 = 24 * 60;
+
+/// A user's report of a room message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Report {
+    pub id: String,
+    pub msg_id: String,
+    pub room: String,
+    /// Who wrote the reported message
+    pub author: String,
+    /// The author's `rate_limit` key (account, or IP address for guests)
+    /// when the report was filed; mutes apply to it. Not sent to clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author_key: Option<String>,
+    pub reporter: String,
+    pub reason: String,
+    pub ts: u64,
+    /// Set once a moderator has acted on the report
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<Resolution>,
+}
+
+/// How a report was closed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Resolution {
+    /// `dismiss`, `delete` or `mute`
+    pub action: String,
+    pub by: String,
+    pub ts: u64,
+}
+
+/// An open report with the reported message and the messages before it,
+/// as sent in `Outgoing::Reports`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReportView {
+    #[serde(flatten)]
+    pub report: Report,
+    /// The reported message, if it is still in history
+    pub message: Option<HistoryItem>,
+    /// Up to two messages posted just before it
+    pub context: Vec<HistoryItem>,
+}
+
+/// What a moderator does with a report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportAction {
+    Dismiss,
+    Delete,
+    /// Mute the author for this many minutes
+    Mute(u64),
+}
+
+impl ReportAction {
+    /// Parse `dismiss`, `delete` or `mute` (with optional minutes).
+    pub fn parse(action: &str, minutes: Option<u64>) -> Option<Self> {
+        match action.to_lowercase().as_str() {
+            "dismiss" => Some(ReportAction::Dismiss),
+            "delete" => Some(ReportAction::Delete),
+            "mute" => Some(ReportAction::Mute(
+                minutes
+                    .unwrap_or(DEFAULT_MUTE_MINUTES)
+                    .clamp(1, MAX_MUTE_MINUTES),
+            )),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ReportAction::Dismiss => "dismiss",
+            ReportAction::Delete => "delete",
+            ReportAction::Mute(_) => "mute",
+        }
+    }
+}
+
+/// Load reports from `reports.json` (synchronous, done at startup).
+pub fn load_reports() -> anyhow::Result<HashMap<String, Report>> {
+    let path = "reports.json";
+    if Path::new(path).exists() {
+        let s = fs::read_to_string(path)?;
+        let m: HashMap<String, Report> = serde_json::from_str(&s)?;
+        info!("Loaded {} reports from disk", m.len());
+        Ok(m)
+    } else {
+        Ok(HashMap::new())
+    }
+}
+
+/// Save reports to disk (async-friendly via spawn_blocking).
+pub async fn save_reports_async(reports: &Reports) -> anyhow::Result<()> {
+    let map: HashMap<String, Report> = reports
+        .iter()
+        .map(|r| (r.key().clone(), r.value().clone()))
+        .collect();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let s = serde_json::to_string_pretty(&map)?;
+        fs::write("reports.json", s)?;
+        Ok(())
+    })
+    .await??;
+    Ok(())
+}
+
+async fn save(state: &AppState) {
+    if let Err(e) = save_reports_async(&state.reports).await {
+        tracing::error!("failed to save reports: {}", e);
+    }
+}
+
+/// The `rate_limit` key of the user posting as `name`: a connected
+/// session's key, else the account of that name.
+fn author_key(state: &AppState, name: &str) -> Option<String> {
+    session_ids_by_name(&state.clients, name)
+        .first()
+        .and_then(|id| state.clients.get(id).map(|r| client_key(r.value())))
+        .or_else(|| state.users.contains_key(name).then(|| account_key(name)))
+}
+
+/// File a report from `client_id` against message `msg_id` in `room` and
+/// tell the moderators.
+pub async fn file_report(
+    state: &AppState,
+    client_id: &str,
+    room: &str,
+    msg_id: &str,
+    reason: &str,
+) -> ActionResult {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(ActionError::new(
+            ErrorCode::BadRequest,
+            "Please give a reason for the report.",
+        ));
+    }
+    let reason: String = reason.chars().take(MAX_REASON).collect();
+    let reporter = client_name_by_id(&state.clients, client_id).await;
+
+    let author = {
+        let locked = state.histories.read().await;
+        locked
+            .get(room)
+            .and_then(|q| q.iter().find(|i| i.id == msg_id && !i.deleted))
+            .map(|i| i.from.clone())
+            .ok_or_else(|| ActionError::new(ErrorCode::NotFound, "Message not found"))?
+    };
+    if author.eq_ignore_ascii_case(&reporter) {
+        return Err(ActionError::new(
+            ErrorCode::BadRequest,
+            "You cannot report your own message.",
+        ));
+    }
+    let duplicate = state.reports.iter().any(|r| {
+        r.resolution.is_none() && r.msg_id == msg_id && r.reporter.eq_ignore_ascii_case(&reporter)
+    });
+    if duplicate {
+        return Err(ActionError::new(
+            ErrorCode::Conflict,
+            "You have already reported this message.",
+        ));
+    }
+
+    let report = Report {
+        id: generate_msg_id(),
+        msg_id: msg_id.to_string(),
+        room: room.to_string(),
+        author_key: author_key(state, &author),
+        author,
+        reporter,
+        reason,
+        ts: now_ts(),
+        resolution: None,
+    };
+    info!(
+        "{} reported message {} by {} in '{}'",
+        report.reporter, report.msg_id, report.author, report.room
+    );
+    notify_moderators(
+        &state.clients,
+        &state.roles,
+        &format!(
+            "[report] {} reported a message by {} in '{}': {} (/reports to review)",
+            report.reporter, report.author, report.room, report.reason
+        ),
+    );
+    state.reports.insert(report.id.clone(), report);
+    save(state).await;
+
+    if let Some(tx) = client_tx_by_id(&state.clients, client_id).await {
+        send(
+            &tx,
+            Outgoing::System {
+                text: "Thanks, the moderators have been notified.".to_string(),
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Open reports, oldest first, with their message context.
+pub async fn open_reports(state: &AppState) -> Vec<ReportView> {
+    let mut open: Vec<Report> = state
+        .reports
+        .iter()
+        .filter(|r| r.resolution.is_none())
+        .map(|r| r.value().clone())
+        .collect();
+    open.sort_by_key(|r| r.ts);
+
+    let locked = state.histories.read().await;
+    open.into_iter()
+        .map(|mut report| {
+            // The author's key stays on the server
+            report.author_key = None;
+            let queue = locked.get(&report.room);
+            let pos = queue.and_then(|q| q.iter().position(|i| i.id == report.msg_id));
+            let (message, context) = match (queue, pos) {
+                (Some(q), Some(pos)) => (
+                    q.get(pos).cloned(),
+                    q.range(pos.saturating_sub(CONTEXT_BEFORE)..pos)
+                        .cloned()
+                        .collect(),
+                ),
+                _ => (None, Vec::new()),
+            };
+            ReportView {
+                report,
+                message,
+                context,
+            }
+        })
+        .collect()
+}
+
+/// Resolve report `id` with `action`, taken by moderator `by`. Every other
+/// open report of the same message is closed with it.
+pub async fn resolve_report(
+    state: &AppState,
+    id: &str,
+    action: ReportAction,
+    by: &str,
+) -> ActionResult {
+    let report = state
+        .reports
+        .get(id)
+        .map(|r| r.value().clone())
+        .filter(|r| r.resolution.is_none())
+        .ok_or_else(|| ActionError::new(ErrorCode::NotFound, format!("No open report '{}'", id)))?;
+
+    let done = match action {
+        ReportAction::Dismiss => format!("dismissed the report on a message by {}", report.author),
+        ReportAction::Delete => {
+            delete_message(
+                &state.clients,
+                &state.histories,
+                &report.room,
+                &report.msg_id,
+                None,
+            )
+            .await?;
+            format!("deleted the message by {}", report.author)
+        }
+        ReportAction::Mute(minutes) => {
+            let key = report.author_key.as_deref().ok_or_else(|| {
+                ActionError::new(
+                    ErrorCode::NotFound,
+                    format!(
+                        "{} was neither connected nor registered when reported",
+                        report.author
+                    ),
+                )
+            })?;
+            state.spam.mute(key, Duration::from_secs(minutes * 60));
+            tell_user(
+                state,
+                key,
+                &format!(
+                    "You have been muted for {} minutes by a moderator.",
+                    minutes
+                ),
+            );
+            format!("muted {} for {} minutes", report.author, minutes)
+        }
+    };
+
+    let ts = now_ts();
+    let mut closed = 0;
+    for mut r in state.reports.iter_mut() {
+        if r.resolution.is_none() && r.msg_id == report.msg_id && r.room == report.room {
+            r.resolution = Some(Resolution {
+                action: action.name().to_string(),
+                by: by.to_string(),
+                ts,
+            });
+            closed += 1;
+        }
+    }
+    save(state).await;
+
+    let detail = match action {
+        ReportAction::Mute(minutes) => format!(
+            "{} minutes, report {}: {}",
+            minutes, report.id, report.reason
+        ),
+        _ => format!("report {}: {}", report.id, report.reason),
+    };
+    let audit_action = match action {
+        ReportAction::Dismiss => "dismiss_report",
+        ReportAction::Delete => "delete",
+        ReportAction::Mute(_) => "mute",
+    };
+    state.audit.record(
+        by,
+        audit_action,
+        &report.author,
+        Some(&report.room),
+        Some(&detail),
+    );
+    info!("{} resolved report {} ({})", by, id, done);
+    notify_moderators(
+        &state.clients,
+        &state.roles,
+        &format!(
+            "[report] {} {} in '{}' ({} report(s) closed)",
+            by, done, report.room, closed
+        ),
+    );
+    Ok(())
+}
@@ -0,0 +1,107 @@
+//! User roles: loading, saving, and permission lookups.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use tracing::info;
+
+use crate::encoding::Payload;
+use crate::types::{Clients, Outgoing, Roles};
+
+/// Permission level of a user. Ordered from least to most privileged.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Guest,
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(s: &str) -> Option<Role> {
+        match s.to_ascii_lowercase().as_str() {
+            "guest" => Some(Role::Guest),
+            "user" => Some(Role::User),
+            "moderator" | "mod" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Role::Guest => "guest",
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Load roles from `roles.json` (synchronous, done at startup).
+/// The file maps lowercase usernames to roles and may be edited by hand
+/// to bootstrap the first admin.
+pub fn load_roles() -> anyhow::Result<HashMap<String, Role>> {
+    let path = "roles.json";
+    if Path::new(path).exists() {
+        let s = fs::read_to_string(path)?;
+        let m: HashMap<String, Role> = serde_json::from_str(&s)?;
+        info!("Loaded {} roles from disk", m.len());
+        Ok(m)
+    } else {
+        Ok(HashMap::new())
+    }
+}
+
+/// Save roles map to disk (async-friendly via spawn_blocking).
+pub async fn save_roles_async(roles: &Roles) -> anyhow::Result<()> {
+    let map: HashMap<String, Role> = roles
+        .iter()
+        .map(|r| (r.key().clone(), *r.value()))
+        .collect();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let s = serde_json::to_string_pretty(&map)?;
+        fs::write("roles.json", s)?;
+        Ok(())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Role of an account; registered users default to `User`.
+pub fn account_role(roles: &Roles, account: Option<&str>) -> Role {
+    match account {
+        Some(acc) => roles
+            .get(&acc.to_lowercase())
+            .map(|r| *r.value())
+            .unwrap_or(Role::User),
+        None => Role::Guest,
+    }
+}
+
+/// Role of a connected client.
+pub fn client_role(clients: &Clients, roles: &Roles, client_id: &str) -> Role {
+    let account = clients
+        .get(client_id)
+        .and_then(|r| r.value().account.clone());
+    account_role(roles, account.as_deref())
+}
+
+/// Send a system message to every connected moderator and admin.
+pub fn notify_moderators(clients: &Clients, roles: &Roles, text: &str) {
+    let payload = Payload::new(Outgoing::System {
+        text: text.to_string(),
+    });
+    for r in clients.iter() {
+        let c = r.value();
+        if account_role(roles, c.account.as_deref()) >= Role::Moderator {
+            payload.send_to(&c.tx);
+        }
+    }
+}
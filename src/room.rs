@@ -1,9 +1,20 @@
 //! Room management: broadcasting, history, and room switching.
 
-use crate::helpers::{client_name_by_id, client_tx_by_id, now_ts};
-use crate::types::{Clients, Histories, HistoryItem, Outgoing, PrivateHistories, Tx};
+use crate::encoding::{send, Payload};
+use crate::errors::{ActionError, ActionResult, ErrorCode};
+use crate::helpers::{client_name_by_id, client_tx_by_id, now_ts, other_session_in_room};
+use crate::mentions::process_mentions;
+use crate::message_filter::MessageContext;
+use crate::outgoing_webhooks::{OutgoingWebhooks, RoomEvent};
+use crate::presence::{snapshot_all, Presence};
+use crate::roles::{account_role, Role};
+use crate::spam::check_room_change;
+use crate::types::{
+    AppState, Clients, Histories, HistoryItem, Outgoing, PrivateHistories, RoomInfo, Tx, UserEntry,
+    PROTOCOL_USER_LIST,
+};
+use crate::unread::notify_room_activity;
 use std::collections::{HashMap, VecDeque};
-use std::sync::OnceLock;
 use tracing::{error, info};
 use uuid::Uuid;
 
@@ -17,15 +28,7 @@ pub async fn send_system_to_room(clients: &Clients, histories: &Histories, room:
     let msg = Outgoing::System {
         text: text.to_string(),
     };
-    let item = HistoryItem {
-        id: generate_msg_id(),
-        from: "system".to_string(),
-        text: text.to_string(),
-        ts: now_ts(),
-        reactions: HashMap::new(),
-        edited: false,
-        deleted: false,
-    };
+    let item = HistoryItem::new("system", text);
     {
         let mut locked_h = histories.write().await;
         let q = locked_h
@@ -36,11 +39,11 @@ pub async fn send_system_to_room(clients: &Clients, histories: &Histories, room:
             q.pop_front();
         }
     }
-    let s = serde_json::to_string(&msg).unwrap_or_default();
+    let payload = Payload::new(msg);
     for r in clients.iter() {
         let c = r.value();
-        if c.room == room {
-            let _ = c.tx.send(warp::ws::Message::text(s.clone()));
+        if c.in_room(room) {
+            payload.send_to(&c.tx);
         }
     }
 }
@@ -55,26 +58,64 @@ pub async fn send_history_to_client_room(tx: &Tx, histories: &Histories, room: &
             .unwrap_or_default()
     };
     let msg = Outgoing::History { items };
-    if let Ok(text) = serde_json::to_string(&msg) {
-        let _ = tx.send(warp::ws::Message::text(text));
-    }
+    send(tx, msg);
 }
 
-/// Send user list to all users in a room.
-pub async fn send_user_list_to_room(clients: &Clients, room: &str) {
-    let names: Vec<String> = clients
-        .iter()
-        .filter(|r| r.value().room == room)
-        .map(|r| r.value().name.clone())
-        .collect();
-    let user_count = names.len();
-
-    let msg = Outgoing::List { users: names };
-    if let Ok(s) = serde_json::to_string(&msg) {
-        for r in clients.iter() {
-            let c = r.value();
-            if c.room == room {
-                let _ = c.tx.send(warp::ws::Message::text(s.clone()));
+/// Send user list to all users in a room (one entry per user, not per session).
+/// Clients on protocol v2+ get structured `UserList` entries; older clients
+/// get the plain `List` of names.
+pub async fn send_user_list_to_room(state: &AppState, room: &str) {
+    let clients = &state.clients;
+    let mut entries: Vec<UserEntry> = Vec::new();
+    for r in clients.iter().filter(|r| r.value().in_room(room)) {
+        let c = r.value();
+        if let Some(e) = entries
+            .iter_mut()
+            .find(|e| e.name.eq_ignore_ascii_case(&c.name))
+        {
+            e.joined_at = e.joined_at.min(c.joined_at);
+            continue;
+        }
+        entries.push(UserEntry {
+            name: c.name.clone(),
+            account: c.account.clone(),
+            logged_in: c.logged_in,
+            role: account_role(&state.roles, c.account.as_deref()),
+            status: "active".to_string(),
+            emoji: None,
+            text: None,
+            devices: Vec::new(),
+            joined_at: c.joined_at,
+            bot: c.bot,
+        });
+    }
+    let presences = snapshot_all(state);
+    for e in entries.iter_mut() {
+        if let Some(p) = presences.get(&e.name) {
+            e.status = p.status.clone();
+            e.emoji = p.emoji.clone();
+            e.text = p.text.clone();
+            e.devices = p.devices.clone();
+        }
+    }
+    let user_count = entries.len();
+
+    let legacy = Outgoing::List {
+        users: entries.iter().map(|e| e.name.clone()).collect(),
+    };
+    let structured = Outgoing::UserList {
+        room: room.to_string(),
+        users: entries,
+    };
+    let v1 = Payload::new(legacy);
+    let v2 = Payload::new(structured);
+    for r in clients.iter() {
+        let c = r.value();
+        if c.in_room(room) {
+            if c.protocol >= PROTOCOL_USER_LIST {
+                v2.send_to(&c.tx);
+            } else {
+                v1.send_to(&c.tx);
             }
         }
     }
@@ -84,19 +125,17 @@ pub async fn send_user_list_to_room(clients: &Clients, room: &str) {
     );
 }
 
-/// Broadcast a message to all clients in a room and store in history.
-/// Returns the message ID.
+/// Broadcast a message to all clients in a room, store it in history and
+/// queue it for outgoing webhooks. Returns the message ID.
 pub async fn broadcast_to_room_and_store(
     clients: &Clients,
     histories: &Histories,
+    hooks: &OutgoingWebhooks,
     room: &str,
     item: HistoryItem,
 ) -> String {
     let msg_id = item.id.clone();
 
-    // Check for @mentions
-    let mentions = extract_mentions(&item.text);
-
     {
         let mut locked_h = histories.write().await;
         let q = locked_h
@@ -114,60 +153,104 @@ pub async fn broadcast_to_room_and_store(
         ts: item.ts,
         reactions: item.reactions.clone(),
         edited: item.edited,
+        bot: item.bot,
+        icon: item.icon.clone(),
+        room: Some(room.to_string()),
     };
-    if let Ok(s) = serde_json::to_string(&outgoing) {
-        for r in clients.iter() {
-            let c = r.value();
-            if c.room == room {
-                let _ = c.tx.send(warp::ws::Message::text(s.clone()));
-            }
-        }
-
-        // Send mention notifications with a single pass over room clients.
-        let mention_lookup: HashMap<String, String> = mentions
-            .into_iter()
-            .map(|m| (m.to_lowercase(), m))
-            .collect();
-        for r in clients.iter() {
-            let c = r.value();
-            if c.room != room {
-                continue;
-            }
-            let name_lower = c.name.to_lowercase();
-            if let Some(mentioned) = mention_lookup.get(&name_lower) {
-                let mention_msg = Outgoing::Mention {
-                    from: item.from.clone(),
-                    text: item.text.clone(),
-                    mentioned: mentioned.clone(),
-                };
-                if let Ok(m) = serde_json::to_string(&mention_msg) {
-                    let _ = c.tx.send(warp::ws::Message::text(m));
-                }
-            }
+    let payload = Payload::new(outgoing);
+    for r in clients.iter() {
+        let c = r.value();
+        if c.in_room(room) {
+            payload.send_to(&c.tx);
         }
     }
+    hooks.emit(RoomEvent::Message {
+        room: room.to_string(),
+        message: item,
+    });
 
     msg_id
 }
 
-/// Extract @mentions from text.
-fn extract_mentions(text: &str) -> Vec<String> {
-    static MENTION_RE: OnceLock<regex::Regex> = OnceLock::new();
-    let re = MENTION_RE.get_or_init(|| regex::Regex::new(r"@(\w+)").expect("valid mention regex"));
-    re.captures_iter(text)
-        .filter_map(|cap| cap.get(1).map(|m| m.as_str().to_string()))
+/// Post a new chat message to `room`: run it through the message pipeline,
+/// store and broadcast it, route its mentions and update unread counts.
+/// Notices for the sender (refused mentions) go to `sender_tx` when the
+/// sender is connected. `sender_key` is the sender's `rate_limit` key, used
+/// for spam mutes. Returns the stored item, or the error of the filter that
+/// rejected it.
+pub async fn post_to_room(
+    state: &AppState,
+    room: &str,
+    mut item: HistoryItem,
+    sender_role: Role,
+    sender_tx: Option<&Tx>,
+    sender_key: Option<&str>,
+) -> Result<HistoryItem, ActionError> {
+    let ctx = MessageContext {
+        state,
+        room,
+        sender_role,
+        sender_tx,
+        sender_key,
+    };
+    if let Some(key) = sender_key {
+        state.spam.check_muted(key)?;
+    }
+    state.filters.before_store(&ctx, &mut item).await?;
+    broadcast_to_room_and_store(
+        &state.clients,
+        &state.histories,
+        &state.outgoing_webhooks,
+        room,
+        item.clone(),
+    )
+    .await;
+    process_mentions(state, ctx.sender_role, ctx.sender_tx, room, &item).await;
+    notify_room_activity(state, room).await;
+
+    // Increment message counter
+    state.metrics.increment_messages();
+
+    state.filters.after_store(&ctx, &item).await;
+    Ok(item)
+}
+
+/// Every known room with the number of sessions currently in it.
+pub async fn room_list(clients: &Clients, histories: &Histories) -> Vec<RoomInfo> {
+    let locked_h = histories.read().await;
+    locked_h
+        .keys()
+        .map(|room_name| {
+            let member_count = clients
+                .iter()
+                .filter(|r| r.value().in_room(room_name))
+                .count();
+            RoomInfo {
+                name: room_name.clone(),
+                members: member_count,
+            }
+        })
         .collect()
 }
 
+/// Key of a DM conversation in `PrivateHistories`: both names lowercased,
+/// sorted and joined, e.g. "alfred,batman".
+pub fn dm_key(a: &str, b: &str) -> String {
+    let mut participants = [a.to_lowercase(), b.to_lowercase()];
+    participants.sort_unstable();
+    participants.join(",")
+}
+
 /// Add or toggle a reaction on a message.
 pub async fn add_reaction(
     clients: &Clients,
     histories: &Histories,
+    hooks: &OutgoingWebhooks,
     room: &str,
     msg_id: &str,
     emoji: &str,
     user: &str,
-) {
+) -> ActionResult {
     let added = {
         let mut locked_h = histories.write().await;
         if let Some(q) = locked_h.get_mut(room) {
@@ -184,10 +267,10 @@ pub async fn add_reaction(
                     true
                 }
             } else {
-                return;
+                return Err(message_not_found());
             }
         } else {
-            return;
+            return Err(message_not_found());
         }
     };
 
@@ -198,95 +281,134 @@ pub async fn add_reaction(
         user: user.to_string(),
         added,
     };
-    if let Ok(s) = serde_json::to_string(&msg) {
-        for r in clients.iter() {
-            let c = r.value();
-            if c.room == room {
-                let _ = c.tx.send(warp::ws::Message::text(s.clone()));
-            }
+    let payload = Payload::new(msg);
+    for r in clients.iter() {
+        let c = r.value();
+        if c.in_room(room) {
+            payload.send_to(&c.tx);
         }
     }
+    hooks.emit(RoomEvent::Reaction {
+        room: room.to_string(),
+        msg_id: msg_id.to_string(),
+        emoji: emoji.to_string(),
+        user: user.to_string(),
+        added,
+    });
+    Ok(())
 }
 
-/// Edit a message.
+/// Edit a message. Only its author may edit it; the new text passes
+/// through the room's message filters, which may change or refuse it.
 pub async fn edit_message(
-    clients: &Clients,
-    histories: &Histories,
-    room: &str,
+    ctx: &MessageContext<'_>,
     msg_id: &str,
     new_text: &str,
     requester: &str,
-) -> bool {
-    let edited = {
+) -> ActionResult {
+    let histories = &ctx.state.histories;
+    let original = {
+        let locked_h = histories.read().await;
+        let item = locked_h
+            .get(ctx.room)
+            .and_then(|q| q.iter().find(|i| i.id == msg_id))
+            .ok_or_else(message_not_found)?;
+        check_editable(item, requester)?;
+        item.clone()
+    };
+    let mut edited = original.clone();
+    edited.text = new_text.to_string();
+    ctx.state.filters.before_edit(ctx, &mut edited).await?;
+    {
+        // The lock was released for the filters; the message may have been
+        // deleted or edited by another request since
         let mut locked_h = histories.write().await;
-        if let Some(q) = locked_h.get_mut(room) {
-            if let Some(item) = q.iter_mut().find(|i| i.id == msg_id && i.from == requester) {
-                item.text = new_text.to_string();
-                item.edited = true;
-                true
-            } else {
-                false
-            }
-        } else {
-            false
+        let item = locked_h
+            .get_mut(ctx.room)
+            .and_then(|q| q.iter_mut().find(|i| i.id == msg_id))
+            .ok_or_else(message_not_found)?;
+        check_editable(item, requester)?;
+        if item.text != original.text {
+            return Err(ActionError::new(
+                ErrorCode::Conflict,
+                "The message changed while you were editing it",
+            ));
         }
-    };
+        item.text = edited.text.clone();
+        item.flags = edited.flags;
+        item.edited = true;
+    }
 
-    if edited {
-        let msg = Outgoing::Edit {
-            msg_id: msg_id.to_string(),
-            new_text: new_text.to_string(),
-        };
-        if let Ok(s) = serde_json::to_string(&msg) {
-            for r in clients.iter() {
-                let c = r.value();
-                if c.room == room {
-                    let _ = c.tx.send(warp::ws::Message::text(s.clone()));
-                }
-            }
+    let msg = Outgoing::Edit {
+        msg_id: msg_id.to_string(),
+        new_text: edited.text,
+    };
+    let payload = Payload::new(msg);
+    for r in ctx.state.clients.iter() {
+        let c = r.value();
+        if c.in_room(ctx.room) {
+            payload.send_to(&c.tx);
         }
     }
 
-    edited
+    Ok(())
+}
+
+/// Whether `requester` may edit `item`: it must be theirs and not deleted.
+fn check_editable(item: &HistoryItem, requester: &str) -> ActionResult {
+    if item.deleted {
+        return Err(message_not_found());
+    }
+    if item.from != requester {
+        return Err(ActionError::new(
+            ErrorCode::Forbidden,
+            "Cannot edit this message",
+        ));
+    }
+    Ok(())
 }
 
-/// Delete a message.
+/// Delete a message. With a `requester`, only their own message may be
+/// deleted; `None` skips the check (moderator removal).
 pub async fn delete_message(
     clients: &Clients,
     histories: &Histories,
     room: &str,
     msg_id: &str,
-    requester: &str,
-) -> bool {
-    let deleted = {
+    requester: Option<&str>,
+) -> ActionResult {
+    {
         let mut locked_h = histories.write().await;
-        if let Some(q) = locked_h.get_mut(room) {
-            if let Some(item) = q.iter_mut().find(|i| i.id == msg_id && i.from == requester) {
-                item.deleted = true;
-                true
-            } else {
-                false
-            }
-        } else {
-            false
+        let item = locked_h
+            .get_mut(room)
+            .and_then(|q| q.iter_mut().find(|i| i.id == msg_id))
+            .ok_or_else(message_not_found)?;
+        if requester.is_some_and(|r| item.from != r) {
+            return Err(ActionError::new(
+                ErrorCode::Forbidden,
+                "Cannot delete this message",
+            ));
         }
-    };
+        item.deleted = true;
+    }
 
-    if deleted {
-        let msg = Outgoing::Delete {
-            msg_id: msg_id.to_string(),
-        };
-        if let Ok(s) = serde_json::to_string(&msg) {
-            for r in clients.iter() {
-                let c = r.value();
-                if c.room == room {
-                    let _ = c.tx.send(warp::ws::Message::text(s.clone()));
-                }
-            }
+    let msg = Outgoing::Delete {
+        msg_id: msg_id.to_string(),
+    };
+    let payload = Payload::new(msg);
+    for r in clients.iter() {
+        let c = r.value();
+        if c.in_room(room) {
+            payload.send_to(&c.tx);
         }
     }
 
-    deleted
+    Ok(())
+}
+
+/// Error for an action on a message that isn't in the room's history.
+fn message_not_found() -> ActionError {
+    ActionError::new(ErrorCode::NotFound, "Message not found")
 }
 
 /// Broadcast read receipt.
@@ -295,18 +417,19 @@ pub async fn broadcast_read_receipt(clients: &Clients, room: &str, user: &str, l
         user: user.to_string(),
         last_msg_id: last_msg_id.to_string(),
     };
-    if let Ok(s) = serde_json::to_string(&msg) {
-        for r in clients.iter() {
-            let c = r.value();
-            if c.room == room {
-                let _ = c.tx.send(warp::ws::Message::text(s.clone()));
-            }
+    let payload = Payload::new(msg);
+    for r in clients.iter() {
+        let c = r.value();
+        if c.in_room(room) {
+            payload.send_to(&c.tx);
         }
     }
 }
 
 /// Move a client to a new room with announcements and history.
-pub async fn join_room(client_id: &str, room: &str, clients: &Clients, histories: &Histories) {
+pub async fn join_room(client_id: &str, room: &str, state: &AppState) {
+    let clients = &state.clients;
+    let histories = &state.histories;
     let target = room.trim();
     if target.is_empty() {
         return;
@@ -320,6 +443,8 @@ pub async fn join_room(client_id: &str, room: &str, clients: &Clients, histories
                 return;
             }
             c.room = target.to_string();
+            c.subscriptions.retain(|r| r != target);
+            c.joined_at = now_ts();
             old
         } else {
             return;
@@ -334,52 +459,65 @@ pub async fn join_room(client_id: &str, room: &str, clients: &Clients, histories
             .or_insert_with(|| VecDeque::with_capacity(200));
     }
 
-    // Announce leave in old room
+    // Announce leave in old room (only when no other session of the user remains)
     let name = client_name_by_id(clients, client_id).await;
-    send_system_to_room(
-        clients,
-        histories,
-        &old_room,
-        &format!("-- {} left the room --", name),
-    )
-    .await;
-    send_user_list_to_room(clients, &old_room).await;
-
-    // Announce join in new room
-    send_system_to_room(
-        clients,
-        histories,
-        target,
-        &format!("-- {} joined the room --", name),
-    )
-    .await;
+    if !other_session_in_room(clients, client_id, &name, &old_room) {
+        send_system_to_room(
+            clients,
+            histories,
+            &old_room,
+            &format!("-- {} left the room --", name),
+        )
+        .await;
+        state.outgoing_webhooks.emit(RoomEvent::Leave {
+            room: old_room.clone(),
+            user: name.clone(),
+        });
+    }
+    send_user_list_to_room(state, &old_room).await;
+
+    // Announce join in new room (only for the user's first session there)
+    if !other_session_in_room(clients, client_id, &name, target) {
+        send_system_to_room(
+            clients,
+            histories,
+            target,
+            &format!("-- {} joined the room --", name),
+        )
+        .await;
+        state.outgoing_webhooks.emit(RoomEvent::Join {
+            room: target.to_string(),
+            user: name.clone(),
+        });
+    }
     if let Some(tx) = client_tx_by_id(clients, client_id).await {
         send_history_to_client_room(&tx, histories, target).await;
     }
-    send_user_list_to_room(clients, target).await;
+    send_user_list_to_room(state, target).await;
 
     // Notify the mover
     if let Some(tx) = client_tx_by_id(clients, client_id).await {
         let msg = Outgoing::System {
             text: format!("You joined room '{}'", target),
         };
-        if let Ok(payload) = serde_json::to_string(&msg) {
-            let _ = tx.send(warp::ws::Message::text(payload));
-        }
+        send(&tx, msg);
     }
     info!("Client {} joined room '{}'", name, target);
+    check_room_change(state, client_id, target).await;
 }
 
-/// Broadcast a status update (active/idle) to ALL connected clients.
-pub async fn broadcast_status(clients: &Clients, user: &str, status: &str) {
+/// Broadcast a status update (active/idle/away/dnd) to ALL connected clients.
+pub async fn broadcast_status(clients: &Clients, user: &str, presence: Presence) {
     let msg = Outgoing::Status {
         user: user.to_string(),
-        status: status.to_string(),
+        status: presence.status,
+        devices: presence.devices,
+        emoji: presence.emoji,
+        text: presence.text,
     };
-    if let Ok(s) = serde_json::to_string(&msg) {
-        for r in clients.iter() {
-            let _ = r.value().tx.send(warp::ws::Message::text(s.clone()));
-        }
+    let payload = Payload::new(msg);
+    for r in clients.iter().filter(|r| r.value().supports("presence")) {
+        payload.send_to(&r.value().tx);
     }
 }
 
@@ -436,3 +574,23 @@ pub async fn load_private_history(private_histories: &PrivateHistories) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_author_may_edit_a_live_message() {
+        let mut item = HistoryItem::new("alice", "hi");
+        assert!(check_editable(&item, "alice").is_ok());
+        assert_eq!(
+            check_editable(&item, "bob").unwrap_err().code,
+            ErrorCode::Forbidden
+        );
+        item.deleted = true;
+        assert_eq!(
+            check_editable(&item, "alice").unwrap_err().code,
+            ErrorCode::NotFound
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Per-room posting rules set in `room_settings.rs`: slow mode, read-only
+//! rooms and the guest-posting switch. Moderators and admins are exempt.
+
+use dashmap::mapref::entry::Entry;
+use std::time::{Duration, Instant};
+
+use crate::errors::{ActionError, ActionResult, ErrorCode};
+use crate::roles::Role;
+use crate::room_settings::{guest_posting, read_only, slow_mode};
+use crate::types::{AppState, LastPosts};
+
+/// Longest slow mode interval a moderator may set (one hour).
+pub const MAX_SLOW_MODE_SECS: u64 = 3600;
+
+/// Post times are pruned once this many are tracked.
+const PRUNE_AT: usize = 10_000;
+
+/// Whether the sender with `rate_limit` key `sender_key` (and `role`) may
+/// post in `room` now. Records nothing; see `claim_post` for chat messages.
+pub fn check_post(state: &AppState, room: &str, sender_key: &str, role: Role) -> ActionResult {
+    if role >= Role::Moderator {
+        return Ok(());
+    }
+    check_room_rules(state, room, role)?;
+    let secs = slow_mode(&state.room_settings, room);
+    if secs > 0 {
+        let key = (room.to_string(), sender_key.to_string());
+        if let Some(last) = state.last_posts.get(&key) {
+            slow_mode_wait(*last, secs)?;
+        }
+    }
+    Ok(())
+}
+
+/// Like `check_post`, but also takes the sender's slow-mode slot in the
+/// same step, so concurrent posts from one key cannot all pass. Commit the
+/// claim once the message is posted; dropping it undoes the slot.
+pub fn claim_post(
+    state: &AppState,
+    room: &str,
+    sender_key: &str,
+    role: Role,
+) -> Result<PostClaim, ActionError> {
+    let mut claim = PostClaim {
+        last_posts: state.last_posts.clone(),
+        taken: None,
+        committed: false,
+    };
+    if role >= Role::Moderator {
+        return Ok(claim);
+    }
+    check_room_rules(state, room, role)?;
+    let secs = slow_mode(&state.room_settings, room);
+    if secs == 0 {
+        return Ok(claim);
+    }
+    let last_posts = &state.last_posts;
+    if last_posts.len() >= PRUNE_AT {
+        let max = Duration::from_secs(MAX_SLOW_MODE_SECS);
+        last_posts.retain(|_, t| t.elapsed() < max);
+    }
+    let key = (room.to_string(), sender_key.to_string());
+    let now = Instant::now();
+    let previous = match last_posts.entry(key.clone()) {
+        Entry::Occupied(mut e) => {
+            slow_mode_wait(*e.get(), secs)?;
+            Some(e.insert(now))
+        }
+        Entry::Vacant(e) => {
+            e.insert(now);
+            None
+        }
+    };
+    claim.taken = Some((key, previous, now));
+    Ok(claim)
+}
+
+/// A slow-mode slot taken by `claim_post`.
+pub struct PostClaim {
+    last_posts: LastPosts,
+    /// Key, the post time it replaced and the time it recorded
+    taken: Option<((String, String), Option<Instant>, Instant)>,
+    committed: bool,
+}
+
+impl PostClaim {
+    /// Keep the recorded post time. Called once the message is posted.
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for PostClaim {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        let Some((key, previous, taken_at)) = self.taken.take() else {
+            return;
+        };
+        // Rejected messages do not restart the timer
+        if let Entry::Occupied(mut e) = self.last_posts.entry(key) {
+            if *e.get() == taken_at {
+                match previous {
+                    Some(t) => {
+                        e.insert(t);
+                    }
+                    None => {
+                        e.remove();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read-only and guest-posting rules for a sender below moderator.
+fn check_room_rules(state: &AppState, room: &str, role: Role) -> ActionResult {
+    let settings = &state.room_settings;
+    if read_only(settings, room) {
+        return Err(ActionError::new(
+            ErrorCode::Forbidden,
+            format!("'{}' is read-only; only moderators can post here.", room),
+        ));
+    }
+    if role == Role::Guest && !guest_posting(settings, room) {
+        return Err(ActionError::new(
+            ErrorCode::NotAuthenticated,
+            format!(
+                "Guests cannot post in '{}'. Register or log in first.",
+                room
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Fails while a post made at `last` is less than `secs` seconds old.
+fn slow_mode_wait(last: Instant, secs: u64) -> ActionResult {
+    let wait = Duration::from_secs(secs).saturating_sub(last.elapsed());
+    if wait.is_zero() {
+        return Ok(());
+    }
+    Err(ActionError::new(
+        ErrorCode::RateLimited,
+        format!(
+            "Slow mode is on: you can post again in {} seconds.",
+            wait.as_secs().max(1)
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dashmap::DashMap;
+    use std::sync::Arc;
+
+    fn claim(last_posts: &LastPosts, previous: Option<Instant>) -> PostClaim {
+        let key = ("lobby".to_string(), "user:bob".to_string());
+        let now = Instant::now();
+        last_posts.insert(key.clone(), now);
+        PostClaim {
+            last_posts: last_posts.clone(),
+            taken: Some((key, previous, now)),
+            committed: false,
+        }
+    }
+
+    #[test]
+    fn dropped_claim_restores_the_previous_post_time() {
+        let last_posts: LastPosts = Arc::new(DashMap::new());
+        drop(claim(&last_posts, None));
+        assert!(last_posts.is_empty());
+
+        let earlier = Instant::now() - Duration::from_secs(90);
+        drop(claim(&last_posts, Some(earlier)));
+        assert_eq!(*last_posts.iter().next().unwrap().value(), earlier);
+
+        claim(&last_posts, Some(earlier)).commit();
+        assert_ne!(*last_posts.iter().next().unwrap().value(), earlier);
+    }
+
+    #[test]
+    fn slow_mode_waits_out_the_interval() {
+        assert!(slow_mode_wait(Instant::now() - Duration::from_secs(11), 10).is_ok());
+        let err = slow_mode_wait(Instant::now(), 10).unwrap_err();
+        assert_eq!(err.code, ErrorCode::RateLimited);
+    }
+}
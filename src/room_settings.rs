@@ -0,0 +1,147 @@
+//! Per-room settings changed by moderators, persisted to `room_settings.json`.
+//! Rooms without an entry use the defaults.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use tracing::info;
+
+use crate::types::RoomSettingsMap;
+use crate::word_filter::WordFilterMode;
+
+/// Settings of one room.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RoomSettings {
+    /// Message filters switched on or off in this room, by filter name.
+    /// Filters not listed use their own default.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub filters: HashMap<String, bool>,
+    /// What the word filter does here; `mask` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub word_filter: Option<WordFilterMode>,
+    /// Slow mode: seconds a user must wait between messages (0 = off).
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub slow_mode: u64,
+    /// Only moderators and admins may post; everyone may still react.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
+    /// Whether guests may post; allowed when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_posting: Option<bool>,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// Load room settings from `room_settings.json` (synchronous, done at startup).
+pub fn load_room_settings() -> anyhow::Result<HashMap<String, RoomSettings>> {
+    let path = "room_settings.json";
+    if Path::new(path).exists() {
+        let s = fs::read_to_string(path)?;
+        let m: HashMap<String, RoomSettings> = serde_json::from_str(&s)?;
+        info!("Loaded settings for {} rooms from disk", m.len());
+        Ok(m)
+    } else {
+        Ok(HashMap::new())
+    }
+}
+
+/// Save room settings to disk (async-friendly via spawn_blocking).
+pub async fn save_room_settings_async(settings: &RoomSettingsMap) -> anyhow::Result<()> {
+    let map: HashMap<String, RoomSettings> = settings
+        .iter()
+        .map(|r| (r.key().clone(), r.value().clone()))
+        .collect();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let s = serde_json::to_string_pretty(&map)?;
+        fs::write("room_settings.json", s)?;
+        Ok(())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Whether filter `name` runs in `room`, given the filter's default.
+pub fn filter_enabled(settings: &RoomSettingsMap, room: &str, name: &str, default: bool) -> bool {
+    settings
+        .get(room)
+        .and_then(|s| s.filters.get(name).copied())
+        .unwrap_or(default)
+}
+
+/// Switch filter `name` on or off in `room`; `None` restores its default.
+pub fn set_filter(settings: &RoomSettingsMap, room: &str, name: &str, enabled: Option<bool>) {
+    let mut entry = settings.entry(room.to_string()).or_default();
+    match enabled {
+        Some(on) => {
+            entry.filters.insert(name.to_string(), on);
+        }
+        None => {
+            entry.filters.remove(name);
+        }
+    }
+}
+
+/// The word filter mode of `room`.
+pub fn word_filter_mode(settings: &RoomSettingsMap, room: &str) -> WordFilterMode {
+    settings
+        .get(room)
+        .and_then(|s| s.word_filter)
+        .unwrap_or_default()
+}
+
+/// Set the word filter mode of `room`.
+pub fn set_word_filter_mode(settings: &RoomSettingsMap, room: &str, mode: WordFilterMode) {
+    settings.entry(room.to_string()).or_default().word_filter = Some(mode);
+}
+
+/// Slow mode interval of `room` in seconds (0 = off).
+pub fn slow_mode(settings: &RoomSettingsMap, room: &str) -> u64 {
+    settings.get(room).map(|s| s.slow_mode).unwrap_or(0)
+}
+
+/// Set the slow mode interval of `room` (0 turns it off).
+pub fn set_slow_mode(settings: &RoomSettingsMap, room: &str, secs: u64) {
+    settings.entry(room.to_string()).or_default().slow_mode = secs;
+}
+
+/// Whether `room` is read-only.
+pub fn read_only(settings: &RoomSettingsMap, room: &str) -> bool {
+    settings.get(room).is_some_and(|s| s.read_only)
+}
+
+/// Make `room` read-only or open it again.
+pub fn set_read_only(settings: &RoomSettingsMap, room: &str, on: bool) {
+    settings.entry(room.to_string()).or_default().read_only = on;
+}
+
+/// Whether guests may post in `room`.
+pub fn guest_posting(settings: &RoomSettingsMap, room: &str) -> bool {
+    settings
+        .get(room)
+        .and_then(|s| s.guest_posting)
+        .unwrap_or(true)
+}
+
+/// Allow or forbid guest posts in `room`.
+pub fn set_guest_posting(settings: &RoomSettingsMap, room: &str, on: bool) {
+    settings.entry(room.to_string()).or_default().guest_posting = Some(on);
+}
+
+/// Short notes on the posting restrictions of `room`, for `/room`.
+pub fn restrictions(settings: &RoomSettingsMap, room: &str) -> Vec<String> {
+    let mut notes = Vec::new();
+    let secs = slow_mode(settings, room);
+    if secs > 0 {
+        notes.push(format!("slow mode {}s", secs));
+    }
+    if read_only(settings, room) {
+        notes.push("read-only".to_string());
+    }
+    if !guest_posting(settings, room) {
+        notes.push("no guest posts".to_string());
+    }
+    notes
+}
@@ -0,0 +1,425 @@
+//! Spam and flood detection: scores repeated text, mention bombs, link
+//! posting and join/leave churn per user, and escalates from a warning to
+//! temporary mutes to a kick. Users are tracked by account, or by IP address
+//! for guests, so changing display name does not shake off a score or mute.
+
+use dashmap::DashMap;
+use futures::future::BoxFuture;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::encoding::Payload;
+use crate::errors::{ActionError, ActionResult, ErrorCode};
+use crate::filters::first_url;
+use crate::helpers::{disconnect_sessions, session_ids_by_key};
+use crate::mentions::extract_mentions;
+use crate::message_filter::{MessageContext, MessageFilter};
+use crate::rate_limit::client_key;
+use crate::roles::{notify_moderators, Role};
+use crate::room::send_system_to_room;
+use crate::types::{AppState, HistoryItem, Outgoing};
+
+/// How far back messages, links and room changes are remembered.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Identical messages count as repeats within this time.
+const REPEAT_WINDOW: Duration = Duration::from_secs(30);
+
+/// Points for each earlier identical message in `REPEAT_WINDOW`.
+const REPEAT_SCORE: f64 = 3.0;
+
+/// Distinct @mentions in one message before it counts as a mention bomb.
+const FREE_MENTIONS: usize = 4;
+
+/// Points for each mention beyond `FREE_MENTIONS`.
+const MENTION_SCORE: f64 = 3.0;
+
+/// Messages with links allowed per `WINDOW`.
+const FREE_LINKS: usize = 3;
+
+/// Points for each link message beyond `FREE_LINKS`.
+const LINK_SCORE: f64 = 3.0;
+
+/// Room changes allowed per `WINDOW`.
+const FREE_ROOM_CHANGES: usize = 6;
+
+/// Points for each room change beyond `FREE_ROOM_CHANGES`.
+const CHURN_SCORE: f64 = 2.0;
+
+/// Points the score loses per second.
+const DECAY_PER_SEC: f64 = 0.1;
+
+/// Score at which the user gets a strike.
+const THRESHOLD: f64 = 10.0;
+
+/// Strikes are forgotten after this long without a new one.
+const STRIKE_RESET: Duration = Duration::from_secs(3600);
+
+/// Mute lengths for the second and third strike.
+const SHORT_MUTE: Duration = Duration::from_secs(120);
+const LONG_MUTE: Duration = Duration::from_secs(600);
+
+/// Recent activity of one user.
+struct SpamRecord {
+    score: f64,
+    scored_at: Instant,
+    texts: VecDeque<(Instant, String)>,
+    links: VecDeque<Instant>,
+    room_changes: VecDeque<Instant>,
+    strikes: u32,
+    last_strike: Option<Instant>,
+}
+
+impl SpamRecord {
+    fn new() -> Self {
+        SpamRecord {
+            score: 0.0,
+            scored_at: Instant::now(),
+            texts: VecDeque::new(),
+            links: VecDeque::new(),
+            room_changes: VecDeque::new(),
+            strikes: 0,
+            last_strike: None,
+        }
+    }
+
+    /// Drop old activity and decay the score up to `now`.
+    fn age(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.scored_at).as_secs_f64();
+        self.score = (self.score - elapsed * DECAY_PER_SEC).max(0.0);
+        self.scored_at = now;
+        self.texts.retain(|(t, _)| now.duration_since(*t) < WINDOW);
+        self.links.retain(|t| now.duration_since(*t) < WINDOW);
+        self.room_changes
+            .retain(|t| now.duration_since(*t) < WINDOW);
+        if self
+            .last_strike
+            .is_some_and(|t| now.duration_since(t) >= STRIKE_RESET)
+        {
+            self.strikes = 0;
+            self.last_strike = None;
+        }
+    }
+
+    /// Add `points`; returns the new strike count if the score crossed the
+    /// threshold. A strike starts the user over from a clean slate, so the
+    /// next one takes fresh activity.
+    fn add(&mut self, points: f64, now: Instant) -> Option<u32> {
+        self.score += points;
+        if self.score < THRESHOLD {
+            return None;
+        }
+        self.score = 0.0;
+        self.texts.clear();
+        self.links.clear();
+        self.room_changes.clear();
+        self.strikes += 1;
+        self.last_strike = Some(now);
+        Some(self.strikes)
+    }
+}
+
+/// What a strike leads to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpamAction {
+    Warn,
+    Mute(Duration),
+    Kick,
+}
+
+impl SpamAction {
+    /// The response to the `strike`th strike.
+    fn for_strike(strike: u32) -> Self {
+        match strike {
+            1 => SpamAction::Warn,
+            2 => SpamAction::Mute(SHORT_MUTE),
+            3 => SpamAction::Mute(LONG_MUTE),
+            _ => SpamAction::Kick,
+        }
+    }
+}
+
+/// Per-user spam scores and active mutes, keyed by `rate_limit::client_key`
+/// (`user:<account>` or `ip:<address>`). Held in `AppState::spam`.
+pub struct SpamDetector {
+    records: DashMap<String, SpamRecord>,
+    mutes: DashMap<String, Instant>,
+}
+
+impl Default for SpamDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpamDetector {
+    pub fn new() -> Self {
+        SpamDetector {
+            records: DashMap::new(),
+            mutes: DashMap::new(),
+        }
+    }
+
+    /// Score a message from the user with key `key`; returns a reason and
+    /// strike number if it earned a strike.
+    pub fn score_message(&self, key: &str, text: &str) -> Option<(&'static str, u32)> {
+        let now = Instant::now();
+        let mut record = self
+            .records
+            .entry(key.to_string())
+            .or_insert_with(SpamRecord::new);
+        record.age(now);
+
+        let normalized = text.trim().to_lowercase();
+        let repeats = record
+            .texts
+            .iter()
+            .filter(|(t, s)| now.duration_since(*t) < REPEAT_WINDOW && *s == normalized)
+            .count();
+        record.texts.push_back((now, normalized));
+
+        let mentions: HashSet<String> = extract_mentions(text)
+            .into_iter()
+            .map(|m| m.to_lowercase())
+            .collect();
+        let extra_mentions = mentions.len().saturating_sub(FREE_MENTIONS);
+
+        let extra_links = if first_url(text).is_some() {
+            record.links.push_back(now);
+            record.links.len().saturating_sub(FREE_LINKS)
+        } else {
+            0
+        };
+
+        // The largest contribution names the reason
+        let parts = [
+            (repeats as f64 * REPEAT_SCORE, "repeating the same message"),
+            (
+                extra_mentions as f64 * MENTION_SCORE,
+                "mentioning too many users",
+            ),
+            (extra_links as f64 * LINK_SCORE, "posting too many links"),
+        ];
+        let points: f64 = parts.iter().map(|(p, _)| p).sum();
+        let reason = parts
+            .iter()
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, r)| *r)
+            .unwrap_or_default();
+        record.add(points, now).map(|strike| (reason, strike))
+    }
+
+    /// Record that `key` changed rooms; returns the strike number if it earned one.
+    pub fn score_room_change(&self, key: &str) -> Option<u32> {
+        let now = Instant::now();
+        let mut record = self
+            .records
+            .entry(key.to_string())
+            .or_insert_with(SpamRecord::new);
+        record.age(now);
+        record.room_changes.push_back(now);
+        let extra = record.room_changes.len().saturating_sub(FREE_ROOM_CHANGES);
+        record.add(extra as f64 * CHURN_SCORE, now)
+    }
+
+    /// Stop `key` from posting to rooms for `duration`.
+    pub fn mute(&self, key: &str, duration: Duration) {
+        self.mutes
+            .insert(key.to_string(), Instant::now() + duration);
+    }
+
+    /// Time left on `key`'s mute, if any.
+    pub fn muted_for(&self, key: &str) -> Option<Duration> {
+        let until = *self.mutes.get(key)?;
+        let now = Instant::now();
+        if until <= now {
+            self.mutes.remove(key);
+            return None;
+        }
+        Some(until - now)
+    }
+
+    /// `RateLimited` if `key` is muted.
+    pub fn check_muted(&self, key: &str) -> ActionResult {
+        match self.muted_for(key) {
+            Some(left) => Err(ActionError::new(
+                ErrorCode::RateLimited,
+                format!(
+                    "You are muted for another {} seconds.",
+                    left.as_secs().max(1)
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Whether spam scoring applies to a sender of `role`.
+fn exempt(role: Role) -> bool {
+    role >= Role::Moderator
+}
+
+/// Send a system message to every session with the key `key`.
+pub fn tell_user(state: &AppState, key: &str, text: &str) {
+    let payload = Payload::new(Outgoing::System {
+        text: text.to_string(),
+    });
+    for id in session_ids_by_key(&state.clients, key) {
+        if let Some(c) = state.clients.get(&id) {
+            payload.send_to(&c.tx);
+        }
+    }
+}
+
+/// Apply the response to the `strike`th strike of the user with key `key`
+/// (shown to others as `name`), earned in `room`, and tell moderators.
+/// Returns the action taken.
+pub async fn punish(
+    state: &AppState,
+    key: &str,
+    name: &str,
+    room: &str,
+    reason: &str,
+    strike: u32,
+) -> SpamAction {
+    let action = SpamAction::for_strike(strike);
+    let taken = match action {
+        SpamAction::Warn => {
+            tell_user(
+                state,
+                key,
+                &format!(
+                    "Warning: slow down, you are {}. Continuing will get you muted.",
+                    reason
+                ),
+            );
+            "warned".to_string()
+        }
+        SpamAction::Mute(duration) => {
+            state.spam.mute(key, duration);
+            tell_user(
+                state,
+                key,
+                &format!(
+                    "You have been muted for {} minutes for {}.",
+                    duration.as_secs() / 60,
+                    reason
+                ),
+            );
+            format!("muted for {} minutes", duration.as_secs() / 60)
+        }
+        SpamAction::Kick => {
+            tell_user(state, key, &format!("You have been kicked for {}.", reason));
+            send_system_to_room(
+                &state.clients,
+                &state.histories,
+                room,
+                &format!("-- {} has been kicked for spamming --", name),
+            )
+            .await;
+            disconnect_sessions(
+                &state.clients,
+                &session_ids_by_key(&state.clients, key),
+                "kicked for spamming",
+            );
+            "kicked".to_string()
+        }
+    };
+    warn!(
+        "Spam: {} {} in '{}' ({}, strike {})",
+        name, taken, room, reason, strike
+    );
+    let audit_action = match action {
+        SpamAction::Warn => "warn",
+        SpamAction::Mute(_) => "mute",
+        SpamAction::Kick => "kick",
+    };
+    state.audit.record(
+        "system",
+        audit_action,
+        name,
+        Some(room),
+        Some(&format!("spam: {}, strike {}", reason, strike)),
+    );
+    notify_moderators(
+        &state.clients,
+        &state.roles,
+        &format!(
+            "[spam] {} was {} in '{}' for {} (strike {})",
+            name, taken, room, reason, strike
+        ),
+    );
+    action
+}
+
+/// Score a room change by `client_id` into `room`, if the spam filter is on
+/// there. Called from `room::join_room`.
+pub async fn check_room_change(state: &AppState, client_id: &str, room: &str) {
+    let (key, name, bot) = match state.clients.get(client_id) {
+        Some(c) => (client_key(&c), c.name.clone(), c.bot),
+        None => return,
+    };
+    let role = crate::roles::client_role(&state.clients, &state.roles, client_id);
+    let enabled = state
+        .filters
+        .get(SPAM_FILTER)
+        .is_some_and(|f| state.filters.enabled_in(state, room, f));
+    if bot || exempt(role) || !enabled {
+        return;
+    }
+    if let Some(strike) = state.spam.score_room_change(&key) {
+        punish(
+            state,
+            &key,
+            &name,
+            room,
+            "joining and leaving rooms rapidly",
+            strike,
+        )
+        .await;
+    }
+}
+
+/// Name of the spam filter.
+pub const SPAM_FILTER: &str = "spam";
+
+/// The `spam` `MessageFilter`: scores each message and rejects it when the
+/// sender is muted or kicked as a result.
+pub struct SpamFilter;
+
+impl MessageFilter for SpamFilter {
+    fn name(&self) -> &str {
+        SPAM_FILTER
+    }
+
+    fn summary(&self) -> &str {
+        "Warn, mute and kick users who flood, repeat, mass-mention or spam links"
+    }
+
+    fn before<'a>(
+        &'a self,
+        ctx: &'a MessageContext<'a>,
+        item: &'a mut HistoryItem,
+    ) -> BoxFuture<'a, ActionResult> {
+        Box::pin(async move {
+            let Some(key) = ctx.sender_key else {
+                return Ok(());
+            };
+            if item.bot || exempt(ctx.sender_role) {
+                return Ok(());
+            }
+            let Some((reason, strike)) = ctx.state.spam.score_message(key, &item.text) else {
+                return Ok(());
+            };
+            match punish(ctx.state, key, &item.from, ctx.room, reason, strike).await {
+                SpamAction::Warn => Ok(()),
+                SpamAction::Mute(_) => ctx.state.spam.check_muted(key),
+                SpamAction::Kick => Err(ActionError::new(
+                    ErrorCode::Forbidden,
+                    "You have been kicked for spamming.",
+                )),
+            }
+        })
+    }
+}
@@ -0,0 +1,118 @@
+//! Fallback transport for networks that break WebSockets: server messages
+//! stream down as Server-Sent Events, client messages go up as HTTP POSTs.
+//! Sessions run through the same `run_session` as WebSocket connections.
+
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::{future, stream, StreamExt};
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tracing::info;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::sse::Event;
+use warp::Reply;
+
+use crate::client::run_session;
+use crate::connections::ConnectionPermit;
+use crate::errors::{error_reply, ActionError, ErrorCode};
+use crate::protocol::Transport;
+use crate::types::{AppState, SseSessions, WsParams};
+
+/// Largest accepted `POST /sse/{token}` body.
+pub const MAX_POST_BYTES: u64 = 64 * 1024;
+
+/// Removes the session's inbound sender once the event stream is dropped,
+/// which ends the session just like a closed WebSocket.
+struct SessionGuard {
+    sessions: SseSessions,
+    token: String,
+    _permit: ConnectionPermit,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.remove(&self.token);
+    }
+}
+
+/// `GET /sse`: start a session and stream its messages. The first event is
+/// `session`, carrying the token to POST to; every other event is one
+/// JSON-encoded server message.
+pub fn sse_connected(
+    remote: Option<SocketAddr>,
+    user_agent: Option<String>,
+    params: WsParams,
+    state: AppState,
+) -> warp::reply::Response {
+    let permit = match state.connections.acquire(remote) {
+        Ok(permit) => permit,
+        Err(e) => return error_reply(&e, None).into_response(),
+    };
+    let token = Uuid::new_v4().to_string();
+    let (in_tx, in_rx) = mpsc::unbounded();
+    let (out_tx, out_rx) = mpsc::unbounded::<warp::ws::Message>();
+    state.sse_sessions.insert(token.clone(), in_tx);
+    info!("SSE session opened from {:?}", remote);
+
+    let guard = SessionGuard {
+        sessions: state.sse_sessions.clone(),
+        token: token.clone(),
+        _permit: permit,
+    };
+    tokio::task::spawn(run_session(
+        Transport::EventStream,
+        out_tx,
+        in_rx,
+        remote,
+        user_agent,
+        params,
+        state,
+    ));
+
+    let first = stream::once(future::ready(Event::default().event("session").data(token)));
+    // A close frame ends the stream; SSE sessions never negotiate binary
+    // encodings, so every other frame is text.
+    let messages = out_rx
+        .take_while(|m| future::ready(!m.is_close()))
+        .filter_map(|m| future::ready(m.to_str().ok().map(|t| Event::default().data(t))));
+    let events = first.chain(messages).map(move |event| {
+        let _ = &guard;
+        Ok::<_, Infallible>(event)
+    });
+    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+}
+
+/// `POST /sse/{token}`: feed one client message (a JSON text frame) into
+/// the session. Replies arrive on the event stream.
+pub async fn sse_post(
+    token: String,
+    body: Bytes,
+    state: AppState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let text = match String::from_utf8(body.to_vec()) {
+        Ok(text) => text,
+        Err(_) => {
+            let e = ActionError::new(ErrorCode::BadRequest, "body must be UTF-8 text");
+            return Ok(error_reply(&e, None));
+        }
+    };
+    let delivered = state
+        .sse_sessions
+        .get(&token)
+        .map(|inbound| {
+            inbound
+                .unbounded_send(Ok(warp::ws::Message::text(text)))
+                .is_ok()
+        })
+        .unwrap_or(false);
+    if !delivered {
+        let e = ActionError::new(ErrorCode::NotFound, "unknown or closed session");
+        return Ok(error_reply(&e, None));
+    }
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({"ok": true})),
+        StatusCode::ACCEPTED,
+    ))
+}
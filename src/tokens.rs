@@ -0,0 +1,81 @@
+//! Bearer tokens for the HTTP API, each tied to a registered account.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::helpers::now_ts;
+use crate::types::{ApiTokens, Bots, Users};
+
+/// One issued token.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiToken {
+    /// Account name as stored in `users.json`.
+    pub account: String,
+    pub created: u64,
+}
+
+/// Load tokens from `api_tokens.json` (synchronous, done at startup).
+pub fn load_tokens() -> anyhow::Result<HashMap<String, ApiToken>> {
+    let path = "api_tokens.json";
+    if Path::new(path).exists() {
+        let s = fs::read_to_string(path)?;
+        let m: HashMap<String, ApiToken> = serde_json::from_str(&s)?;
+        info!("Loaded {} API tokens from disk", m.len());
+        Ok(m)
+    } else {
+        Ok(HashMap::new())
+    }
+}
+
+/// Save tokens to disk (async-friendly via spawn_blocking).
+pub async fn save_tokens_async(tokens: &ApiTokens) -> anyhow::Result<()> {
+    let map: HashMap<String, ApiToken> = tokens
+        .iter()
+        .map(|r| (r.key().clone(), r.value().clone()))
+        .collect();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let s = serde_json::to_string_pretty(&map)?;
+        fs::write("api_tokens.json", s)?;
+        Ok(())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Issue a new random token for `account`.
+pub fn issue_token(tokens: &ApiTokens, account: &str) -> String {
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    tokens.insert(
+        token.clone(),
+        ApiToken {
+            account: account.to_string(),
+            created: now_ts(),
+        },
+    );
+    info!("Issued API token for {}", account);
+    token
+}
+
+/// The token in an `Authorization: Bearer <token>` header value.
+pub fn bearer_token(header: &str) -> Option<&str> {
+    match header.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+        _ => None,
+    }
+}
+
+/// The account a token belongs to, if the token exists and the account is
+/// still registered (as a user or a bot).
+pub fn token_account(
+    tokens: &ApiTokens,
+    users: &Users,
+    bots: &Bots,
+    token: &str,
+) -> Option<String> {
+    let account = tokens.get(token)?.value().account.clone();
+    (users.contains_key(&account) || bots.contains_key(&account)).then_some(account)
+}
@@ -7,8 +7,45 @@ use std::sync::Arc;
 use std::time::Instant;
 use tokio::sync::{mpsc, RwLock};
 
-/// Sender channel for WebSocket messages to a client.
-pub type Tx = mpsc::UnboundedSender<warp::ws::Message>;
+use crate::audit::AuditLog;
+use crate::bots::BotAccount;
+use crate::command_registry::CommandRegistry;
+use crate::config::Config;
+use crate::connections::ConnectionCaps;
+use crate::encoding::Outbound;
+use crate::errors::ErrorCode;
+use crate::incoming_webhooks::IncomingWebhook;
+use crate::login_guard::LoginGuard;
+use crate::message_filter::MessagePipeline;
+use crate::metrics::ServerMetrics;
+use crate::outgoing_webhooks::OutgoingWebhooks;
+use crate::rate_limit::RateLimiter;
+use crate::reports::{Report, ReportView};
+use crate::roles::Role;
+use crate::room_settings::RoomSettings;
+use crate::spam::SpamDetector;
+use crate::tokens::ApiToken;
+use crate::upload_quota::{UploadRecord, UploadUsage};
+use crate::word_filter::WordList;
+
+/// Protocol version assumed for clients that don't ask for one.
+pub const PROTOCOL_V1: u32 = 1;
+
+/// First protocol version with structured `UserList` entries.
+pub const PROTOCOL_USER_LIST: u32 = 2;
+
+/// First protocol version with the `hello` handshake; plain-text frames
+/// are rejected instead of being treated as chat messages.
+pub const PROTOCOL_STRICT: u32 = 3;
+
+/// Oldest protocol version the server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = PROTOCOL_V1;
+
+/// Newest protocol version the server speaks.
+pub const PROTOCOL_VERSION: u32 = PROTOCOL_STRICT;
+
+/// Sender channel for outgoing messages to a client.
+pub type Tx = mpsc::UnboundedSender<Outbound>;
 
 /// Connected clients map: client_id -> Client
 pub type Clients = Arc<DashMap<String, Client>>;
@@ -19,20 +56,118 @@ pub type Histories = Arc<RwLock<HashMap<String, VecDeque<HistoryItem>>>>;
 /// Private message histories: key (sorted usernames) -> VecDeque<HistoryItem>
 pub type PrivateHistories = Arc<RwLock<HashMap<String, VecDeque<HistoryItem>>>>;
 
+/// Read markers: account -> (room -> last read message ID)
+pub type ReadMarkers = Arc<RwLock<HashMap<String, HashMap<String, String>>>>;
+
+/// Mention inboxes: account -> VecDeque<MentionItem>
+pub type Mentions = Arc<RwLock<HashMap<String, VecDeque<MentionItem>>>>;
+
+/// User-set statuses: account (or "guest:<name>") -> UserStatus
+pub type Presences = Arc<DashMap<String, UserStatus>>;
+
 /// Registered users: username -> password_hash
 pub type Users = Arc<DashMap<String, String>>;
 
+/// Assigned roles: lowercase username -> Role (unlisted accounts are `User`)
+pub type Roles = Arc<DashMap<String, Role>>;
+
+/// HTTP API bearer tokens: token -> owning account
+pub type ApiTokens = Arc<DashMap<String, ApiToken>>;
+
+/// Bot accounts: bot name -> account details
+pub type Bots = Arc<DashMap<String, BotAccount>>;
+
+/// Per-room settings: room name -> settings (rooms without an entry use
+/// the defaults)
+pub type RoomSettingsMap = Arc<DashMap<String, RoomSettings>>;
+
+/// When each user last posted in each room, for slow mode:
+/// (room, `rate_limit` key) -> time
+pub type LastPosts = Arc<DashMap<(String, String), Instant>>;
+
+/// Message reports, open and resolved: report id -> report
+pub type Reports = Arc<DashMap<String, Report>>;
+
+/// Who stored each uploaded file: stored file name -> record
+pub type UploadIndex = Arc<DashMap<String, UploadRecord>>;
+
+/// Incoming webhooks: hook id -> hook
+pub type IncomingWebhooks = Arc<DashMap<String, IncomingWebhook>>;
+
+/// Open SSE sessions: session token -> sender feeding that session's
+/// incoming frames (from `POST /sse/{token}`)
+pub type SseSessions = Arc<DashMap<String, SseInbound>>;
+
+/// Incoming side of an SSE session.
+pub type SseInbound =
+    futures::channel::mpsc::UnboundedSender<Result<warp::ws::Message, std::convert::Infallible>>;
+
+/// Bundle of shared state handles passed to connection and command handlers.
+/// Cloned cheaply (all fields are `Arc`-wrapped).
+#[derive(Clone)]
+pub struct AppState {
+    pub clients: Clients,
+    pub histories: Histories,
+    pub private_histories: PrivateHistories,
+    pub users: Users,
+    pub roles: Roles,
+    pub read_markers: ReadMarkers,
+    pub mentions: Mentions,
+    pub presences: Presences,
+    pub metrics: Arc<ServerMetrics>,
+    pub config: Arc<Config>,
+    pub sse_sessions: SseSessions,
+    pub api_tokens: ApiTokens,
+    pub incoming_webhooks: IncomingWebhooks,
+    pub outgoing_webhooks: Arc<OutgoingWebhooks>,
+    pub bots: Bots,
+    pub commands: Arc<CommandRegistry>,
+    pub filters: Arc<MessagePipeline>,
+    pub room_settings: RoomSettingsMap,
+    pub last_posts: LastPosts,
+    pub word_list: Arc<WordList>,
+    pub spam: Arc<SpamDetector>,
+    pub reports: Reports,
+    pub audit: Arc<AuditLog>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub connections: Arc<ConnectionCaps>,
+    pub login_guard: Arc<LoginGuard>,
+    pub uploads: UploadIndex,
+    pub upload_usage: Arc<UploadUsage>,
+}
+
 /// Represents a connected client.
 #[derive(Clone)]
 pub struct Client {
     pub name: String,
     pub tx: Tx,
     pub logged_in: bool,
+    pub account: Option<String>, // Registered username shared by all sessions
+    pub device: String,          // Coarse device label from the User-Agent
+    pub ip: String,              // Remote IP address, keys guests' rate limits
+    pub protocol: u32,           // Negotiated protocol version
+    pub capabilities: Vec<String>, // Capabilities agreed in the hello handshake
+    pub joined_at: u64,          // When the client entered its current room
     pub room: String,
-    pub last_message_times: Vec<Instant>, // For rate limiting
     pub is_typing: bool,
-    pub last_read_msg_id: Option<String>, // For read receipts
-    pub last_active: Instant,             // For online status
+    pub last_active: Instant,       // For online status
+    pub bot: bool,                  // Authenticated with a bot token
+    pub subscriptions: Vec<String>, // Extra rooms a bot receives, besides `room`
+}
+
+impl Client {
+    /// Whether the client wants an optional feature's messages. Clients that
+    /// skipped the `hello` handshake get everything; others only what they
+    /// declared as capabilities.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.protocol < PROTOCOL_STRICT || self.capabilities.iter().any(|c| c == feature)
+    }
+
+    /// Whether the client receives `room`'s traffic: its current room, or
+    /// one a bot subscribed to.
+    pub fn in_room(&self, room: &str) -> bool {
+        self.room == room || self.subscriptions.iter().any(|r| r == room)
+    }
 }
 
 /// A single message in the chat history.
@@ -48,12 +183,61 @@ pub struct HistoryItem {
     pub edited: bool,
     #[serde(default)]
     pub deleted: bool,
+    /// Posted by a bot or webhook rather than a person
+    #[serde(default)]
+    pub bot: bool,
+    /// Avatar URL or emoji supplied by a webhook
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    /// Notes added by message filters (e.g. `"link"`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<String>,
+}
+
+impl HistoryItem {
+    /// A new message from `from`, with a fresh ID and the current time.
+    pub fn new(from: impl Into<String>, text: impl Into<String>) -> Self {
+        HistoryItem {
+            id: crate::room::generate_msg_id(),
+            from: from.into(),
+            text: text.into(),
+            ts: crate::helpers::now_ts(),
+            reactions: HashMap::new(),
+            edited: false,
+            deleted: false,
+            bot: false,
+            icon: None,
+            flags: Vec::new(),
+        }
+    }
 }
 
 /// Messages sent from server to client.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum Outgoing {
+    Hello {
+        version: u32,
+        min_version: u32,
+        features: Vec<String>,
+    },
+    Incompatible {
+        reason: String,
+        min_version: u32,
+        max_version: u32,
+    },
+    Invalid {
+        reason: String,
+    },
+    Ack {
+        req_id: String,
+    },
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        req_id: Option<String>,
+        code: ErrorCode,
+        message: String,
+    },
     System {
         text: String,
     },
@@ -64,10 +248,21 @@ pub enum Outgoing {
         ts: u64,
         reactions: HashMap<String, Vec<String>>,
         edited: bool,
+        #[serde(default)]
+        bot: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        icon: Option<String>,
+        /// Room the message was posted in; absent for DMs
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
     },
     List {
         users: Vec<String>,
     },
+    UserList {
+        room: String,
+        users: Vec<UserEntry>,
+    },
     History {
         items: Vec<HistoryItem>,
     },
@@ -92,16 +287,31 @@ pub enum Outgoing {
         last_msg_id: String,
     },
     Mention {
+        msg_id: String,
+        room: String,
         from: String,
         text: String,
         mentioned: String,
     },
+    Mentions {
+        items: Vec<MentionItem>,
+    },
+    /// Open message reports, for moderators
+    Reports {
+        reports: Vec<ReportView>,
+    },
     RoomList {
         rooms: Vec<RoomInfo>,
     },
     Status {
         user: String,
         status: String,
+        #[serde(default)]
+        devices: Vec<DeviceStatus>,
+        #[serde(default)]
+        emoji: Option<String>,
+        #[serde(default)]
+        text: Option<String>,
     },
     LinkPreview {
         msg_id: String,
@@ -113,6 +323,9 @@ pub enum Outgoing {
     Nudge {
         from: String,
     },
+    Unread {
+        rooms: Vec<UnreadInfo>,
+    },
 }
 
 /// Room info for available rooms list.
@@ -122,15 +335,480 @@ pub struct RoomInfo {
     pub members: usize,
 }
 
-/// Messages received from client.
+/// A stored mention in a user's inbox.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MentionItem {
+    pub msg_id: String,
+    pub room: String,
+    pub from: String,
+    pub text: String,
+    pub ts: u64,
+    #[serde(default)]
+    pub read: bool,
+}
+
+/// Unread counts for one room, relative to the account's read marker.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnreadInfo {
+    pub room: String,
+    pub unread: usize,
+    pub mentions: usize,
+    pub last_read: String,
+}
+
+/// One user in a structured room user list.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserEntry {
+    pub name: String,
+    pub account: Option<String>,
+    pub logged_in: bool,
+    pub role: Role,
+    pub status: String,
+    pub emoji: Option<String>,
+    pub text: Option<String>,
+    pub devices: Vec<DeviceStatus>,
+    pub joined_at: u64,
+    #[serde(default)]
+    pub bot: bool,
+}
+
+/// Query parameters accepted on the `/ws` route.
+#[derive(Deserialize, Debug, Default)]
+pub struct WsParams {
+    /// Requested protocol version (defaults to `PROTOCOL_V1`).
+    pub v: Option<u32>,
+    /// Bot API token; authenticates the bot and skips the name phase.
+    pub token: Option<String>,
+}
+
+/// Presence of one session (device) of a user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceStatus {
+    pub device: String,
+    pub status: String,
+}
+
+/// A manually chosen presence that overrides active/idle detection.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceMode {
+    Away,
+    Dnd,
+}
+
+/// Status set by the user with `/away`, `/dnd` or `/status`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UserStatus {
+    #[serde(default)]
+    pub mode: Option<PresenceMode>,
+    #[serde(default)]
+    pub emoji: Option<String>,
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+/// Messages received from client. Every message may carry a `req_id`,
+/// echoed back in the `Ack` or `Error` reply.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum Incoming {
-    Cmd { cmd: String },
-    Msg { text: String },
-    Typing { is_typing: bool },
-    React { msg_id: String, emoji: String },
-    Edit { msg_id: String, new_text: String },
-    Delete { msg_id: String },
-    MarkRead { last_msg_id: String },
+    Hello {
+        #[serde(default)]
+        req_id: Option<String>,
+        version: u32,
+        #[serde(default)]
+        min_version: Option<u32>,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    Cmd {
+        #[serde(default)]
+        req_id: Option<String>,
+        cmd: String,
+    },
+    Msg {
+        #[serde(default)]
+        req_id: Option<String>,
+        text: String,
+        /// Target room for bots; defaults to the current room
+        #[serde(default)]
+        room: Option<String>,
+    },
+    Typing {
+        #[serde(default)]
+        req_id: Option<String>,
+        is_typing: bool,
+    },
+    React {
+        #[serde(default)]
+        req_id: Option<String>,
+        msg_id: String,
+        emoji: String,
+    },
+    Edit {
+        #[serde(default)]
+        req_id: Option<String>,
+        msg_id: String,
+        new_text: String,
+    },
+    Delete {
+        #[serde(default)]
+        req_id: Option<String>,
+        msg_id: String,
+    },
+    MarkRead {
+        #[serde(default)]
+        req_id: Option<String>,
+        last_msg_id: String,
+    },
+    /// Report a message in the current room to the moderators.
+    Report {
+        #[serde(default)]
+        req_id: Option<String>,
+        msg_id: String,
+        reason: String,
+    },
+    /// Switch to (or create) a room.
+    Join {
+        #[serde(default)]
+        req_id: Option<String>,
+        room: String,
+    },
+    /// Return to the lobby.
+    Leave {
+        #[serde(default)]
+        req_id: Option<String>,
+    },
+    /// List all rooms.
+    Rooms {
+        #[serde(default)]
+        req_id: Option<String>,
+    },
+    /// Show the current room.
+    Room {
+        #[serde(default)]
+        req_id: Option<String>,
+    },
+    /// Change display name.
+    Name {
+        #[serde(default)]
+        req_id: Option<String>,
+        name: String,
+    },
+    /// Request the user list of the current room.
+    List {
+        #[serde(default)]
+        req_id: Option<String>,
+    },
+    /// Create an account.
+    Register {
+        #[serde(default)]
+        req_id: Option<String>,
+        username: String,
+        password: String,
+    },
+    /// Log in to an account.
+    Login {
+        #[serde(default)]
+        req_id: Option<String>,
+        username: String,
+        password: String,
+    },
+    /// Reload the current room's history.
+    History {
+        #[serde(default)]
+        req_id: Option<String>,
+    },
+    /// Private message to a user in the same room.
+    Dm {
+        #[serde(default)]
+        req_id: Option<String>,
+        to: String,
+        text: String,
+    },
+    /// Disconnect every session of a user.
+    Kick {
+        #[serde(default)]
+        req_id: Option<String>,
+        user: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Server statistics.
+    Stats {
+        #[serde(default)]
+        req_id: Option<String>,
+    },
+    /// Command help.
+    Help {
+        #[serde(default)]
+        req_id: Option<String>,
+    },
+    /// Users in the current room with their status.
+    Who {
+        #[serde(default)]
+        req_id: Option<String>,
+    },
+    /// This user's connected devices.
+    Sessions {
+        #[serde(default)]
+        req_id: Option<String>,
+    },
+    /// Show the mention inbox, or empty it with `clear`.
+    Mentions {
+        #[serde(default)]
+        req_id: Option<String>,
+        #[serde(default)]
+        clear: bool,
+    },
+    /// Show a user's role, or set it when `role` is given (admin only).
+    Role {
+        #[serde(default)]
+        req_id: Option<String>,
+        user: String,
+        #[serde(default)]
+        role: Option<String>,
+    },
+    /// Mark yourself away.
+    Away {
+        #[serde(default)]
+        req_id: Option<String>,
+        #[serde(default)]
+        message: Option<String>,
+    },
+    /// Turn on do-not-disturb.
+    Dnd {
+        #[serde(default)]
+        req_id: Option<String>,
+        #[serde(default)]
+        message: Option<String>,
+    },
+    /// Clear away / do-not-disturb.
+    Back {
+        #[serde(default)]
+        req_id: Option<String>,
+    },
+    /// Set a custom status; without an emoji the status is cleared.
+    Status {
+        #[serde(default)]
+        req_id: Option<String>,
+        #[serde(default)]
+        emoji: Option<String>,
+        #[serde(default)]
+        text: Option<String>,
+    },
+    /// Nudge everyone in the room.
+    Nudge {
+        #[serde(default)]
+        req_id: Option<String>,
+    },
+    /// Manage incoming webhooks (admin only). `action` is `create` (needs
+    /// `room`, optional `name`), `list`, or `delete` (needs `id`).
+    Webhook {
+        #[serde(default)]
+        req_id: Option<String>,
+        action: String,
+        #[serde(default)]
+        room: Option<String>,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        id: Option<String>,
+    },
+    /// Manage bot accounts (admin only). `action` is `create`, `token`
+    /// (issue a new token, revoking old ones) or `delete`, all needing
+    /// `name`; or `list`.
+    Bot {
+        #[serde(default)]
+        req_id: Option<String>,
+        action: String,
+        #[serde(default)]
+        name: Option<String>,
+    },
+    /// Also receive a room's traffic (bots only).
+    Subscribe {
+        #[serde(default)]
+        req_id: Option<String>,
+        room: String,
+    },
+    /// Stop receiving a subscribed room's traffic (bots only).
+    Unsubscribe {
+        #[serde(default)]
+        req_id: Option<String>,
+        room: String,
+    },
+    /// Show the room's message filters, or switch one (moderators only).
+    /// `setting` is `on`, `off` or `default`; without `name` the filters
+    /// are listed.
+    Filter {
+        #[serde(default)]
+        req_id: Option<String>,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        setting: Option<String>,
+    },
+    /// List open message reports, or resolve one (moderators only).
+    /// `action` is `dismiss`, `delete` (the message) or `mute` (its author,
+    /// for `minutes`, default 10), with the report `id`.
+    Reports {
+        #[serde(default)]
+        req_id: Option<String>,
+        #[serde(default)]
+        action: Option<String>,
+        #[serde(default)]
+        id: Option<String>,
+        #[serde(default)]
+        minutes: Option<u64>,
+    },
+    /// Show recent audit log entries, optionally only those naming a user
+    /// or room (admins only).
+    Audit {
+        #[serde(default)]
+        req_id: Option<String>,
+        #[serde(default)]
+        query: Option<String>,
+    },
+    /// Show or set the room's slow mode interval in seconds; 0 turns it
+    /// off (moderators only).
+    SlowMode {
+        #[serde(default)]
+        req_id: Option<String>,
+        #[serde(default)]
+        seconds: Option<u64>,
+    },
+    /// Show whether the room is read-only, or switch it (moderators only).
+    ReadOnly {
+        #[serde(default)]
+        req_id: Option<String>,
+        #[serde(default)]
+        enabled: Option<bool>,
+    },
+    /// Show whether guests may post in the room, or switch it (moderators
+    /// only).
+    GuestPosting {
+        #[serde(default)]
+        req_id: Option<String>,
+        #[serde(default)]
+        enabled: Option<bool>,
+    },
+    /// Show the room's word filter mode, or set it to `off`, `mask`,
+    /// `block` or `flag` (moderators only).
+    WordFilter {
+        #[serde(default)]
+        req_id: Option<String>,
+        #[serde(default)]
+        mode: Option<String>,
+    },
+    /// Run a registered command that has no typed message of its own
+    /// (e.g. `/roll`). `name` is without the slash.
+    Command {
+        #[serde(default)]
+        req_id: Option<String>,
+        name: String,
+        #[serde(default)]
+        args: String,
+    },
+}
+
+impl Incoming {
+    /// The client-chosen request id, if any.
+    pub fn req_id(&self) -> Option<String> {
+        match self {
+            Incoming::Hello { req_id, .. }
+            | Incoming::Cmd { req_id, .. }
+            | Incoming::Msg { req_id, .. }
+            | Incoming::Typing { req_id, .. }
+            | Incoming::React { req_id, .. }
+            | Incoming::Edit { req_id, .. }
+            | Incoming::Delete { req_id, .. }
+            | Incoming::MarkRead { req_id, .. }
+            | Incoming::Report { req_id, .. }
+            | Incoming::Reports { req_id, .. }
+            | Incoming::Audit { req_id, .. }
+            | Incoming::SlowMode { req_id, .. }
+            | Incoming::ReadOnly { req_id, .. }
+            | Incoming::GuestPosting { req_id, .. }
+            | Incoming::Join { req_id, .. }
+            | Incoming::Leave { req_id, .. }
+            | Incoming::Rooms { req_id, .. }
+            | Incoming::Room { req_id, .. }
+            | Incoming::Name { req_id, .. }
+            | Incoming::List { req_id, .. }
+            | Incoming::Register { req_id, .. }
+            | Incoming::Login { req_id, .. }
+            | Incoming::History { req_id, .. }
+            | Incoming::Dm { req_id, .. }
+            | Incoming::Kick { req_id, .. }
+            | Incoming::Stats { req_id, .. }
+            | Incoming::Help { req_id, .. }
+            | Incoming::Who { req_id, .. }
+            | Incoming::Sessions { req_id, .. }
+            | Incoming::Mentions { req_id, .. }
+            | Incoming::Role { req_id, .. }
+            | Incoming::Away { req_id, .. }
+            | Incoming::Dnd { req_id, .. }
+            | Incoming::Back { req_id, .. }
+            | Incoming::Status { req_id, .. }
+            | Incoming::Nudge { req_id, .. }
+            | Incoming::Webhook { req_id, .. }
+            | Incoming::Bot { req_id, .. }
+            | Incoming::Subscribe { req_id, .. }
+            | Incoming::Unsubscribe { req_id, .. }
+            | Incoming::Filter { req_id, .. }
+            | Incoming::WordFilter { req_id, .. }
+            | Incoming::Command { req_id, .. } => req_id.clone(),
+        }
+    }
+
+    /// The registry name of the command this message runs, or `None` for
+    /// messages that are not commands (chat, typing, reactions, ...).
+    pub fn command_name(&self) -> Option<&str> {
+        let name = match self {
+            Incoming::Join { .. } => "join",
+            Incoming::Leave { .. } => "leave",
+            Incoming::Rooms { .. } => "rooms",
+            Incoming::Room { .. } => "room",
+            Incoming::Name { .. } => "name",
+            Incoming::List { .. } => "list",
+            Incoming::Register { .. } => "register",
+            Incoming::Login { .. } => "login",
+            Incoming::History { .. } => "history",
+            Incoming::Dm { .. } => "msg",
+            Incoming::Kick { .. } => "kick",
+            Incoming::Stats { .. } => "stats",
+            Incoming::Help { .. } => "help",
+            Incoming::Who { .. } => "who",
+            Incoming::Sessions { .. } => "sessions",
+            Incoming::Mentions { .. } => "mentions",
+            Incoming::Role { .. } => "role",
+            Incoming::Away { .. } => "away",
+            Incoming::Dnd { .. } => "dnd",
+            Incoming::Back { .. } => "back",
+            Incoming::Status { .. } => "status",
+            Incoming::Nudge { .. } => "nudge",
+            Incoming::Webhook { .. } => "webhook",
+            Incoming::Bot { .. } => "bot",
+            Incoming::Subscribe { .. } => "subscribe",
+            Incoming::Unsubscribe { .. } => "unsubscribe",
+            Incoming::Filter { .. } => "filter",
+            Incoming::WordFilter { .. } => "wordfilter",
+            Incoming::Reports { .. } => "reports",
+            Incoming::Audit { .. } => "audit",
+            Incoming::SlowMode { .. } => "slowmode",
+            Incoming::ReadOnly { .. } => "readonly",
+            Incoming::GuestPosting { .. } => "guestposting",
+            Incoming::Command { name, .. } => name,
+            Incoming::Hello { .. }
+            | Incoming::Cmd { .. }
+            | Incoming::Msg { .. }
+            | Incoming::Typing { .. }
+            | Incoming::React { .. }
+            | Incoming::Edit { .. }
+            | Incoming::Delete { .. }
+            | Incoming::MarkRead { .. }
+            | Incoming::Report { .. } => return None,
+        };
+        Some(name)
+    }
 }
@@ -1,5 +1,6 @@
 //! Typing indicator functionality.
 
+use crate::encoding::Payload;
 use crate::types::{Clients, Outgoing};
 
 /// Set typing status for a client.
@@ -16,23 +17,24 @@ pub async fn broadcast_typing_status(clients: &Clients, client_id: &str) {
             .get(client_id)
             .map(|r| r.value().room.clone())
             .unwrap_or_default();
-        let typing: Vec<String> = clients
-            .iter()
-            .filter(|r| r.value().room == room && r.value().is_typing)
-            .map(|r| r.value().name.clone())
-            .collect();
+        let mut typing: Vec<String> = Vec::new();
+        for r in clients.iter() {
+            let c = r.value();
+            if c.room == room && c.is_typing && !typing.contains(&c.name) {
+                typing.push(c.name.clone());
+            }
+        }
         (room, typing)
     };
 
     let msg = Outgoing::Typing {
         users: typing_users,
     };
-    if let Ok(s) = serde_json::to_string(&msg) {
-        for r in clients.iter() {
-            let c = r.value();
-            if c.room == room {
-                let _ = c.tx.send(warp::ws::Message::text(s.clone()));
-            }
+    let payload = Payload::new(msg);
+    for r in clients.iter() {
+        let c = r.value();
+        if c.room == room {
+            payload.send_to(&c.tx);
         }
     }
 }
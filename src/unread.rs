@@ -0,0 +1,244 @@
+//! Per-account read markers and unread counts.
+
+use crate::encoding::Payload;
+use crate::helpers::session_ids_by_account;
+use crate::mentions::mentions_user;
+use crate::roles::{account_role, Role};
+use crate::types::{AppState, HistoryItem, Outgoing, ReadMarkers, UnreadInfo};
+use std::collections::{HashMap, VecDeque};
+use tracing::{error, info};
+
+/// Record that `account` has read `room` up to `msg_id`.
+/// Returns false if the marker was already there.
+pub async fn mark_read(markers: &ReadMarkers, account: &str, room: &str, msg_id: &str) -> bool {
+    let mut locked = markers.write().await;
+    let rooms = locked.entry(account.to_lowercase()).or_default();
+    if rooms.get(room).map(|m| m == msg_id).unwrap_or(false) {
+        return false;
+    }
+    rooms.insert(room.to_string(), msg_id.to_string());
+    true
+}
+
+/// Mark the client's current room as read up to its latest message.
+pub async fn mark_current_room_read(state: &AppState, client_id: &str) {
+    let (account, room) = match state.clients.get(client_id) {
+        Some(r) => match &r.value().account {
+            Some(acc) => (acc.clone(), r.value().room.clone()),
+            None => return,
+        },
+        None => return,
+    };
+    let latest = {
+        let locked = state.histories.read().await;
+        locked
+            .get(&room)
+            .and_then(|q| q.back())
+            .map(|i| i.id.clone())
+    };
+    // Empty rooms still get a marker so they show up in the summary
+    let latest = latest.unwrap_or_default();
+    if mark_read(&state.read_markers, &account, &room, &latest).await {
+        send_unread_summary(state, &account, Some(&room)).await;
+    }
+}
+
+/// Count unread messages and unread mentions after `marker` for the user
+/// known by `names` (account first, then display names). `sender_role`
+/// gives the role of a message's author, for `@here` and `@room`.
+fn count_unread(
+    q: &VecDeque<HistoryItem>,
+    marker: &str,
+    names: &[&str],
+    sender_role: impl Fn(&str) -> Role,
+) -> (usize, usize) {
+    // If the marker rolled out of the history window everything left is unread
+    let start = q
+        .iter()
+        .position(|i| i.id == marker)
+        .map(|p| p + 1)
+        .unwrap_or(0);
+    let mut unread = 0;
+    let mut mentions = 0;
+    for item in q.iter().skip(start) {
+        if item.deleted
+            || item.from == "system"
+            || names.iter().any(|n| item.from.eq_ignore_ascii_case(n))
+        {
+            continue;
+        }
+        unread += 1;
+        if mentions_user(&item.text, sender_role(&item.from), names) {
+            mentions += 1;
+        }
+    }
+    (unread, mentions)
+}
+
+/// Build the unread summary for an account, optionally for a single room.
+pub async fn unread_summary(
+    state: &AppState,
+    account: &str,
+    only: Option<&str>,
+) -> Vec<UnreadInfo> {
+    let rooms: HashMap<String, String> = {
+        let locked = state.read_markers.read().await;
+        locked
+            .get(&account.to_lowercase())
+            .cloned()
+            .unwrap_or_default()
+    };
+    // Display names of the account's sessions reach it too while connected
+    let mut names: Vec<String> = vec![account.to_string()];
+    for id in session_ids_by_account(&state.clients, account) {
+        if let Some(r) = state.clients.get(&id) {
+            if !names
+                .iter()
+                .any(|n| n.eq_ignore_ascii_case(&r.value().name))
+            {
+                names.push(r.value().name.clone());
+            }
+        }
+    }
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let sender_role = |from: &str| {
+        if state.users.contains_key(from) {
+            account_role(&state.roles, Some(from))
+        } else {
+            Role::Guest
+        }
+    };
+    let locked_h = state.histories.read().await;
+    let mut summary: Vec<UnreadInfo> = rooms
+        .into_iter()
+        .filter(|(room, _)| only.map(|o| o == room).unwrap_or(true))
+        .map(|(room, marker)| {
+            let (unread, mentions) = locked_h
+                .get(&room)
+                .map(|q| count_unread(q, &marker, &names, sender_role))
+                .unwrap_or((0, 0));
+            UnreadInfo {
+                room,
+                unread,
+                mentions,
+                last_read: marker,
+            }
+        })
+        .collect();
+    summary.sort_by(|a, b| a.room.cmp(&b.room));
+    summary
+}
+
+/// Push an unread summary to every session of `account`.
+pub async fn send_unread_summary(state: &AppState, account: &str, only: Option<&str>) {
+    let rooms = unread_summary(state, account, only).await;
+    if rooms.is_empty() {
+        return;
+    }
+    let msg = Outgoing::Unread { rooms };
+    let payload = Payload::new(msg);
+    for id in session_ids_by_account(&state.clients, account) {
+        if let Some(r) = state.clients.get(&id) {
+            if r.value().supports("unread") {
+                payload.send_to(&r.value().tx);
+            }
+        }
+    }
+}
+
+/// After a new message in `room`, update counts for accounts that have
+/// visited it but have no session currently in it.
+pub async fn notify_room_activity(state: &AppState, room: &str) {
+    let mut accounts: Vec<String> = Vec::new();
+    for r in state.clients.iter() {
+        if let Some(acc) = &r.value().account {
+            if !accounts.iter().any(|a| a.eq_ignore_ascii_case(acc)) {
+                accounts.push(acc.clone());
+            }
+        }
+    }
+    for account in accounts {
+        let in_room = state.clients.iter().any(|r| {
+            let c = r.value();
+            c.in_room(room)
+                && c.account
+                    .as_deref()
+                    .map(|a| a.eq_ignore_ascii_case(&account))
+                    .unwrap_or(false)
+        });
+        if !in_room {
+            send_unread_summary(state, &account, Some(room)).await;
+        }
+    }
+}
+
+pub async fn save_read_markers(markers: &ReadMarkers) {
+    let m = markers.read().await;
+    match serde_json::to_string(&*m) {
+        Ok(json) => {
+            if let Err(e) = tokio::fs::write("read_markers.json", json).await {
+                error!("Failed to save read_markers.json: {}", e);
+            } else {
+                info!("Read markers saved to read_markers.json");
+            }
+        }
+        Err(e) => error!("Failed to serialize read markers: {}", e),
+    }
+}
+
+pub async fn load_read_markers(markers: &ReadMarkers) {
+    if let Ok(json) = tokio::fs::read_to_string("read_markers.json").await {
+        match serde_json::from_str::<HashMap<String, HashMap<String, String>>>(&json) {
+            Ok(loaded) => {
+                let mut m = markers.write().await;
+                *m = loaded;
+                info!("Loaded read markers from read_markers.json");
+            }
+            Err(e) => error!("Failed to parse read_markers.json: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(items: &[(&str, &str)]) -> VecDeque<HistoryItem> {
+        items
+            .iter()
+            .map(|(from, text)| HistoryItem::new(*from, *text))
+            .collect()
+    }
+
+    fn role_of(from: &str) -> Role {
+        match from {
+            "mod" => Role::Moderator,
+            "guest" => Role::Guest,
+            _ => Role::User,
+        }
+    }
+
+    #[test]
+    fn counts_mentions_like_the_inbox() {
+        let q = history(&[
+            ("bob", "hi @Alice"),
+            ("bob", "@Ally look"),
+            ("bob", "@here anyone?"),
+            ("guest", "@here hello"),
+            ("mod", "@room meeting"),
+            ("bob", "@room not allowed"),
+            ("bob", "email alice@example.com"),
+            ("ally", "@alice my own message"),
+        ]);
+        let (unread, mentions) = count_unread(&q, "", &["alice", "Ally"], role_of);
+        assert_eq!(unread, 7);
+        assert_eq!(mentions, 4);
+    }
+
+    #[test]
+    fn counts_only_after_the_marker() {
+        let q = history(&[("bob", "@alice one"), ("bob", "@alice two")]);
+        let marker = q[0].id.clone();
+        assert_eq!(count_unread(&q, &marker, &["alice"], role_of), (1, 1));
+    }
+}
@@ -4,29 +4,139 @@ use bytes::Buf;
 use futures::StreamExt;
 use futures::TryStreamExt;
 use serde_json::json;
+use std::net::SocketAddr;
 use tokio::io::AsyncWriteExt;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 use warp::http::StatusCode;
 use warp::multipart::{FormData, Part};
 
-/// Handle multipart file upload.
-pub async fn handle_upload(form: FormData) -> Result<impl warp::Reply, warp::Rejection> {
-    // Ensure uploads dir exists
-    if let Err(e) = tokio::fs::create_dir_all("uploads").await {
-        error!("failed to create uploads dir: {}", e);
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&json!({"ok": false, "error": "internal"})),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ));
+use crate::errors::{error_reply, ActionError, ErrorCode};
+use crate::helpers::now_ts;
+use crate::rate_limit::{account_key, ip_key, Action};
+use crate::tokens::{bearer_token, token_account};
+use crate::types::AppState;
+use crate::upload_quota::{save_uploads_async, UploadBudget, UploadRecord, UPLOAD_DIR};
+
+/// Room for multipart boundaries and part headers on top of the
+/// per-request limit when capping the body length.
+pub const MULTIPART_OVERHEAD: u64 = 64 * 1024;
+
+/// Handle multipart file upload. The optional `X-Request-Id` header is
+/// echoed back as `req_id` in the response.
+pub async fn handle_upload(
+    form: FormData,
+    req_id: Option<String>,
+    authorization: Option<String>,
+    remote: Option<SocketAddr>,
+    state: AppState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match upload(form, authorization, remote, &state).await {
+        Ok(saved_urls) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"ok": true, "files": saved_urls, "req_id": req_id})),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(error_reply(&e, req_id)),
+    }
+}
+
+/// Turn rejections raised before the handler runs (such as the body size
+/// limit) into structured upload errors; everything else passes through.
+pub async fn handle_upload_rejection(
+    rejection: warp::Rejection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if rejection.find::<warp::reject::PayloadTooLarge>().is_some() {
+        let e = ActionError::new(
+            ErrorCode::PayloadTooLarge,
+            "upload is larger than the per-request limit",
+        );
+        return Ok(error_reply(&e, None));
+    }
+    Err(rejection)
+}
+
+/// Check the caller and its limits, then store the files. Uploads with a
+/// bearer token count against the token's account, others against the
+/// remote address.
+async fn upload(
+    form: FormData,
+    authorization: Option<String>,
+    remote: Option<SocketAddr>,
+    state: &AppState,
+) -> Result<Vec<serde_json::Value>, ActionError> {
+    let ip = remote
+        .map(|a| a.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    state.rate_limiter.check(Action::Upload, &ip_key(&ip))?;
+    let owner = match authorization.as_deref() {
+        Some(header) => {
+            let token = bearer_token(header).ok_or_else(|| {
+                ActionError::new(ErrorCode::NotAuthenticated, "expected a bearer token")
+            })?;
+            let account = token_account(&state.api_tokens, &state.users, &state.bots, token)
+                .ok_or_else(|| ActionError::new(ErrorCode::AuthFailed, "invalid token"))?;
+            account_key(&account)
+        }
+        None => ip_key(&ip),
+    };
+    let mut budget = UploadBudget::for_owner(state, &owner).await?;
+
+    let mut stored: Vec<(String, u64)> = Vec::new();
+    match save_parts(form, &mut budget, &mut stored).await {
+        Ok(saved_urls) => {
+            let ts = now_ts();
+            for (name, size) in stored {
+                let record = UploadRecord {
+                    owner: owner.clone(),
+                    size,
+                    ts,
+                };
+                state.uploads.insert(name, record);
+            }
+            budget.commit();
+            if let Err(e) = save_uploads_async(&state.uploads).await {
+                error!("failed to save upload index: {}", e);
+            }
+            Ok(saved_urls)
+        }
+        Err(e) => {
+            // Nothing from a refused request stays on disk; dropping the
+            // budget then releases its reservations
+            for (name, _) in &stored {
+                let path = format!("{}/{}", UPLOAD_DIR, name);
+                if let Err(e) = tokio::fs::remove_file(&path).await {
+                    warn!("failed to remove {}: {}", path, e);
+                }
+            }
+            Err(e)
+        }
     }
+}
+
+/// Write every file part to `uploads/`, charging each chunk to `budget`
+/// before it is written, and describe the saved files. Every file created,
+/// even partly written, is added to `stored` with its size.
+async fn save_parts(
+    form: FormData,
+    budget: &mut UploadBudget,
+    stored: &mut Vec<(String, u64)>,
+) -> Result<Vec<serde_json::Value>, ActionError> {
+    let internal = |what: &str, e: &dyn std::fmt::Display| {
+        error!("{}: {}", what, e);
+        ActionError::new(ErrorCode::Internal, "failed to store upload")
+    };
+
+    // Ensure uploads dir exists
+    tokio::fs::create_dir_all(UPLOAD_DIR)
+        .await
+        .map_err(|e| internal("failed to create uploads dir", &e))?;
 
     let mut parts = form;
     let mut saved_urls: Vec<serde_json::Value> = Vec::new();
 
     while let Some(part_result) = parts.try_next().await.map_err(|e| {
         error!("multipart error: {}", e);
-        warp::reject::reject()
+        ActionError::new(ErrorCode::BadRequest, "malformed multipart body")
     })? {
         let part: Part = part_result;
         let filename_opt: Option<String> = part.filename().map(|s| s.to_string());
@@ -39,51 +149,51 @@ pub async fn handle_upload(form: FormData) -> Result<impl warp::Reply, warp::Rej
             );
             let id = Uuid::new_v4().to_string();
             let stored_name = format!("{}_{}", id, safe_name);
-            let path = format!("uploads/{}", stored_name);
+            let path = format!("{}/{}", UPLOAD_DIR, stored_name);
 
-            let mut file = tokio::fs::File::create(&path).await.map_err(|e| {
-                error!("create file error: {}", e);
-                warp::reject::reject()
-            })?;
+            let mut file = tokio::fs::File::create(&path)
+                .await
+                .map_err(|e| internal("create file error", &e))?;
+            stored.push((stored_name.clone(), 0));
 
             let mut stream = part.stream();
+            let mut written: u64 = 0;
 
             while let Some(chunk_res) = stream.next().await {
                 let mut buf = chunk_res.map_err(|e| {
                     error!("chunk error: {}", e);
-                    warp::reject::reject()
+                    ActionError::new(ErrorCode::BadRequest, "upload interrupted")
                 })?;
                 while buf.has_remaining() {
                     let bytes = buf.chunk();
                     if !bytes.is_empty() {
-                        file.write_all(bytes).await.map_err(|e| {
-                            error!("write error: {}", e);
-                            warp::reject::reject()
-                        })?;
                         let n = bytes.len();
+                        budget.charge(written, n as u64)?;
+                        file.write_all(bytes)
+                            .await
+                            .map_err(|e| internal("write error", &e))?;
+                        written += n as u64;
                         buf.advance(n);
                     } else {
                         break;
                     }
                 }
             }
+            if let Some(last) = stored.last_mut() {
+                last.1 = written;
+            }
 
-            let meta_len = file.metadata().await.map(|m| m.len()).unwrap_or(0);
             let url = format!("/uploads/{}", stored_name);
-            saved_urls.push(json!({ "filename": filename, "url": url, "size": meta_len }));
+            saved_urls.push(json!({ "filename": filename, "url": url, "size": written }));
             info!("File uploaded: {} -> {}", filename, url);
         }
     }
 
     if saved_urls.is_empty() {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&json!({"ok": false, "files": []})),
-            StatusCode::BAD_REQUEST,
-        ))
-    } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&json!({"ok": true, "files": saved_urls})),
-            StatusCode::OK,
-        ))
+        return Err(ActionError::new(
+            ErrorCode::BadRequest,
+            "no files in upload",
+        ));
     }
+    Ok(saved_urls)
 }
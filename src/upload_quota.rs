@@ -0,0 +1,381 @@
+//! Upload limits: per-file and per-request sizes, per-uploader and global
+//! disk quotas, and a free-disk-space floor. Who uploaded what is kept in
+//! `uploads.json`, so per-uploader usage survives restarts. Bytes are
+//! reserved in shared counters as they are written, so concurrent requests
+//! see each other's uploads.
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::errors::{ActionError, ActionResult, ErrorCode};
+use crate::types::{AppState, UploadIndex};
+
+/// Directory uploaded files are stored in and served from.
+pub const UPLOAD_DIR: &str = "uploads";
+
+/// One stored file, keyed by its name in `uploads/`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadRecord {
+    /// `user:<account>` for uploads with a bearer token, else `ip:<address>`
+    pub owner: String,
+    pub size: u64,
+    pub ts: u64,
+}
+
+/// Load the upload index from `uploads.json` (synchronous, done at startup).
+pub fn load_uploads() -> anyhow::Result<HashMap<String, UploadRecord>> {
+    let path = "uploads.json";
+    if Path::new(path).exists() {
+        let s = fs::read_to_string(path)?;
+        let m: HashMap<String, UploadRecord> = serde_json::from_str(&s)?;
+        info!("Loaded {} upload records from disk", m.len());
+        Ok(m)
+    } else {
+        Ok(HashMap::new())
+    }
+}
+
+/// Save the upload index to disk (async-friendly via spawn_blocking).
+pub async fn save_uploads_async(uploads: &UploadIndex) -> anyhow::Result<()> {
+    let map: HashMap<String, UploadRecord> = uploads
+        .iter()
+        .map(|r| (r.key().clone(), r.value().clone()))
+        .collect();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let s = serde_json::to_string_pretty(&map)?;
+        fs::write("uploads.json", s)?;
+        Ok(())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Bytes in `uploads/`, including files that predate the index.
+fn dir_usage() -> u64 {
+    fs::read_dir(UPLOAD_DIR)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok()?.metadata().ok())
+                .filter(|m| m.is_file())
+                .map(|m| m.len())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Free space on the disk holding `uploads/`, if it can be read.
+async fn free_space() -> Option<u64> {
+    tokio::task::spawn_blocking(|| fs2::available_space(UPLOAD_DIR))
+        .await
+        .ok()?
+        .map_err(|e| warn!("cannot read free space for {}: {}", UPLOAD_DIR, e))
+        .ok()
+}
+
+/// A byte count as `N MB`, `N KB` or `N bytes`, for error messages.
+fn size_label(n: u64) -> String {
+    const MB: u64 = 1024 * 1024;
+    match n {
+        n if n >= MB && n % MB == 0 => format!("{} MB", n / MB),
+        n if n >= 1024 && n % 1024 == 0 => format!("{} KB", n / 1024),
+        n => format!("{} bytes", n),
+    }
+}
+
+/// Bytes stored or being written, per uploader and in total, shared by all
+/// upload requests so that concurrent uploads cannot together overshoot a
+/// quota. Every chunk is reserved here before it is written; a refused
+/// request releases what it reserved, a successful one keeps it as stored.
+/// Held in `AppState::upload_usage`.
+pub struct UploadUsage {
+    /// Per uploader key (`user:<account>` or `ip:<address>`)
+    owners: DashMap<String, u64>,
+    /// All of `uploads/`
+    total: AtomicU64,
+    /// Bytes reserved since startup; requests measure the free-space floor
+    /// against it
+    reserved: AtomicU64,
+}
+
+impl UploadUsage {
+    /// Usage per the upload index and the files in `uploads/` (synchronous,
+    /// done at startup).
+    pub fn load(uploads: &HashMap<String, UploadRecord>) -> Self {
+        let owners = DashMap::new();
+        for record in uploads.values() {
+            *owners.entry(record.owner.clone()).or_insert(0) += record.size;
+        }
+        UploadUsage {
+            owners,
+            total: AtomicU64::new(dir_usage()),
+            reserved: AtomicU64::new(0),
+        }
+    }
+
+    /// Bytes stored or reserved by `owner`.
+    fn owner(&self, owner: &str) -> u64 {
+        self.owners.get(owner).map(|r| *r).unwrap_or(0)
+    }
+
+    /// Give back `n` bytes reserved by `owner`.
+    fn release(&self, owner: &str, n: u64) {
+        if let Some(mut used) = self.owners.get_mut(owner) {
+            *used = used.saturating_sub(n);
+        }
+        let _ = self
+            .total
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |t| {
+                Some(t.saturating_sub(n))
+            });
+    }
+}
+
+/// What one upload request may still write. Every chunk is charged before
+/// it is written, so no limit is overshot on disk. Dropping the budget
+/// releases its reservations unless `commit` was called.
+pub struct UploadBudget {
+    usage: Arc<UploadUsage>,
+    owner: String,
+    max_file: u64,
+    max_request: u64,
+    request_left: u64,
+    owner_quota: Option<u64>,
+    total_quota: Option<u64>,
+    /// Room above the free-space floor when the request started, and
+    /// `UploadUsage::reserved` at that time
+    floor_left: Option<(u64, u64)>,
+    /// Bytes this request has reserved
+    reserved: u64,
+    committed: bool,
+}
+
+impl UploadBudget {
+    /// The budget for a request from `owner`, or a `quota_exceeded` error
+    /// when there is no room left at all.
+    pub async fn for_owner(state: &AppState, owner: &str) -> Result<Self, ActionError> {
+        let config = &state.config;
+        let usage = state.upload_usage.clone();
+        let owner_quota =
+            (config.upload_user_quota_bytes > 0).then_some(config.upload_user_quota_bytes);
+        let total_quota =
+            (config.upload_total_quota_bytes > 0).then_some(config.upload_total_quota_bytes);
+        // Read the counter first: bytes reserved while free space is being
+        // read are then counted against the floor, not missed
+        let reserved_before = usage.reserved.load(Ordering::SeqCst);
+        let floor_left = match config.upload_min_free_bytes {
+            0 => None,
+            floor => free_space()
+                .await
+                .map(|free| (free.saturating_sub(floor), reserved_before)),
+        };
+        if owner_quota.is_some_and(|quota| usage.owner(owner) >= quota) {
+            return Err(owner_full());
+        }
+        if total_quota.is_some_and(|quota| usage.total.load(Ordering::SeqCst) >= quota)
+            || floor_left.is_some_and(|(left, _)| left == 0)
+        {
+            return Err(disk_full());
+        }
+        Ok(UploadBudget {
+            usage,
+            owner: owner.to_string(),
+            max_file: config.upload_max_file_bytes,
+            max_request: config.upload_max_request_bytes,
+            request_left: config.upload_max_request_bytes,
+            owner_quota,
+            total_quota,
+            floor_left,
+            reserved: 0,
+            committed: false,
+        })
+    }
+
+    /// Charge `n` more bytes to a file that already has `file_len` bytes,
+    /// reserving them in the shared usage.
+    pub fn charge(&mut self, file_len: u64, n: u64) -> ActionResult {
+        if file_len + n > self.max_file {
+            return Err(ActionError::new(
+                ErrorCode::PayloadTooLarge,
+                format!(
+                    "file is larger than the {} limit",
+                    size_label(self.max_file)
+                ),
+            ));
+        }
+        if n > self.request_left {
+            return Err(ActionError::new(
+                ErrorCode::PayloadTooLarge,
+                format!(
+                    "upload is larger than the {} per-request limit",
+                    size_label(self.max_request)
+                ),
+            ));
+        }
+        self.reserve(n)?;
+        self.request_left -= n;
+        self.reserved += n;
+        Ok(())
+    }
+
+    /// Reserve `n` bytes under the global quota, the uploader's quota and
+    /// the floor, in that order, undoing the earlier steps if a later one
+    /// fails.
+    fn reserve(&self, n: u64) -> ActionResult {
+        let usage = &self.usage;
+        usage
+            .total
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |t| {
+                match self.total_quota {
+                    Some(quota) if t + n > quota => None,
+                    _ => Some(t + n),
+                }
+            })
+            .map_err(|_| disk_full())?;
+        {
+            let mut used = usage.owners.entry(self.owner.clone()).or_insert(0);
+            if self.owner_quota.is_some_and(|quota| *used + n > quota) {
+                drop(used);
+                usage.total.fetch_sub(n, Ordering::SeqCst);
+                return Err(owner_full());
+            }
+            *used += n;
+        }
+        let floor = usage
+            .reserved
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |r| {
+                match self.floor_left {
+                    Some((left, before)) if r - before + n > left => None,
+                    _ => Some(r + n),
+                }
+            });
+        if floor.is_err() {
+            usage.release(&self.owner, n);
+            return Err(disk_full());
+        }
+        Ok(())
+    }
+
+    /// Keep the reserved bytes as stored. Called once the request's files
+    /// are in the upload index.
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for UploadBudget {
+    fn drop(&mut self) {
+        if !self.committed && self.reserved > 0 {
+            self.usage.release(&self.owner, self.reserved);
+        }
+    }
+}
+
+fn owner_full() -> ActionError {
+    ActionError::new(ErrorCode::QuotaExceeded, "your upload quota is used up")
+}
+
+fn disk_full() -> ActionError {
+    ActionError::new(
+        ErrorCode::QuotaExceeded,
+        "the server is out of space for uploads",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(total: u64) -> Arc<UploadUsage> {
+        Arc::new(UploadUsage {
+            owners: DashMap::new(),
+            total: AtomicU64::new(total),
+            reserved: AtomicU64::new(0),
+        })
+    }
+
+    fn budget(
+        usage: &Arc<UploadUsage>,
+        owner_quota: Option<u64>,
+        total_quota: Option<u64>,
+        floor_left: Option<u64>,
+    ) -> UploadBudget {
+        UploadBudget {
+            usage: usage.clone(),
+            owner: "user:alice".to_string(),
+            max_file: 100,
+            max_request: 150,
+            request_left: 150,
+            owner_quota,
+            total_quota,
+            floor_left: floor_left.map(|left| (left, usage.reserved.load(Ordering::SeqCst))),
+            reserved: 0,
+            committed: false,
+        }
+    }
+
+    fn code(result: ActionResult) -> ErrorCode {
+        result.unwrap_err().code
+    }
+
+    #[test]
+    fn charge_enforces_file_and_request_limits() {
+        let usage = usage(0);
+        let mut b = budget(&usage, None, None, None);
+        assert!(b.charge(0, 100).is_ok());
+        assert_eq!(code(b.charge(100, 1)), ErrorCode::PayloadTooLarge);
+        assert!(b.charge(0, 50).is_ok());
+        assert_eq!(code(b.charge(0, 1)), ErrorCode::PayloadTooLarge);
+        assert_eq!(usage.owner("user:alice"), 150);
+    }
+
+    #[test]
+    fn refused_reservations_are_rolled_back() {
+        let usage = usage(0);
+        let mut b = budget(&usage, Some(60), None, None);
+        assert!(b.charge(0, 50).is_ok());
+        assert_eq!(code(b.charge(50, 20)), ErrorCode::QuotaExceeded);
+        assert_eq!(usage.owner("user:alice"), 50);
+        assert_eq!(usage.total.load(Ordering::SeqCst), 50);
+
+        let mut b = budget(&usage, None, None, Some(10));
+        assert_eq!(code(b.charge(0, 20)), ErrorCode::QuotaExceeded);
+        assert_eq!(usage.owner("user:alice"), 50);
+        assert_eq!(usage.total.load(Ordering::SeqCst), 50);
+
+        let mut b = budget(&usage, None, Some(60), None);
+        assert_eq!(code(b.charge(0, 20)), ErrorCode::QuotaExceeded);
+        assert_eq!(usage.total.load(Ordering::SeqCst), 50);
+    }
+
+    #[test]
+    fn concurrent_budgets_share_the_quota() {
+        let usage = usage(0);
+        let mut first = budget(&usage, Some(100), None, None);
+        let mut second = budget(&usage, Some(100), None, None);
+        assert!(first.charge(0, 70).is_ok());
+        assert_eq!(code(second.charge(0, 70)), ErrorCode::QuotaExceeded);
+        assert!(second.charge(0, 30).is_ok());
+    }
+
+    #[test]
+    fn drop_releases_unless_committed() {
+        let usage = usage(0);
+        let mut b = budget(&usage, None, None, None);
+        b.charge(0, 40).unwrap();
+        drop(b);
+        assert_eq!(usage.owner("user:alice"), 0);
+        assert_eq!(usage.total.load(Ordering::SeqCst), 0);
+
+        let mut b = budget(&usage, None, None, None);
+        b.charge(0, 40).unwrap();
+        b.commit();
+        assert_eq!(usage.owner("user:alice"), 40);
+        assert_eq!(usage.total.load(Ordering::SeqCst), 40);
+    }
+}
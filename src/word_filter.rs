@@ -0,0 +1,397 @@
+//! Word filter: banned words loaded from a text file (one per line, `#`
+//! starts a comment) and reloaded when the file changes. Each room picks
+//! what happens to a message containing one: nothing, mask it, block it, or
+//! let it through and flag it for moderators.
+//!
+//! Matching is on whole words and tolerates common leetspeak (`b4dw0rd`,
+//! `$pam`), so `class` never matches `ass` but `a$$` does.
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::errors::{ActionError, ActionResult, ErrorCode};
+use crate::message_filter::{MessageContext, MessageFilter};
+use crate::roles::notify_moderators;
+use crate::room_settings::word_filter_mode;
+use crate::types::HistoryItem;
+
+/// How often the word file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Replacement for a masked word.
+const MASK: &str = "****";
+
+/// Flag added to messages let through in `flag` mode.
+pub const WORD_FLAG: &str = "word_filter";
+
+/// Symbols that stand in for letters in leetspeak; they count as part of a
+/// word when splitting text.
+const LEET_SYMBOLS: &[char] = &['@', '$', '!', '|', '+'];
+
+/// What a room does with a message containing a banned word.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WordFilterMode {
+    /// Let it through unchanged.
+    Off,
+    /// Replace the word with `****`.
+    #[default]
+    Mask,
+    /// Refuse the message and tell the sender why.
+    Block,
+    /// Let it through, flag it and notify moderators.
+    Flag,
+}
+
+impl WordFilterMode {
+    pub fn parse(s: &str) -> Option<WordFilterMode> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Some(WordFilterMode::Off),
+            "mask" => Some(WordFilterMode::Mask),
+            "block" => Some(WordFilterMode::Block),
+            "flag" => Some(WordFilterMode::Flag),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for WordFilterMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            WordFilterMode::Off => "off",
+            WordFilterMode::Mask => "mask",
+            WordFilterMode::Block => "block",
+            WordFilterMode::Flag => "flag",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The banned words, kept in sync with the word file.
+pub struct WordList {
+    path: PathBuf,
+    /// Lowercase words plus their leetspeak-normalized spellings.
+    words: RwLock<HashSet<String>>,
+    /// How many words the file lists.
+    count: RwLock<usize>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl WordList {
+    /// Load the word file. A missing file gives an empty list that is
+    /// picked up once the file appears.
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let list = WordList {
+            path: path.into(),
+            words: RwLock::new(HashSet::new()),
+            count: RwLock::new(0),
+            modified: Mutex::new(None),
+        };
+        list.reload_if_changed();
+        list
+    }
+
+    /// Number of words in the list.
+    pub fn word_count(&self) -> usize {
+        *self.count.read().unwrap()
+    }
+
+    /// Re-read the file if its modification time changed. Returns whether
+    /// the list was reloaded.
+    pub fn reload_if_changed(&self) -> bool {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok();
+        {
+            let mut last = self.modified.lock().unwrap();
+            if *last == modified {
+                return false;
+            }
+            *last = modified;
+        }
+        let text = match modified {
+            Some(_) => match std::fs::read_to_string(&self.path) {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!("failed to read {}: {}", self.path.display(), e);
+                    return false;
+                }
+            },
+            None => String::new(),
+        };
+        let listed: Vec<String> = text
+            .lines()
+            .map(|l| {
+                l.split('#')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_lowercase()
+            })
+            .filter(|l| !l.is_empty())
+            .collect();
+        let mut words = HashSet::new();
+        for word in &listed {
+            words.insert(word.clone());
+            words.extend(normalized(word));
+        }
+        *self.words.write().unwrap() = words;
+        *self.count.write().unwrap() = listed.len();
+        info!(
+            "Loaded {} filtered words from {}",
+            listed.len(),
+            self.path.display()
+        );
+        true
+    }
+
+    /// Byte ranges of the banned words in `text`, in order.
+    pub fn find(&self, text: &str) -> Vec<Range<usize>> {
+        let words = self.words.read().unwrap();
+        if words.is_empty() {
+            return Vec::new();
+        }
+        let mut found = Vec::new();
+        for (start, token) in tokens(text) {
+            // Try the token as written, then without symbols at its edges
+            // ("word!" or "|word|")
+            let trimmed = token.trim_matches(|c: char| !c.is_alphanumeric());
+            let offset = token.find(trimmed).unwrap_or(0);
+            for (candidate, at) in [(token, 0), (trimmed, offset)] {
+                if candidate.is_empty() {
+                    continue;
+                }
+                let lower = candidate.to_lowercase();
+                if words.contains(&lower) || normalized(&lower).iter().any(|w| words.contains(w)) {
+                    found.push(start + at..start + at + candidate.len());
+                    break;
+                }
+            }
+        }
+        found
+    }
+
+    /// `text` with every banned word replaced by `****`.
+    pub fn mask(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut last = 0;
+        for range in self.find(text) {
+            out.push_str(&text[last..range.start]);
+            out.push_str(MASK);
+            last = range.end;
+        }
+        out.push_str(&text[last..]);
+        out
+    }
+}
+
+/// Check the word file for changes every few seconds, forever.
+pub async fn watch(list: Arc<WordList>) {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        let list = list.clone();
+        let _ = tokio::task::spawn_blocking(move || list.reload_if_changed()).await;
+    }
+}
+
+/// Words of `text` with their byte offsets. Leetspeak symbols count as
+/// word characters.
+fn tokens(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    let is_word = |c: char| c.is_alphanumeric() || LEET_SYMBOLS.contains(&c);
+    let mut rest = text.char_indices().peekable();
+    std::iter::from_fn(move || {
+        while let Some(&(_, c)) = rest.peek() {
+            if is_word(c) {
+                break;
+            }
+            rest.next();
+        }
+        let (start, _) = *rest.peek()?;
+        let mut end = start;
+        while let Some(&(i, c)) = rest.peek() {
+            if !is_word(c) {
+                break;
+            }
+            end = i + c.len_utf8();
+            rest.next();
+        }
+        Some((start, &text[start..end]))
+    })
+}
+
+/// Leetspeak readings of a lowercase word. `1` and `|` can be `i` or `l`,
+/// so there are two.
+fn normalized(word: &str) -> [String; 2] {
+    let read = |one: char| {
+        word.chars()
+            .map(|c| match c {
+                '4' | '@' => 'a',
+                '8' => 'b',
+                '3' => 'e',
+                '9' => 'g',
+                '!' => 'i',
+                '1' | '|' => one,
+                '0' => 'o',
+                '5' | '$' => 's',
+                '7' | '+' => 't',
+                c => c,
+            })
+            .collect()
+    };
+    [read('i'), read('l')]
+}
+
+/// Applies the word list to new messages and edits, according to each
+/// room's mode.
+pub struct WordFilter {
+    list: Arc<WordList>,
+}
+
+impl WordFilter {
+    pub fn new(list: Arc<WordList>) -> Self {
+        WordFilter { list }
+    }
+
+    /// Apply the room's mode to `item`.
+    fn apply(&self, ctx: &MessageContext<'_>, item: &mut HistoryItem) -> ActionResult {
+        let mode = word_filter_mode(&ctx.state.room_settings, ctx.room);
+        if mode == WordFilterMode::Off || self.list.find(&item.text).is_empty() {
+            item.flags.retain(|f| f != WORD_FLAG);
+            return Ok(());
+        }
+        match mode {
+            WordFilterMode::Off => {}
+            WordFilterMode::Mask => item.text = self.list.mask(&item.text),
+            WordFilterMode::Block => {
+                return Err(ActionError::new(
+                    ErrorCode::Forbidden,
+                    "Message blocked: it contains a word that is not allowed in this room.",
+                ));
+            }
+            WordFilterMode::Flag => {
+                if !item.flags.iter().any(|f| f == WORD_FLAG) {
+                    item.flags.push(WORD_FLAG.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell moderators about a flagged message.
+    fn report(&self, ctx: &MessageContext<'_>, item: &HistoryItem, edited: bool) {
+        if !item.flags.iter().any(|f| f == WORD_FLAG) {
+            return;
+        }
+        let text = format!(
+            "[word filter] {} {} a flagged message in '{}' ({}): {}",
+            item.from,
+            if edited { "edited" } else { "posted" },
+            ctx.room,
+            item.id,
+            item.text
+        );
+        notify_moderators(&ctx.state.clients, &ctx.state.roles, &text);
+    }
+}
+
+impl MessageFilter for WordFilter {
+    fn name(&self) -> &str {
+        "words"
+    }
+
+    fn summary(&self) -> &str {
+        "Mask, block or flag banned words (mode set with /wordfilter)"
+    }
+
+    fn before<'a>(
+        &'a self,
+        ctx: &'a MessageContext<'a>,
+        item: &'a mut HistoryItem,
+    ) -> BoxFuture<'a, ActionResult> {
+        let result = self.apply(ctx, item);
+        Box::pin(async move { result })
+    }
+
+    fn after<'a>(
+        &'a self,
+        ctx: &'a MessageContext<'a>,
+        item: &'a HistoryItem,
+    ) -> BoxFuture<'a, ()> {
+        self.report(ctx, item, false);
+        Box::pin(async {})
+    }
+
+    fn before_edit<'a>(
+        &'a self,
+        ctx: &'a MessageContext<'a>,
+        item: &'a mut HistoryItem,
+    ) -> BoxFuture<'a, ActionResult> {
+        let result = self.apply(ctx, item);
+        if result.is_ok() {
+            self.report(ctx, item, true);
+        }
+        Box::pin(async move { result })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A list of `words` written to a file of its own, as the server loads it.
+    fn list(name: &str, words: &str) -> WordList {
+        let path =
+            std::env::temp_dir().join(format!("word_filter_{}_{}.txt", name, std::process::id()));
+        std::fs::write(&path, words).unwrap();
+        let list = WordList::load(&path);
+        let _ = std::fs::remove_file(&path);
+        list
+    }
+
+    fn spans(list: &WordList, text: &str) -> Vec<(usize, usize)> {
+        list.find(text)
+            .into_iter()
+            .map(|r| (r.start, r.end))
+            .collect()
+    }
+
+    #[test]
+    fn splits_words_keeping_leet_symbols() {
+        let found: Vec<_> = tokens("hi, a$$ (b@d) ok!").collect();
+        assert_eq!(found, [(0, "hi"), (4, "a$$"), (9, "b@d"), (14, "ok!")]);
+    }
+
+    #[test]
+    fn reads_leetspeak_both_ways() {
+        assert_eq!(normalized("a$$"), ["ass", "ass"]);
+        assert_eq!(normalized("1337"), ["ieet", "leet"]);
+        assert_eq!(normalized("plain"), ["plain", "plain"]);
+    }
+
+    #[test]
+    fn matches_whole_words_and_leetspeak() {
+        let list = list("match", "ass\n# comment\nbad  # trailing\n");
+        assert_eq!(list.word_count(), 2);
+        assert!(list.find("a classic class").is_empty());
+        assert!(list.find("badly").is_empty());
+        assert_eq!(spans(&list, "you a$$"), [(4, 7)]);
+        assert_eq!(spans(&list, "so BAD!"), [(3, 6)]);
+        assert_eq!(spans(&list, "|b4d|"), [(1, 4)]);
+        assert_eq!(list.mask("bad and a55"), "**** and ****");
+    }
+
+    #[test]
+    fn missing_file_is_an_empty_list() {
+        let list = WordList::load(std::env::temp_dir().join("word_filter_missing.txt"));
+        assert_eq!(list.word_count(), 0);
+        assert!(list.find("anything").is_empty());
+    }
+}
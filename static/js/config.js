@@ -1,6 +1,11 @@
 // ===== Configuration =====
+const PROTOCOL_VERSION = 3;
+const CLIENT_CAPABILITIES = ['userlist', 'unread', 'mentions', 'presence', 'sessions']
+    .concat(typeof DecompressionStream === 'function' ? ['deflate'] : []);
 const wsUrl = (location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host + '/ws';
-const commands = ['/name', '/msg', '/list', '/history', '/join', '/rooms', '/register', '/login', '/help', '/who', '/leave', '/room', '/pin', '/unpin'];
+const sseUrl = '/sse';
+const WS_FAILURES_BEFORE_SSE = 2;
+const commands = ['/name', '/msg', '/list', '/history', '/join', '/rooms', '/register', '/login', '/help', '/who', '/leave', '/room', '/pin', '/unpin', '/mentions', '/sessions', '/away', '/dnd', '/back', '/status', '/webhook', '/bot', '/roll', '/filter', '/wordfilter', '/reports', '/audit', '/slowmode', '/readonly', '/guestposting'];
 const REACTION_EMOJIS = ['👍', '❤️', '😂', '😮', '😢', '🎉'];
 
 // Full emoji list for picker
@@ -45,7 +45,7 @@ function initEventListeners() {
     DOM.setNameBtn.onclick = () => {
         const n = DOM.nameInput.value.trim();
         if (!n) return;
-        sendCommand('/name ' + n);
+        sendAction('name', { name: n });
         myName = n;
         named = true;
         updateInputState();
@@ -177,7 +177,7 @@ function initEventListeners() {
     DOM.roomList.onclick = (e) => {
         const item = e.target.closest('.room-item');
         if (item) {
-            sendCommand('/join ' + item.dataset.room);
+            sendAction('join', { room: item.dataset.room });
             closeSidebar();
         }
     };
@@ -202,7 +202,7 @@ function initEventListeners() {
 
     DOM.joinRoomBtn.onclick = () => {
         const r = DOM.newRoomInput.value.trim();
-        if (r) { sendCommand('/join ' + r); DOM.newRoomInput.value = ''; closeSidebar(); }
+        if (r) { sendAction('join', { room: r }); DOM.newRoomInput.value = ''; closeSidebar(); }
     };
 
     DOM.newRoomInput.onkeydown = (e) => {
@@ -233,7 +233,7 @@ function initEventListeners() {
         btn.classList.add('loading');
         btn.textContent = 'Authenticating...';
 
-        sendCommand(`/login ${u} ${p}`);
+        sendAction('login', { username: u, password: p });
 
         // Remove loading state after 5 seconds if no response
         setTimeout(() => {
@@ -250,7 +250,7 @@ function initEventListeners() {
         btn.classList.add('loading');
         btn.textContent = 'Processing...';
 
-        sendCommand(`/register ${u} ${p}`);
+        sendAction('register', { username: u, password: p });
 
         // Remove loading state after 5 seconds if no response
         setTimeout(() => {
@@ -304,7 +304,9 @@ function initEventListeners() {
                     DOM.uploadMsg.textContent = 'Error parsing response';
                 }
             } else {
-                DOM.uploadMsg.textContent = `Error: ${xhr.status}`;
+                let message = `Error: ${xhr.status}`;
+                try { message = JSON.parse(xhr.responseText).message || message; } catch (e) { }
+                DOM.uploadMsg.textContent = message;
             }
             DOM.uploadBtn.disabled = false;
             // Hide progress after success
@@ -416,6 +418,14 @@ function initEventListeners() {
             sendDelete(deleteBtn.dataset.msgId);
         }
 
+        const reportBtn = e.target.closest('.report-btn');
+        if (reportBtn) {
+            const reason = prompt('Why are you reporting this message?');
+            if (reason && reason.trim()) {
+                sendReport(reportBtn.dataset.msgId, reason.trim());
+            }
+        }
+
         const replyBtn = e.target.closest('.reply-btn');
         if (replyBtn) {
             const msgId = replyBtn.dataset.msgId;
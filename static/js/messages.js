@@ -27,7 +27,7 @@ function hideEmptyState() {
   if (emptyState) emptyState.remove();
 }
 
-function appendMessage(id, from, text, ts, reactions = {}, edited = false, replyTo = null) {
+function appendMessage(id, from, text, ts, reactions = {}, edited = false, replyTo = null, bot = false, icon = null) {
   hideEmptyState(); // Hide empty state when messages arrive
   // Store for search
   allMessages.push({ id, from, text, ts });
@@ -38,6 +38,8 @@ function appendMessage(id, from, text, ts, reactions = {}, edited = false, reply
   div.dataset.msgId = id;
 
   const editedLabel = edited ? '<span class="edited-label">(edited)</span>' : '';
+  const botLabel = bot ? '<span class="bot-label">BOT</span>' : '';
+  const iconHtml = botIcon(icon);
   const fullDate = fullTimestamp(ts);
 
   // Reply preview
@@ -54,7 +56,7 @@ function appendMessage(id, from, text, ts, reactions = {}, edited = false, reply
 
   div.innerHTML = `
     <div class="message-header">
-      <span class="message-author">${escapeHtml(from)}</span>
+      ${iconHtml}<span class="message-author">${escapeHtml(from)}</span>${botLabel}
       <span class="message-time" data-ts="${ts}" title="${fullDate}">${relativeTime(ts)}</span>
       ${editedLabel}
       ${createMessageActions(id, from)}
@@ -80,16 +82,27 @@ function appendMessage(id, from, text, ts, reactions = {}, edited = false, reply
   }
 }
 
+// Webhook posts may carry an icon: an image URL or a short emoji/text.
+function botIcon(icon) {
+  if (!icon) return '';
+  if (/^https?:\/\//i.test(icon)) {
+    return `<img class="bot-icon" src="${escapeHtml(icon).replace(/"/g, '&quot;')}" alt="">`;
+  }
+  return `<span class="bot-icon">${escapeHtml(icon.slice(0, 4))}</span>`;
+}
+
 function updateUsers(users) {
   if (DOM.userCount) DOM.userCount.textContent = `(${users.length})`;
   if (DOM.userList) {
     DOM.userList.innerHTML = users.map(u => {
       const status = userStatuses[u] || 'active';
-      const statusClass = status === 'idle' ? 'status-idle' : 'status-active';
+      const statusClass = status === 'dnd' ? 'status-dnd'
+        : (status === 'idle' || status === 'away') ? 'status-idle' : 'status-active';
+      const custom = userStatusText[u] || '';
       return `
         <li class="user-item" data-user="${escapeHtml(u)}">
           <div class="user-avatar">${u[0].toUpperCase()}<span class="status-dot ${statusClass}"></span></div>
-          <span>${escapeHtml(u)}</span>
+          <span>${escapeHtml(u)}</span>${roleBadge(u)}${custom ? `<span class="user-status-text">${escapeHtml(custom)}</span>` : ''}
           <button class="dm-btn" data-user="${escapeHtml(u)}" title="Send DM">💬</button>
         </li>
       `;
@@ -97,6 +110,15 @@ function updateUsers(users) {
   }
 }
 
+function roleBadge(user) {
+  if (userBots[user]) return '<span class="bot-label" title="Bot">BOT</span>';
+  const role = userRoles[user];
+  if (role === 'admin') return '<span class="user-status-text" title="Admin">★</span>';
+  if (role === 'moderator') return '<span class="user-status-text" title="Moderator">☆</span>';
+  if (role === 'guest') return '<span class="user-status-text">guest</span>';
+  return '';
+}
+
 function updateAvailableRooms(rooms) {
   if (!DOM.roomList) return;
 
@@ -111,12 +133,18 @@ function updateAvailableRooms(rooms) {
       <div class="room-icon">#</div>
       <div class="room-info">
         <div class="room-name">${escapeHtml(r.name)}</div>
-        <div class="room-members">${r.members} ${r.members === 1 ? 'member' : 'members'}</div>
+        <div class="room-members">${r.members} ${r.members === 1 ? 'member' : 'members'}${unreadLabel(r.name)}</div>
       </div>
     </li>
   `).join('');
 }
 
+function unreadLabel(room) {
+  const u = roomUnread[room];
+  if (!u || room === currentRoom || u.unread === 0) return '';
+  return ` · ${u.unread} unread${u.mentions ? ` (${u.mentions} @)` : ''}`;
+}
+
 function updatePinnedMessages(messages) {
   if (!DOM.pinnedMessages) return;
 
@@ -34,6 +34,8 @@ function createMessageActions(msgId, from) {
     if (from === myName) {
         html += `<button class="action-btn edit-btn" data-msg-id="${msgId}" title="Edit">✏️</button>`;
         html += `<button class="action-btn delete-btn" data-msg-id="${msgId}" title="Delete">🗑️</button>`;
+    } else {
+        html += `<button class="action-btn report-btn" data-msg-id="${msgId}" title="Report">🚩</button>`;
     }
 
     // Pin button
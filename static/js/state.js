@@ -1,6 +1,7 @@
 // ===== Application State =====
 let ws = null;
 let connected = false;
+let wsFailures = 0; // WebSocket attempts that never opened
 let named = false;
 let currentRoom = 'lobby';
 let typingTimeout = null;
@@ -15,3 +16,7 @@ let allMessages = []; // Store all messages for search
 let pinnedMessages = []; // Pinned message IDs
 let emojiPickerOpen = false;
 let userStatuses = {}; // username -> status
+let userStatusText = {}; // username -> custom status (emoji + text)
+let userRoles = {}; // username -> role (guest/user/moderator/admin)
+let userBots = {}; // username -> true for bot accounts
+let roomUnread = {}; // room -> { unread, mentions }
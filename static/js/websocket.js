@@ -1,80 +1,36 @@
 // ===== WebSocket Connection =====
 
 function connect() {
+    // Fall back to SSE + POST when WebSockets keep failing to open
+    if (wsFailures >= WS_FAILURES_BEFORE_SSE && typeof EventSource === 'function') {
+        connectSse();
+        return;
+    }
+
     ws = new WebSocket(wsUrl);
+    ws.binaryType = 'arraybuffer';
     DOM.connStatus.textContent = 'connecting...';
     DOM.statusDot.classList.remove('connected');
+    let opened = false;
 
     ws.onopen = () => {
-        connected = true;
-        DOM.connStatus.textContent = 'connected';
-        DOM.statusDot.classList.add('connected');
-        updateInputState();
-        appendSystem('Connected to server. Set your name to start chatting.');
-        sendCommand('/rooms');
-
-        // Auto-refresh rooms every 10 seconds
-        setInterval(() => {
-            if (connected) sendCommand('/rooms');
-        }, 10000);
+        opened = true;
+        wsFailures = 0;
+        onConnected();
     };
 
+    // Compressed frames decode asynchronously; chain them to keep order
+    let inbound = Promise.resolve();
     ws.onmessage = (e) => {
-        try {
-            const data = JSON.parse(e.data);
-            switch (data.type) {
-                case 'system': handleSystem(data.text); break;
-                case 'msg':
-                    appendMessage(data.id, data.from, data.text, data.ts, data.reactions || {}, data.edited);
-                    if (data.from !== myName) {
-                        playNotificationSound();
-                        incrementUnread();
-                    }
-                    break;
-                case 'list': updateUsers(data.users || []); break;
-                case 'roomlist': updateAvailableRooms(data.rooms || []); break;
-                case 'history':
-                    const items = data.items || [];
-                    items.forEach(m => appendMessage(m.id, m.from, m.text, m.ts, m.reactions || {}, m.edited));
-                    if (items.length === 0) showEmptyState();
-                    break;
-                case 'typing': handleTypingIndicator(data.users || []); break;
-                case 'reaction': handleReactionUpdate(data.msg_id, data.emoji, data.user, data.added); break;
-                case 'edit': handleEditUpdate(data.msg_id, data.new_text); break;
-                case 'delete': handleDeleteUpdate(data.msg_id); break;
-                case 'readreceipt': break;
-                case 'linkpreview':
-                    try { renderLinkPreview(data); }
-                    catch (e) { console.error("Preview failed:", e); }
-                    break;
-                case 'status':
-                    userStatuses[data.user] = data.status;
-                    sendCommand('/list');
-                    break;
-                case 'mention':
-                    if (data.mentioned.toLowerCase() === myName.toLowerCase()) {
-                        playNotificationSound();
-                        incrementUnread();
-                    }
-                    break;
-                case 'nudge':
-                    handleNudge(data.from);
-                    break;
-                default: handleSystem(e.data);
-            }
-        } catch (err) {
-            console.error("WS Error:", err);
-            handleSystem(e.data);
-        }
+        inbound = inbound
+            .then(() => decodeFrame(e.data))
+            .then(handleServerFrame)
+            .catch(err => console.error("WS decode error:", err));
     };
 
     ws.onclose = () => {
-        connected = false;
-        DOM.connStatus.textContent = 'disconnected';
-        DOM.statusDot.classList.remove('connected');
-        updateInputState();
-        appendSystem('Disconnected. Reconnecting...');
-        setTimeout(connect, 2000);
+        if (!opened) wsFailures++;
+        onDisconnected();
     };
 
     ws.onerror = () => {
@@ -89,6 +45,163 @@ function connect() {
     };
 }
 
+// Server-Sent Events down, HTTP POST up. `ws` becomes a small object with
+// the same send() so the rest of the client doesn't care.
+function connectSse() {
+    const source = new EventSource(sseUrl);
+    DOM.connStatus.textContent = 'connecting...';
+    DOM.statusDot.classList.remove('connected');
+    let postUrl = null;
+    let outbox = Promise.resolve();
+
+    ws = {
+        // POSTs are chained so messages arrive in the order they were sent
+        send(text) {
+            if (!postUrl) return;
+            const url = postUrl;
+            outbox = outbox
+                .then(() => fetch(url, { method: 'POST', headers: { 'Content-Type': 'application/json' }, body: text }))
+                .catch(err => console.error("SSE post error:", err));
+        },
+        close() { source.close(); },
+    };
+
+    source.addEventListener('session', (e) => {
+        postUrl = '/sse/' + encodeURIComponent(e.data);
+        onConnected();
+    });
+    source.onmessage = (e) => handleServerFrame(e.data);
+    source.onerror = () => {
+        // The server session is gone; reconnect with a fresh one
+        source.close();
+        onDisconnected();
+    };
+}
+
+function onConnected() {
+    ws.send(JSON.stringify({ type: 'hello', version: PROTOCOL_VERSION, min_version: 2, capabilities: CLIENT_CAPABILITIES }));
+    connected = true;
+    DOM.connStatus.textContent = 'connected';
+    DOM.statusDot.classList.add('connected');
+    updateInputState();
+    appendSystem('Connected to server. Set your name to start chatting.');
+    sendCommand('/rooms');
+
+    // Auto-refresh rooms every 10 seconds
+    setInterval(() => {
+        if (connected) sendCommand('/rooms');
+    }, 10000);
+}
+
+function onDisconnected() {
+    connected = false;
+    DOM.connStatus.textContent = 'disconnected';
+    DOM.statusDot.classList.remove('connected');
+    updateInputState();
+    appendSystem('Disconnected. Reconnecting...');
+    setTimeout(connect, 2000);
+}
+
+// ===== Incoming Frames =====
+
+// Text frames are JSON; binary frames are zlib-compressed JSON
+// (only sent after negotiating 'deflate').
+async function decodeFrame(data) {
+    if (typeof data === 'string') return data;
+    const stream = new Blob([data]).stream().pipeThrough(new DecompressionStream('deflate'));
+    return await new Response(stream).text();
+}
+
+function handleServerFrame(text) {
+    try {
+        const data = JSON.parse(text);
+        switch (data.type) {
+            case 'system': handleSystem(data.text); break;
+            case 'hello': break;
+            case 'incompatible':
+                appendSystem('Server is incompatible with this client: ' + data.reason);
+                break;
+            case 'invalid': appendSystem('Server rejected message: ' + data.reason); break;
+            case 'ack': break;
+            case 'error': appendSystem(data.message); break;
+            case 'msg':
+                appendMessage(data.id, data.from, data.text, data.ts, data.reactions || {}, data.edited, null, data.bot, data.icon);
+                if (data.from !== myName) {
+                    playNotificationSound();
+                    incrementUnread();
+                }
+                break;
+            case 'list': updateUsers(data.users || []); break;
+            case 'userlist':
+                (data.users || []).forEach(u => {
+                    userStatuses[u.name] = u.status;
+                    userStatusText[u.name] = [u.emoji, u.text].filter(Boolean).join(' ');
+                    userRoles[u.name] = u.role;
+                    userBots[u.name] = !!u.bot;
+                });
+                updateUsers((data.users || []).map(u => u.name));
+                break;
+            case 'roomlist': updateAvailableRooms(data.rooms || []); break;
+            case 'history':
+                const items = data.items || [];
+                items.forEach(m => appendMessage(m.id, m.from, m.text, m.ts, m.reactions || {}, m.edited, null, m.bot, m.icon));
+                if (items.length === 0) showEmptyState();
+                break;
+            case 'typing': handleTypingIndicator(data.users || []); break;
+            case 'reaction': handleReactionUpdate(data.msg_id, data.emoji, data.user, data.added); break;
+            case 'edit': handleEditUpdate(data.msg_id, data.new_text); break;
+            case 'delete': handleDeleteUpdate(data.msg_id); break;
+            case 'readreceipt': break;
+            case 'linkpreview':
+                try { renderLinkPreview(data); }
+                catch (e) { console.error("Preview failed:", e); }
+                break;
+            case 'status':
+                userStatuses[data.user] = data.status;
+                userStatusText[data.user] = [data.emoji, data.text].filter(Boolean).join(' ');
+                sendCommand('/list');
+                break;
+            case 'mention':
+                // Only sent to recipients (includes @here / @room)
+                playNotificationSound();
+                incrementUnread();
+                if (data.room !== currentRoom) {
+                    appendSystem(`${data.from} mentioned you in #${data.room}: ${data.text}`);
+                }
+                break;
+            case 'mentions':
+                if (!data.items || data.items.length === 0) {
+                    appendSystem('No mentions.');
+                } else {
+                    data.items.forEach(m => appendSystem(`${m.read ? '' : '• '}#${m.room} ${m.from}: ${m.text}`));
+                }
+                break;
+            case 'reports':
+                if (!data.reports || data.reports.length === 0) {
+                    appendSystem('No open reports.');
+                } else {
+                    data.reports.forEach(r => {
+                        const text = r.message ? `${r.message.from}: ${r.message.text}` : '(message no longer in history)';
+                        appendSystem(`[${r.id}] #${r.room} ${r.reporter} reported "${text}" — ${r.reason}`);
+                    });
+                    appendSystem('Resolve with /reports dismiss|delete|mute <id>');
+                }
+                break;
+            case 'nudge':
+                handleNudge(data.from);
+                break;
+            case 'unread':
+                (data.rooms || []).forEach(r => { roomUnread[r.room] = r; });
+                sendCommand('/rooms');
+                break;
+            default: handleSystem(text);
+        }
+    } catch (err) {
+        console.error("WS Error:", err);
+        handleSystem(text);
+    }
+}
+
 // ===== System Message Handler =====
 
 function handleSystem(text) {
@@ -134,6 +247,12 @@ function sendCommand(cmd) {
     ws.send(JSON.stringify({ type: 'cmd', cmd }));
 }
 
+// Typed command, e.g. sendAction('join', { room: 'tech' })
+function sendAction(type, fields = {}) {
+    if (!connected) return appendSystem('Not connected.');
+    ws.send(JSON.stringify({ type, ...fields }));
+}
+
 function sendMessage(msg) {
     if (!connected) return appendSystem('Not connected.');
     ws.send(JSON.stringify({ type: 'msg', text: msg }));
@@ -155,6 +274,11 @@ function sendDelete(msgId) {
     ws.send(JSON.stringify({ type: 'delete', msg_id: msgId }));
 }
 
+function sendReport(msgId, reason) {
+    if (!connected) return;
+    ws.send(JSON.stringify({ type: 'report', msg_id: msgId, reason }));
+}
+
 function sendTypingStatus(isTyping) {
     if (!connected) return;
     // Don't send if state hasn't changed (optimization)